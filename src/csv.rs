@@ -1,82 +1,149 @@
-use async_stream::try_stream;
-use csv_async::AsyncReaderBuilder;
+use async_stream::{stream, try_stream};
+use csv_async::{AsyncReaderBuilder, AsyncWriterBuilder, StringRecord};
 use futures::{Stream, StreamExt};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, num::NonZeroU64, time::Duration};
 use thiserror::Error;
-use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, BufWriter};
 
-use crate::transaction_manager::Transaction;
+pub use crate::money::RoundingMode;
+use crate::{
+    money::{Money, RoundingError},
+    transaction_manager::{
+        ClientId, Currency, OpeningBalance, Timestamp, Transaction, TransactionId,
+    },
+};
+
+// Defined with the transactions it names, and re-exported as it is read from the type column.
+pub use crate::transaction_manager::OrderType;
+
+// How often read_transactions_with_progress reports progress by default.
+pub const DEFAULT_PROGRESS_INTERVAL: NonZeroU64 = NonZeroU64::new(100_000).unwrap();
 
 #[derive(Error, Debug)]
 pub enum CsvError {
-    #[error("Amount is zero or negative")]
-    InvalidAmount,
-    #[error("Amount is required but is missing")]
-    MissingAmount,
+    #[error("Amount is zero: {value}")]
+    ZeroAmount { value: String },
+    #[error("Amount is negative: {value}")]
+    NegativeAmount { value: String },
+    #[error("Amount is required for {order_type} but is missing")]
+    MissingAmount { order_type: String },
+    #[error("Amount is not a valid decimal number: {value}")]
+    InvalidAmount { value: String },
+    #[error("Amount has more than four decimal places: {value}")]
+    ExcessPrecision { value: String },
+    #[error("Unknown transaction type: {value}")]
+    UnknownTransactionType { value: String },
+    #[error("No row received within {0:?}")]
+    ReadTimeout(Duration),
+    #[error("Malformed row {row}: {message}")]
+    MalformedRow { row: u64, message: String },
 }
 
-#[derive(Debug, Deserialize)]
-pub enum OrderType {
-    #[serde(rename = "deposit")]
-    Deposit,
-    #[serde(rename = "withdrawal")]
-    Withdrawal,
-    #[serde(rename = "dispute")]
-    Dispute,
-    #[serde(rename = "resolve")]
-    Resolve,
-    #[serde(rename = "chargeback")]
-    Chargeback,
+impl CsvError {
+    // The variant's name without any fields, e.g. `ZeroAmount`, as counted in error summaries.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CsvError::ZeroAmount { .. } => "ZeroAmount",
+            CsvError::NegativeAmount { .. } => "NegativeAmount",
+            CsvError::MissingAmount { .. } => "MissingAmount",
+            CsvError::InvalidAmount { .. } => "InvalidAmount",
+            CsvError::ExcessPrecision { .. } => "ExcessPrecision",
+            CsvError::UnknownTransactionType { .. } => "UnknownTransactionType",
+            CsvError::ReadTimeout(..) => "ReadTimeout",
+            CsvError::MalformedRow { .. } => "MalformedRow",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TransactionDto {
     #[serde(rename = "type")]
     pub order_type: OrderType,
-    pub client: u16,
-    pub tx: u32,
-    pub amount: Option<f64>,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    // Kept as a string so it can be converted to base units exactly, without passing through a float.
+    pub amount: Option<String>,
+    // An optional RFC 3339 timestamp, e.g. `2024-05-01T09:30:00Z`. The column may be left out entirely.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    // An optional currency code for a CurrencyLedger, e.g. `EUR`. Left out when written if not set, so
+    // exported files keep the same columns.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
 }
 
 impl TransactionDto {
-    pub fn to_transaction(&self) -> Result<Transaction, CsvError> {
-        match self.order_type {
-            OrderType::Deposit => {
-                let amount_base_units;
-                if let Some(amount) = self.amount {
-                    amount_base_units = Self::to_base_units(amount);
-                } else {
-                    return Err(CsvError::MissingAmount);
-                }
+    // The row that reads back as the given transaction. Account-level operations have no id, so are
+    // written with a tx of 0.
+    pub fn from_transaction(transaction: &Transaction, timestamp: Option<Timestamp>) -> Self {
+        let (order_type, tx, amount) = match *transaction {
+            Transaction::Deposit {
+                id,
+                amount_base_units,
+                ..
+            } => (OrderType::Deposit, id, Some(amount_base_units)),
+            Transaction::Withdrawal {
+                id,
+                amount_base_units,
+                ..
+            } => (OrderType::Withdrawal, id, Some(amount_base_units)),
+            Transaction::SettleWithdrawal { id, .. } => (OrderType::SettleWithdrawal, id, None),
+            Transaction::Adjustment {
+                id,
+                amount_base_units,
+                ..
+            } => (OrderType::Adjustment, id, Some(amount_base_units)),
+            Transaction::ForceDeposit {
+                id,
+                amount_base_units,
+                ..
+            } => (OrderType::ForceDeposit, id, Some(amount_base_units)),
+            Transaction::Dispute { id, .. } => (OrderType::Dispute, id, None),
+            Transaction::Resolve { id, .. } => (OrderType::Resolve, id, None),
+            Transaction::Chargeback { id, .. } => (OrderType::Chargeback, id, None),
+            Transaction::CancelDispute { id, .. } => (OrderType::CancelDispute, id, None),
+            Transaction::Freeze { .. } => (OrderType::Freeze, 0, None),
+            Transaction::Unfreeze { .. } => (OrderType::Unfreeze, 0, None),
+        };
 
-                if amount_base_units <= 0 {
-                    Err(CsvError::InvalidAmount)
-                } else {
-                    Ok(Transaction::Deposit {
-                        id: self.tx,
-                        client_id: self.client,
-                        amount_base_units,
-                    })
-                }
-            }
-            OrderType::Withdrawal => {
-                let amount_base_units;
-                if let Some(amount) = self.amount {
-                    amount_base_units = Self::to_base_units(amount);
-                } else {
-                    return Err(CsvError::MissingAmount);
-                }
+        Self {
+            order_type,
+            client: transaction.client_id(),
+            tx,
+            // Money displays all four decimal places, so the amount reads back exactly.
+            amount: amount.map(|amount| amount.to_string()),
+            timestamp,
+            currency: None,
+        }
+    }
 
-                if amount_base_units <= 0 {
-                    Err(CsvError::InvalidAmount)
-                } else {
-                    Ok(Transaction::Withdrawal {
-                        id: self.tx,
-                        client_id: self.client,
-                        amount_base_units,
-                    })
-                }
-            }
+    pub fn to_transaction(&self, rounding: RoundingMode) -> Result<Transaction, CsvError> {
+        match self.order_type {
+            OrderType::Deposit => Ok(Transaction::Deposit {
+                id: self.tx,
+                client_id: self.client,
+                amount_base_units: self.positive_amount_base_units(rounding)?,
+            }),
+            OrderType::Withdrawal => Ok(Transaction::Withdrawal {
+                id: self.tx,
+                client_id: self.client,
+                amount_base_units: self.positive_amount_base_units(rounding)?,
+            }),
+            OrderType::SettleWithdrawal => Ok(Transaction::SettleWithdrawal {
+                id: self.tx,
+                client_id: self.client,
+            }),
+            OrderType::Adjustment => Ok(Transaction::Adjustment {
+                id: self.tx,
+                client_id: self.client,
+                amount_base_units: self.signed_amount_base_units(rounding)?,
+            }),
+            OrderType::ForceDeposit => Ok(Transaction::ForceDeposit {
+                id: self.tx,
+                client_id: self.client,
+                amount_base_units: self.positive_amount_base_units(rounding)?,
+            }),
             OrderType::Dispute => Ok(Transaction::Dispute {
                 id: self.tx,
                 client_id: self.client,
@@ -89,27 +156,888 @@ impl TransactionDto {
                 id: self.tx,
                 client_id: self.client,
             }),
+            OrderType::CancelDispute => Ok(Transaction::CancelDispute {
+                id: self.tx,
+                client_id: self.client,
+            }),
+            // Account-level operations, so the tx column is ignored.
+            OrderType::Freeze => Ok(Transaction::Freeze {
+                client_id: self.client,
+            }),
+            OrderType::Unfreeze => Ok(Transaction::Unfreeze {
+                client_id: self.client,
+            }),
+            OrderType::Unknown(ref value) => Err(CsvError::UnknownTransactionType {
+                value: value.clone(),
+            }),
+        }
+    }
+
+    // Same as to_transaction, but also returns the error rounding introduced into the amount in base units,
+    // i.e. the rounded minus the exact amount, or zero for a transaction without an amount.
+    pub fn to_transaction_with_rounding_error(
+        &self,
+        rounding: RoundingMode,
+    ) -> Result<(Transaction, RoundingError), CsvError> {
+        let transaction = self.to_transaction(rounding)?;
+
+        let has_amount = matches!(
+            transaction,
+            Transaction::Deposit { .. }
+                | Transaction::Withdrawal { .. }
+                | Transaction::Adjustment { .. }
+                | Transaction::ForceDeposit { .. }
+        );
+
+        let rounding_error = self
+            .amount
+            .as_deref()
+            .filter(|_| has_amount)
+            .and_then(|amount| Money::parse_decimal_with_rounding_error(amount.trim(), rounding))
+            .map_or(RoundingError::ZERO, |(_, rounding_error)| rounding_error);
+
+        Ok((transaction, rounding_error))
+    }
+
+    // Same as to_transaction, but rejects an amount that would have to be rounded to four decimal places
+    // rather than silently changing its value.
+    pub fn to_transaction_exact(&self) -> Result<Transaction, CsvError> {
+        if let Some(amount) = self.amount.as_deref().map(str::trim) {
+            if Money::exceeds_precision(amount) {
+                return Err(CsvError::ExcessPrecision {
+                    value: amount.to_string(),
+                });
+            }
+        }
+
+        // Nothing is left to round, so the rounding mode makes no difference.
+        self.to_transaction(RoundingMode::default())
+    }
+
+    // Deposits and withdrawals require a strictly positive amount.
+    fn positive_amount_base_units(&self, rounding: RoundingMode) -> Result<Money, CsvError> {
+        let amount_base_units = self.signed_amount_base_units(rounding)?;
+
+        if amount_base_units.is_negative() {
+            Err(CsvError::NegativeAmount {
+                value: self
+                    .amount
+                    .as_deref()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            })
+        } else {
+            Ok(amount_base_units)
+        }
+    }
+
+    // Adjustments may be negative, but an adjustment of zero is as much a mistake as a zero deposit.
+    fn signed_amount_base_units(&self, rounding: RoundingMode) -> Result<Money, CsvError> {
+        let amount = self
+            .amount
+            .as_deref()
+            .ok_or_else(|| CsvError::MissingAmount {
+                order_type: self.order_type.to_string(),
+            })?
+            .trim();
+
+        let amount_base_units =
+            Money::parse_decimal(amount, rounding).ok_or_else(|| CsvError::InvalidAmount {
+                value: amount.to_string(),
+            })?;
+
+        if amount_base_units.is_zero() {
+            Err(CsvError::ZeroAmount {
+                value: amount.to_string(),
+            })
+        } else {
+            Ok(amount_base_units)
+        }
+    }
+}
+
+// A row of a balances CSV in the format the engine outputs. The total column, if present, is ignored as
+// it is derived from available and held.
+#[derive(Debug, Deserialize)]
+pub struct BalanceDto {
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub locked: bool,
+}
+
+impl BalanceDto {
+    pub fn to_opening_balance(&self, rounding: RoundingMode) -> OpeningBalance {
+        OpeningBalance {
+            client_id: self.client,
+            available: Money::from_decimal(self.available, rounding),
+            held: Money::from_decimal(self.held, rounding),
+            locked: self.locked,
         }
     }
+}
+
+// The delimiter and quote characters of an input CSV. Defaults to comma separated fields quoted with
+// double quotes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    pub quote: u8,
+}
 
-    fn to_base_units(amount: f64) -> i64 {
-        (amount * 10_000.0).round() as i64
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+        }
     }
 }
 
 // Read transactions as a stream hence the whole CSV does not need to be in memory at once.
-pub fn read_transactions(
-    file: fs::File,
-) -> impl Stream<Item = Result<TransactionDto, Box<dyn std::error::Error>>> {
+//
+// Header names found in `header_aliases` are renamed to the mapped name before deserializing, so a file
+// using e.g. `client_id` instead of `client` can still be read.
+//
+// A leading UTF-8 byte order mark, as written by Excel, is stripped by the reader rather than becoming part
+// of the first header.
+//
+// If `read_timeout` is given and no row arrives within it, e.g. from a stalled network source, the stream
+// yields CsvError::ReadTimeout rather than waiting forever.
+//
+// A row that cannot be parsed, e.g. one with the wrong number of columns, is yielded as
+// CsvError::MalformedRow with its 1-based row number, not counting the header, and the stream carries on
+// with the next row. Any other error, such as failing to read the input, ends the stream.
+pub fn read_transactions<R>(
+    reader: R,
+    header_aliases: HashMap<String, String>,
+    format: CsvFormat,
+    read_timeout: Option<Duration>,
+) -> impl Stream<Item = Result<TransactionDto, Box<dyn std::error::Error>>> + Send
+where
+    R: AsyncRead + Unpin + Send,
+{
     let mut reader = AsyncReaderBuilder::new()
         .has_headers(true)
-        .create_deserializer(file);
+        .delimiter(format.delimiter)
+        .quote(format.quote)
+        .create_deserializer(reader);
+
+    // Errors are held across yields, so they must be Send for the stream to be.
+    let transactions = stream! {
+      if !header_aliases.is_empty() {
+        let headers: StringRecord = match reader.headers().await {
+          Ok(headers) => headers
+            .iter()
+            .map(|header| header_aliases.get(header).map_or(header, String::as_str))
+            .collect(),
+          Err(error) => {
+            yield Err(error.into());
+            return;
+          }
+        };
+
+        reader.set_headers(headers);
+      }
 
-    try_stream! {
       let mut transactions = reader.deserialize::<TransactionDto>();
+      let mut row = 0;
 
-      while let Some(transaction) = transactions.next().await {
-        yield transaction?;
+      loop {
+        let next = match read_timeout {
+          Some(read_timeout) => match tokio::time::timeout(read_timeout, transactions.next()).await {
+            Ok(next) => next,
+            Err(_) => {
+              yield Err(CsvError::ReadTimeout(read_timeout).into());
+              break;
+            }
+          },
+          None => transactions.next().await,
+        };
+
+        row += 1;
+
+        match next {
+          Some(Ok(transaction)) => yield Ok(transaction),
+          Some(Err(error)) if error.is_io_error() => {
+            yield Err(error.into());
+            break;
+          }
+          Some(Err(error)) => {
+            yield Err(CsvError::MalformedRow { row, message: error.to_string() }.into())
+          }
+          None => break,
+        }
       }
+    };
+
+    transactions.map(
+        |result: Result<TransactionDto, Box<dyn std::error::Error + Send + Sync>>| {
+            result.map_err(|error| error as Box<dyn std::error::Error>)
+        },
+    )
+}
+
+// Same as read_transactions, but calls `progress` with the number of rows read so far after every
+// `interval` rows, e.g. to drive a progress indicator. Malformed rows are counted.
+pub fn read_transactions_with_progress<R, F>(
+    reader: R,
+    header_aliases: HashMap<String, String>,
+    format: CsvFormat,
+    read_timeout: Option<Duration>,
+    interval: NonZeroU64,
+    mut progress: F,
+) -> impl Stream<Item = Result<TransactionDto, Box<dyn std::error::Error>>>
+where
+    R: AsyncRead + Unpin + Send,
+    F: FnMut(u64),
+{
+    let mut rows = 0;
+
+    read_transactions(reader, header_aliases, format, read_timeout).inspect(move |_| {
+        rows += 1;
+
+        if rows % interval.get() == 0 {
+            progress(rows);
+        }
+    })
+}
+
+// Blocking equivalent of read_transactions for callers without an async runtime, e.g. parsing in-memory
+// data. Malformed rows are yielded as CsvError::MalformedRow and reading carries on, while any other error
+// ends the iterator.
+pub fn read_transactions_sync<R>(
+    reader: R,
+    header_aliases: HashMap<String, String>,
+    format: CsvFormat,
+) -> impl Iterator<Item = Result<TransactionDto, Box<dyn std::error::Error>>>
+where
+    R: io::Read,
+{
+    let mut reader = ::csv::ReaderBuilder::new()
+        .has_headers(true)
+        .delimiter(format.delimiter)
+        .quote(format.quote)
+        .from_reader(reader);
+
+    let mut header_error = None;
+
+    if !header_aliases.is_empty() {
+        match reader.headers() {
+            Ok(headers) => {
+                let headers: ::csv::StringRecord = headers
+                    .iter()
+                    .map(|header| header_aliases.get(header).map_or(header, String::as_str))
+                    .collect();
+
+                reader.set_headers(headers);
+            }
+            Err(error) => header_error = Some(error),
+        }
+    }
+
+    let mut failed = header_error.is_some();
+
+    let transactions = reader
+        .into_deserialize::<TransactionDto>()
+        .zip(1..)
+        .map_while(move |(result, row)| match result {
+            _ if failed => None,
+            Ok(transaction) => Some(Ok(transaction)),
+            Err(error) if error.is_io_error() => {
+                failed = true;
+                Some(Err(error.into()))
+            }
+            Err(error) => Some(Err(CsvError::MalformedRow {
+                row,
+                message: error.to_string(),
+            }
+            .into())),
+        });
+
+    header_error
+        .map(|error| Err(error.into()))
+        .into_iter()
+        .chain(transactions)
+}
+
+// Write transactions in the format read by read_transactions, including the timestamp column.
+pub async fn write_transactions<W, I>(
+    writer: W,
+    transactions: I,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    W: AsyncWrite + Unpin + Send,
+    I: IntoIterator<Item = TransactionDto>,
+{
+    // The header is written explicitly so it is present even when there are no transactions, and rows
+    // are buffered as the serializer writes each one as it is serialized.
+    let mut writer = AsyncWriterBuilder::new()
+        .has_headers(false)
+        .create_serializer(BufWriter::new(writer));
+
+    writer
+        .serialize(["type", "client", "tx", "amount", "timestamp"])
+        .await?;
+
+    for transaction in transactions {
+        writer.serialize(transaction).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+// Read a balances CSV, e.g. the output of a previous run, to seed opening balances.
+pub fn read_balances<R>(
+    reader: R,
+) -> impl Stream<Item = Result<BalanceDto, Box<dyn std::error::Error>>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut reader = AsyncReaderBuilder::new()
+        .has_headers(true)
+        .create_deserializer(reader);
+
+    try_stream! {
+      let mut balances = reader.deserialize::<BalanceDto>();
+
+      while let Some(balance) = balances.next().await {
+        yield balance?;
+      }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_manager::TransactionManager;
+    use tokio::io::AsyncWriteExt;
+
+    async fn read_all(
+        csv: &'static str,
+        header_aliases: HashMap<String, String>,
+    ) -> Vec<TransactionDto> {
+        let stream = read_transactions(csv.as_bytes(), header_aliases, CsvFormat::default(), None);
+
+        futures::pin_mut!(stream);
+
+        let mut dtos = Vec::new();
+        while let Some(result) = stream.next().await {
+            dtos.push(result.unwrap());
+        }
+
+        dtos
+    }
+
+    fn deposit_dto(amount: Option<&str>) -> TransactionDto {
+        TransactionDto {
+            order_type: OrderType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: amount.map(str::to_string),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn test_deposit_to_transaction() {
+        let transaction = deposit_dto(Some("1.5"))
+            .to_transaction(RoundingMode::default())
+            .unwrap();
+
+        assert_eq!(
+            transaction,
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(15_000)
+            }
+        );
+    }
+
+    #[test]
+    fn test_exact_amounts() {
+        for (amount, base_units) in [
+            ("0.0001", 1),
+            ("1.0001", 10_001),
+            ("99999999.9999", 999_999_999_999),
+        ] {
+            assert_eq!(
+                deposit_dto(Some(amount))
+                    .to_transaction(RoundingMode::default())
+                    .unwrap(),
+                Transaction::Deposit {
+                    id: 1,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(base_units),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_amount() {
+        let res = deposit_dto(Some("1.2.3")).to_transaction(RoundingMode::default());
+
+        assert!(matches!(res, Err(CsvError::InvalidAmount { value }) if value == "1.2.3"));
+    }
+
+    #[test]
+    fn test_missing_amount() {
+        let res = deposit_dto(None).to_transaction(RoundingMode::default());
+
+        assert!(matches!(
+            res,
+            Err(CsvError::MissingAmount { order_type }) if order_type == "deposit"
+        ));
+    }
+
+    #[test]
+    fn test_zero_amount() {
+        let res = deposit_dto(Some("0.0")).to_transaction(RoundingMode::default());
+
+        assert!(matches!(res, Err(CsvError::ZeroAmount { value }) if value == "0.0"));
+    }
+
+    #[test]
+    fn test_negative_amount() {
+        let res = deposit_dto(Some("-1.0")).to_transaction(RoundingMode::default());
+
+        assert!(matches!(res, Err(CsvError::NegativeAmount { value }) if value == "-1.0"));
+
+        let withdrawal = TransactionDto {
+            order_type: OrderType::Withdrawal,
+            client: 1,
+            tx: 1,
+            amount: Some("-1.0".to_string()),
+            timestamp: None,
+            currency: None,
+        };
+
+        assert!(matches!(
+            withdrawal.to_transaction(RoundingMode::default()),
+            Err(CsvError::NegativeAmount { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rounding_mode_zero_amount() {
+        let dto = deposit_dto(Some("0.00005"));
+
+        assert!(matches!(
+            dto.to_transaction(RoundingMode::Truncate),
+            Err(CsvError::ZeroAmount { value }) if value == "0.00005"
+        ));
+        assert_eq!(
+            dto.to_transaction(RoundingMode::HalfUp).unwrap(),
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_to_transaction_exact() {
+        assert!(matches!(
+            deposit_dto(Some("1.00005")).to_transaction_exact(),
+            Err(CsvError::ExcessPrecision { value }) if value == "1.00005"
+        ));
+        assert_eq!(
+            deposit_dto(Some("1.0001")).to_transaction_exact().unwrap(),
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(10_001),
+            }
+        );
+        assert!(matches!(
+            deposit_dto(None).to_transaction_exact(),
+            Err(CsvError::MissingAmount { .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_context() {
+        let error = |dto: TransactionDto| {
+            dto.to_transaction(RoundingMode::default())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error(deposit_dto(Some("abc"))),
+            "Amount is not a valid decimal number: abc"
+        );
+        assert_eq!(error(deposit_dto(Some(" 0 "))), "Amount is zero: 0");
+        assert_eq!(error(deposit_dto(Some("-2.5"))), "Amount is negative: -2.5");
+        assert_eq!(
+            error(TransactionDto {
+                order_type: OrderType::Withdrawal,
+                client: 1,
+                tx: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            }),
+            "Amount is required for withdrawal but is missing"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_byte_order_mark() {
+        let csv = "\u{feff}type,client,tx,amount\ndeposit,1,1,1.5\n";
+
+        let dtos = read_all(csv, HashMap::new()).await;
+
+        assert!(matches!(dtos[0].order_type, OrderType::Deposit));
+        assert_eq!(dtos[0].client, 1);
+
+        // Also with aliases, which read the headers before deserializing.
+        let csv = "\u{feff}kind,client,tx,amount\ndeposit,1,1,1.5\n";
+
+        let dtos = read_all(
+            csv,
+            HashMap::from([("kind".to_string(), "type".to_string())]),
+        )
+        .await;
+
+        assert!(matches!(dtos[0].order_type, OrderType::Deposit));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_transaction_type() {
+        let dtos = read_all(
+            "type,client,tx,amount\ndeposite,1,1,1.0\ndeposit,1,2,1.0\n",
+            HashMap::new(),
+        )
+        .await;
+
+        // The row is read, and only fails to convert.
+        assert!(matches!(&dtos[0].order_type, OrderType::Unknown(value) if value == "deposite"));
+        assert!(matches!(dtos[1].order_type, OrderType::Deposit));
+
+        let error = dtos[0].to_transaction(RoundingMode::default()).unwrap_err();
+
+        assert!(matches!(
+            &error,
+            CsvError::UnknownTransactionType { value } if value == "deposite"
+        ));
+        assert_eq!(error.to_string(), "Unknown transaction type: deposite");
+    }
+
+    #[tokio::test]
+    async fn test_read_currency() {
+        let dtos = read_all(
+            "type,client,tx,amount,currency\ndeposit,1,1,1.0,EUR\ndeposit,1,2,1.0,\n",
+            HashMap::new(),
+        )
+        .await;
+
+        assert_eq!(dtos[0].currency.as_deref(), Some("EUR"));
+        assert_eq!(dtos[1].currency, None);
+
+        // Without the column every row has no currency.
+        let dtos = read_all("type,client,tx,amount\ndeposit,1,1,1.0\n", HashMap::new()).await;
+
+        assert_eq!(dtos[0].currency, None);
+    }
+
+    #[tokio::test]
+    async fn test_read_freeze_unfreeze() {
+        let dtos = read_all(
+            "type,client,tx,amount\nfreeze,4,0,\nunfreeze,4,0,\n",
+            HashMap::new(),
+        )
+        .await;
+
+        assert_eq!(
+            dtos[0].to_transaction(RoundingMode::default()).unwrap(),
+            Transaction::Freeze { client_id: 4 }
+        );
+        assert_eq!(
+            dtos[1].to_transaction(RoundingMode::default()).unwrap(),
+            Transaction::Unfreeze { client_id: 4 }
+        );
+    }
+
+    #[test]
+    fn test_read_transactions_sync() {
+        let csv = "type,client_id,tx,amount
+deposit,1,1,100.5
+withdrawal,1,2,0.5,extra
+withdrawal,1,3,20
+";
+
+        let header_aliases = HashMap::from([("client_id".to_string(), "client".to_string())]);
+
+        let results: Vec<_> =
+            read_transactions_sync(csv.as_bytes(), header_aliases, CsvFormat::default()).collect();
+
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            results[1].as_ref().unwrap_err().downcast_ref::<CsvError>(),
+            Some(CsvError::MalformedRow { row: 2, .. })
+        ));
+
+        let mut manager = TransactionManager::new();
+
+        for dto in results.into_iter().filter_map(Result::ok) {
+            manager
+                .accept(dto.to_transaction(RoundingMode::default()).unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(manager.balance(1).unwrap().available, 80.5);
+    }
+
+    #[tokio::test]
+    async fn test_read_timestamps() {
+        let csv = "type,client,tx,amount,timestamp
+deposit,1,1,1.0,2024-05-01T09:30:00Z
+deposit,1,2,1.0,2024-05-01T11:30:00+02:00
+deposit,1,3,1.0,
+deposit,1,4,1.0,yesterday
+deposit,1,5,1.0,2024-05-01
+";
+
+        let stream = read_transactions(csv.as_bytes(), HashMap::new(), CsvFormat::default(), None);
+
+        let results: Vec<_> = stream.collect().await;
+
+        let expected = "2024-05-01T09:30:00Z".parse::<Timestamp>().unwrap();
+
+        assert_eq!(results[0].as_ref().unwrap().timestamp, Some(expected));
+        // Offsets are normalized to UTC.
+        assert_eq!(results[1].as_ref().unwrap().timestamp, Some(expected));
+        assert_eq!(results[2].as_ref().unwrap().timestamp, None);
+
+        for (index, result) in results.iter().enumerate().skip(3) {
+            let error = result.as_ref().unwrap_err().downcast_ref::<CsvError>();
+
+            assert!(
+                matches!(error, Some(CsvError::MalformedRow { row, .. }) if *row == index as u64 + 1),
+                "{:?}",
+                error
+            );
+        }
+
+        // The column is optional.
+        let dtos = read_all("type,client,tx,amount\ndeposit,1,1,1.0\n", HashMap::new()).await;
+
+        assert_eq!(dtos[0].timestamp, None);
+    }
+
+    #[tokio::test]
+    async fn test_read_adjustments() {
+        let dtos = read_all(
+            "type,client,tx,amount\nadjustment,2,7,-1.25\nadjustment,2,8,0.5\nadjustment,2,9,0\n",
+            HashMap::new(),
+        )
+        .await;
+
+        assert_eq!(
+            dtos[0].to_transaction(RoundingMode::default()).unwrap(),
+            Transaction::Adjustment {
+                id: 7,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(-12_500),
+            }
+        );
+        assert_eq!(
+            dtos[1].to_transaction(RoundingMode::default()).unwrap(),
+            Transaction::Adjustment {
+                id: 8,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(5_000),
+            }
+        );
+        assert!(matches!(
+            dtos[2].to_transaction(RoundingMode::default()),
+            Err(CsvError::ZeroAmount { .. })
+        ));
+    }
+
+    #[cfg(feature = "wide-tx-id")]
+    #[test]
+    fn test_wide_transaction_id() {
+        let id = u32::MAX as TransactionId + 1;
+
+        let dto = TransactionDto {
+            order_type: OrderType::Deposit,
+            client: 1,
+            tx: id,
+            amount: Some("1.0".to_string()),
+            timestamp: None,
+            currency: None,
+        };
+
+        let transaction = dto.to_transaction(RoundingMode::default()).unwrap();
+
+        assert_eq!(
+            transaction,
+            Transaction::Deposit {
+                id,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(10_000),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_transactions() {
+        let dtos = read_all(
+            "type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1,\ncancel_dispute,1,1,\n",
+            HashMap::new(),
+        )
+        .await;
+
+        assert_eq!(dtos.len(), 3);
+        assert!(matches!(dtos[0].order_type, OrderType::Deposit));
+        assert_eq!(dtos[0].amount.as_deref(), Some("1.5"));
+        assert!(matches!(dtos[1].order_type, OrderType::Dispute));
+        assert_eq!(dtos[1].amount, None);
+        assert!(matches!(dtos[2].order_type, OrderType::CancelDispute));
+    }
+
+    #[tokio::test]
+    async fn test_read_transactions_header_aliases() {
+        let header_aliases = HashMap::from([
+            ("client_id".to_string(), "client".to_string()),
+            ("transaction".to_string(), "tx".to_string()),
+        ]);
+
+        // Non-standard header names and column order.
+        let dtos = read_all(
+            "transaction,amount,client_id,type\n7,2.5,3,withdrawal\n",
+            header_aliases,
+        )
+        .await;
+
+        assert_eq!(dtos.len(), 1);
+        assert!(matches!(dtos[0].order_type, OrderType::Withdrawal));
+        assert_eq!(dtos[0].client, 3);
+        assert_eq!(dtos[0].tx, 7);
+        assert_eq!(dtos[0].amount.as_deref(), Some("2.5"));
+    }
+
+    #[tokio::test]
+    async fn test_read_transactions_format() {
+        let format = CsvFormat {
+            delimiter: b';',
+            quote: b'\'',
+        };
+
+        let csv = "type;client;tx;amount\ndeposit;1;1;'1.5'\n'withdrawal';1;2;0.5\n";
+
+        let stream = read_transactions(csv.as_bytes(), HashMap::new(), format, None);
+
+        futures::pin_mut!(stream);
+
+        let mut dtos = Vec::new();
+        while let Some(result) = stream.next().await {
+            dtos.push(result.unwrap());
+        }
+
+        assert_eq!(dtos.len(), 2);
+        assert_eq!(dtos[0].amount.as_deref(), Some("1.5"));
+        assert!(matches!(dtos[1].order_type, OrderType::Withdrawal));
+        assert_eq!(dtos[1].tx, 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_transactions_with_progress() {
+        let csv = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,1.0
+deposit,1,3
+deposit,1,4,1.0
+deposit,1,5,1.0
+";
+
+        let mut reported = Vec::new();
+
+        let stream = read_transactions_with_progress(
+            csv.as_bytes(),
+            HashMap::new(),
+            CsvFormat::default(),
+            None,
+            NonZeroU64::new(2).unwrap(),
+            |rows| reported.push(rows),
+        );
+
+        assert_eq!(stream.count().await, 5);
+        assert_eq!(reported, [2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+
+        // One row arrives, then the source stalls while keeping the connection open.
+        writer
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,1.5\n")
+            .await
+            .unwrap();
+
+        let read_timeout = Duration::from_millis(50);
+
+        let stream = read_transactions(
+            reader,
+            HashMap::new(),
+            CsvFormat::default(),
+            Some(read_timeout),
+        );
+
+        futures::pin_mut!(stream);
+
+        assert_eq!(stream.next().await.unwrap().unwrap().tx, 1);
+
+        let error = stream.next().await.unwrap().unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<CsvError>(),
+            Some(CsvError::ReadTimeout(timeout)) if *timeout == read_timeout
+        ));
+
+        drop(writer);
+    }
+
+    #[tokio::test]
+    async fn test_read_balances() {
+        let csv =
+            "client,available,held,total,locked\n1,90,0,90,false\n3,-50.9999,100,49.0001,true\n";
+
+        let stream = read_balances(csv.as_bytes());
+
+        futures::pin_mut!(stream);
+
+        let mut opening_balances = Vec::new();
+        while let Some(result) = stream.next().await {
+            opening_balances.push(result.unwrap().to_opening_balance(RoundingMode::default()));
+        }
+
+        assert_eq!(
+            opening_balances,
+            vec![
+                OpeningBalance {
+                    client_id: 1,
+                    available: Money::from_base_units(900_000),
+                    held: Money::ZERO,
+                    locked: false,
+                },
+                OpeningBalance {
+                    client_id: 3,
+                    available: Money::from_base_units(-509_999),
+                    held: Money::from_base_units(1_000_000),
+                    locked: true,
+                },
+            ]
+        );
     }
 }
@@ -0,0 +1,239 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    money::{Money, RoundingMode},
+    transaction_manager::{ClientBalance, ClientId, Currency},
+};
+
+// A field that differs between two balances of the same client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldDiff {
+    Available { old: f64, new: f64 },
+    Held { old: f64, new: f64 },
+    Total { old: f64, new: f64 },
+    Locked { old: bool, new: bool },
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldDiff::Available { old, new } => write!(f, "available {} -> {}", old, new),
+            FieldDiff::Held { old, new } => write!(f, "held {} -> {}", old, new),
+            FieldDiff::Total { old, new } => write!(f, "total {} -> {}", old, new),
+            FieldDiff::Locked { old, new } => write!(f, "locked {} -> {}", old, new),
+        }
+    }
+}
+
+// The fields that differ for one client, in the order available, held, total, locked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceDiff {
+    pub client_id: ClientId,
+    pub currency: Option<Currency>,
+    pub fields: Vec<FieldDiff>,
+}
+
+// e.g. `client 1: available 1.5 -> 2, total 1.5 -> 2`.
+impl fmt::Display for BalanceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.client_id)?;
+
+        if let Some(currency) = &self.currency {
+            write!(f, " ({})", currency)?;
+        }
+
+        for (index, field) in self.fields.iter().enumerate() {
+            let separator = if index == 0 { ":" } else { "," };
+
+            write!(f, "{} {}", separator, field)?;
+        }
+
+        Ok(())
+    }
+}
+
+// A client's old and new balances, either of which may be missing.
+type Pair<'a> = (Option<&'a ClientBalance>, Option<&'a ClientBalance>);
+
+// Compares two sets of balances, e.g. a replay against a reference output, returning a diff for every
+// client, per currency, whose balances differ, ordered by client id. A client missing from one side is
+// compared against an empty unlocked balance, so one omitted for being all zero does not show as a
+// difference. Amounts are compared to four decimal places, ignoring floating point noise.
+pub fn diff_balances(old: &[ClientBalance], new: &[ClientBalance]) -> Vec<BalanceDiff> {
+    let mut pairs: BTreeMap<(ClientId, Option<&Currency>), Pair> = BTreeMap::new();
+
+    for balance in old {
+        pairs
+            .entry((balance.client_id, balance.currency.as_ref()))
+            .or_default()
+            .0 = Some(balance);
+    }
+
+    for balance in new {
+        pairs
+            .entry((balance.client_id, balance.currency.as_ref()))
+            .or_default()
+            .1 = Some(balance);
+    }
+
+    pairs
+        .into_iter()
+        .filter_map(|((client_id, currency), (old, new))| {
+            let fields = diff_fields(old, new);
+
+            (!fields.is_empty()).then(|| BalanceDiff {
+                client_id,
+                currency: currency.cloned(),
+                fields,
+            })
+        })
+        .collect()
+}
+
+fn diff_fields(old: Option<&ClientBalance>, new: Option<&ClientBalance>) -> Vec<FieldDiff> {
+    let amounts = |balance: Option<&ClientBalance>| {
+        balance.map_or((0.0, 0.0, 0.0, false), |balance| {
+            (
+                balance.available,
+                balance.held,
+                balance.total,
+                balance.locked,
+            )
+        })
+    };
+
+    let (old_available, old_held, old_total, old_locked) = amounts(old);
+    let (new_available, new_held, new_total, new_locked) = amounts(new);
+
+    let mut fields = Vec::new();
+
+    if differs(old_available, new_available) {
+        fields.push(FieldDiff::Available {
+            old: old_available,
+            new: new_available,
+        });
+    }
+
+    if differs(old_held, new_held) {
+        fields.push(FieldDiff::Held {
+            old: old_held,
+            new: new_held,
+        });
+    }
+
+    if differs(old_total, new_total) {
+        fields.push(FieldDiff::Total {
+            old: old_total,
+            new: new_total,
+        });
+    }
+
+    if old_locked != new_locked {
+        fields.push(FieldDiff::Locked {
+            old: old_locked,
+            new: new_locked,
+        });
+    }
+
+    fields
+}
+
+fn differs(old: f64, new: f64) -> bool {
+    Money::from_decimal(old, RoundingMode::HalfUp) != Money::from_decimal(new, RoundingMode::HalfUp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance(client_id: ClientId, available: f64, held: f64, locked: bool) -> ClientBalance {
+        ClientBalance {
+            client_id,
+            currency: None,
+            available,
+            held,
+            total: available + held,
+            locked,
+        }
+    }
+
+    #[test]
+    fn test_diff_balances() {
+        let old = [
+            balance(1, 0.3, 0.0, false),
+            balance(2, 5.0, 2.5, false),
+            balance(3, 1.0, 0.0, false),
+            balance(4, 0.0, 0.0, false),
+        ];
+
+        let new = [
+            balance(3, 1.0, 0.0, true),
+            // Sums to 0.3 with floating point noise.
+            balance(1, 0.1 + 0.2, 0.0, false),
+            balance(2, 5.0, 2.0, false),
+            balance(5, 7.0, 0.0, false),
+        ];
+
+        let diffs = diff_balances(&old, &new);
+
+        assert_eq!(
+            diffs,
+            vec![
+                BalanceDiff {
+                    client_id: 2,
+                    currency: None,
+                    fields: vec![
+                        FieldDiff::Held { old: 2.5, new: 2.0 },
+                        FieldDiff::Total { old: 7.5, new: 7.0 },
+                    ],
+                },
+                BalanceDiff {
+                    client_id: 3,
+                    currency: None,
+                    fields: vec![FieldDiff::Locked {
+                        old: false,
+                        new: true,
+                    }],
+                },
+                // Client 4's empty balance matches its absence, while client 5 is new.
+                BalanceDiff {
+                    client_id: 5,
+                    currency: None,
+                    fields: vec![
+                        FieldDiff::Available { old: 0.0, new: 7.0 },
+                        FieldDiff::Total { old: 0.0, new: 7.0 },
+                    ],
+                },
+            ]
+        );
+
+        assert_eq!(
+            diffs[0].to_string(),
+            "client 2: held 2.5 -> 2, total 7.5 -> 7"
+        );
+        assert_eq!(diffs[1].to_string(), "client 3: locked false -> true");
+        assert_eq!(diff_balances(&old, &old), Vec::new());
+    }
+
+    #[test]
+    fn test_diff_balances_per_currency() {
+        let usd = ClientBalance {
+            currency: Some("USD".to_string()),
+            ..balance(1, 1.0, 0.0, false)
+        };
+        let eur = ClientBalance {
+            currency: Some("EUR".to_string()),
+            ..balance(1, 2.5, 0.0, false)
+        };
+
+        let new = [usd.clone(), eur];
+
+        let diffs = diff_balances(&[usd], &new);
+
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            diffs[0].to_string(),
+            "client 1 (EUR): available 0 -> 2.5, total 0 -> 2.5"
+        );
+    }
+}
@@ -1,3 +1,9 @@
 pub mod transaction_manager;
 
 pub mod csv;
+
+pub mod money;
+
+pub mod source;
+
+pub mod diff;
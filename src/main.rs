@@ -1,79 +1,2034 @@
-use clap::Parser;
-use futures::StreamExt;
+use async_compression::tokio::bufread::GzipDecoder;
+use clap::{Parser, ValueEnum};
+use csv_async::{AsyncSerializer, AsyncWriterBuilder};
+use futures::{Stream, StreamExt};
 use payments_engine::{
-    csv,
-    transaction_manager::{ClientBalance, TransactionManager},
+    csv::{self, CsvError, CsvFormat, RoundingMode, TransactionDto},
+    money::RoundingError,
+    transaction_manager::{
+        errors::TransactionError, format_amount, Anomaly, ClientBalance, ClientId, Currency,
+        CurrencyLedger, DuplicatePolicy, OpeningBalance, Timestamp, Transaction,
+        TransactionManager,
+    },
 };
-use std::error;
+use serde::Deserialize;
+use std::{
+    collections::HashMap, error, fmt, io, num::NonZeroUsize, path::Path, process::ExitCode,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    sync::mpsc,
+};
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
+
+// Exit code used when balances were printed but at least one transaction was skipped.
+const SKIPPED_TRANSACTIONS_EXIT_CODE: u8 = 2;
+
+// Bytes of output buffered before writing, roughly two thousand balance rows.
+const OUTPUT_BUFFER_CAPACITY: usize = 64 * 1024;
+
+// Filename that reads the input from stdin, e.g. `cat transactions.csv | payments_engine -`.
+const STDIN_FILENAME: &str = "-";
+
+// Number of transactions that may be queued for each worker before reading the input blocks.
+const WORKER_CHANNEL_CAPACITY: usize = 1024;
+
+// Log target for rows read progress, enabled at info by --progress whatever the verbosity.
+const PROGRESS_TARGET: &str = "payments_engine::progress";
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn error::Error>> {
-    let args = Args::parse();
+async fn main() -> Result<ExitCode, Box<dyn error::Error>> {
+    let mut args = Args::parse();
+
+    // Skipped transactions are logged at warn, so are shown unless --quiet or RUST_LOG says otherwise.
+    let mut filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(args.verbosity().default_filter()));
+
+    if args.progress {
+        filter = filter.add_directive(format!("{}=info", PROGRESS_TARGET).parse()?);
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .init();
+
+    if let Some(filename) = &args.config {
+        let config = Config::load(filename).await?;
+
+        args.merge(config);
+    }
+
+    let input = open_input(&args.filename).await?;
+
+    let workers = args.workers.get();
+
+    // Each worker owns a disjoint set of clients, so opening balances are split the same way as
+    // transactions.
+    let mut shard_opening_balances: Vec<Vec<OpeningBalance>> = vec![Vec::new(); workers];
+
+    if let Some(filename) = &args.opening_balances {
+        for opening_balance in read_opening_balances(filename, args.rounding()).await? {
+            shard_opening_balances[shard(opening_balance.client_id, workers)].push(opening_balance);
+        }
+    }
+
+    let mut managers: Vec<TransactionManager> = shard_opening_balances
+        .into_iter()
+        .map(|opening_balances| {
+            configure(TransactionManager::from_balances(opening_balances), &args)
+        })
+        .collect();
+
+    let mut dead_letter = match &args.dead_letter {
+        Some(filename) => {
+            Some(DeadLetter::new(Box::new(tokio::fs::File::create(filename).await?)).await?)
+        }
+        None => None,
+    };
+
+    info!(input = %args.filename, workers, "Processing transactions");
+
+    // Each engine with the currency it holds, if balances are kept per currency.
+    let (summary, managers): (_, Vec<(Option<Currency>, TransactionManager)>) =
+        if let Some(base_currency) = &args.base_currency {
+            // Opening balances have no currency column, so are in the base currency.
+            let template = args.clone();
+
+            let mut ledger =
+                CurrencyLedger::new(base_currency.clone(), managers.remove(0), move || {
+                    configure(TransactionManager::new(), &template)
+                });
+
+            let summary = process(input, &mut ledger, &args, dead_letter.as_mut()).await?;
+
+            let managers = ledger
+                .into_managers()
+                .into_iter()
+                .map(|(currency, manager)| (Some(currency), manager))
+                .collect();
+
+            (summary, managers)
+        } else if workers > 1 {
+            let (sharded, summary) = process_sharded(input, managers, &args).await?;
+
+            (
+                summary,
+                sharded.into_iter().map(|manager| (None, manager)).collect(),
+            )
+        } else {
+            // A single worker processes on the current task rather than paying for a channel.
+            let summary = process(input, &mut managers[0], &args, dead_letter.as_mut()).await?;
+
+            (
+                summary,
+                managers
+                    .into_iter()
+                    .map(|manager| (None, manager))
+                    .collect(),
+            )
+        };
+
+    info!(
+        skipped = summary.skipped(),
+        "Finished processing transactions"
+    );
 
-    let file = tokio::fs::File::open(&args.filename).await?;
+    for (index, (currency, manager)) in managers.iter().enumerate() {
+        // Only label the output by currency or shard when there is more than one.
+        let label = match currency {
+            Some(currency) => format!(" ({})", currency),
+            None if workers > 1 => format!(" (shard {})", index),
+            None => String::new(),
+        };
 
-    let mut manager = TransactionManager::new();
+        // Checked before anything is printed, so balances known to be wrong are never output.
+        if args.verify {
+            if let Err((client_id, error)) = manager.verify_invariants() {
+                return Err(format!(
+                    "Balance verification{} failed for client {}: {}",
+                    label, client_id, error
+                )
+                .into());
+            }
 
-    let stream = csv::read_transactions(file);
+            // Anomalies are suspicious rather than certainly wrong, so are only warned about. A locked account
+            // with funds available is the normal outcome of a chargeback, so is not warned about here.
+            for anomaly in manager.detect_anomalies() {
+                if let Anomaly::HeldExceedsDeposits { .. } = anomaly {
+                    warn!(anomaly = %anomaly, "Anomaly{} detected", label);
+                }
+            }
+        }
+
+        if args.consistency_report {
+            let report = manager.consistency_report();
+
+            if report.is_consistent() {
+                eprintln!("Consistency report{}: {}", label, report);
+            } else {
+                eprintln!(
+                    "Consistency report{} found a discrepancy: {}",
+                    label, report
+                );
+            }
+        }
+
+        if args.reconcile {
+            let report = manager.consistency_report();
+
+            eprintln!(
+                "Reconciliation{}: balances_total={} net_flows={} discrepancy={}",
+                label,
+                report.balances_total,
+                report.expected_total(),
+                report.discrepancy()
+            );
+        }
+
+        if args.metrics {
+            if !label.is_empty() {
+                eprintln!("Metrics{}:", label);
+            }
+
+            eprintln!("{}", manager.metrics());
+        }
+    }
+
+    let mut balances: Vec<ClientBalance> = managers
+        .iter()
+        .flat_map(|(currency, manager)| {
+            manager.balances().into_iter().map(|balance| ClientBalance {
+                currency: currency.clone(),
+                ..balance
+            })
+        })
+        .collect();
+
+    if args.nonzero_only {
+        balances.retain(|balance| !is_empty(balance));
+    }
+
+    match args.format() {
+        OutputFormat::Csv => {
+            write_balances(
+                tokio::io::stdout(),
+                balances,
+                args.base_currency.is_some(),
+                args.sort_by(),
+                args.group_separator,
+            )
+            .await?
+        }
+        OutputFormat::Table => {
+            write_table(
+                tokio::io::stdout(),
+                balances,
+                args.base_currency.is_some(),
+                args.sort_by(),
+                args.group_separator,
+            )
+            .await?
+        }
+    }
+
+    if args.summary {
+        eprintln!("Skipped transactions: {}", summary);
+        eprintln!("Rows processed: {}", args.skip_rows + summary.rows);
+    }
+
+    if summary.skipped() > 0 {
+        Ok(ExitCode::from(SKIPPED_TRANSACTIONS_EXIT_CODE))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+// Applies the options that configure an engine, for every worker or currency.
+fn configure(manager: TransactionManager, args: &Args) -> TransactionManager {
+    manager
+        .with_replay(args.replay)
+        .with_duplicate_policy(args.duplicate_policy())
+        .with_metrics(args.metrics)
+        .with_reject_client_zero(args.reject_client_zero)
+        .with_withdrawal_holds(args.withdrawal_holds)
+        .with_partial_withdrawals(args.partial_withdrawal)
+        .with_evict_finalized(args.evict_finalized)
+        .with_trust_stored_client(args.trust_stored_client)
+}
+
+// Where a row's transaction is applied: a single engine, or one per currency.
+trait Engine {
+    fn accept_row(
+        &mut self,
+        transaction: Transaction,
+        dto: &TransactionDto,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError>;
+}
+
+impl Engine for TransactionManager {
+    fn accept_row(
+        &mut self,
+        transaction: Transaction,
+        dto: &TransactionDto,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError> {
+        self.accept_with_rounding_error(transaction, dto.timestamp, rounding_error)
+    }
+}
+
+impl Engine for CurrencyLedger {
+    fn accept_row(
+        &mut self,
+        transaction: Transaction,
+        dto: &TransactionDto,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError> {
+        self.accept_with_rounding_error(
+            transaction,
+            dto.currency.as_deref(),
+            dto.timestamp,
+            rounding_error,
+        )
+    }
+}
+
+// Open the input file, transparently decompressing it if it has a `.gz` extension. A filename of `-` reads
+// from stdin.
+async fn open_input(filename: &str) -> io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    if filename == STDIN_FILENAME {
+        return Ok(Box::new(tokio::io::stdin()));
+    }
+
+    let file = tokio::fs::File::open(filename).await?;
+
+    if Path::new(filename)
+        .extension()
+        .is_some_and(|extension| extension == "gz")
+    {
+        Ok(Box::new(GzipDecoder::new(BufReader::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+async fn read_opening_balances(
+    filename: &str,
+    rounding: RoundingMode,
+) -> Result<Vec<OpeningBalance>, Box<dyn error::Error>> {
+    let stream = csv::read_balances(open_input(filename).await?);
+
+    let mut opening_balances = Vec::new();
 
     futures::pin_mut!(stream);
     while let Some(result) = stream.next().await {
-        let dto = result?;
-
-        dto.to_transaction()
-            .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
-            .and_then(|tx| {
-                manager
-                    .accept(tx)
-                    .map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
-            })
-            .unwrap_or_else(|err| {
-                eprintln!("Ignoring transaction with error: id={} err={}", dto.tx, err)
+        opening_balances.push(result?.to_opening_balance(rounding));
+    }
+
+    Ok(opening_balances)
+}
+
+// Read the transactions in the input, logging the number of rows read at info as it goes.
+fn read_input<R>(
+    input: R,
+    args: &Args,
+) -> impl Stream<Item = Result<TransactionDto, Box<dyn error::Error>>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    // The reader consumes the header itself, so only data rows are skipped.
+    csv::read_transactions_with_progress(
+        input,
+        args.header_aliases.iter().cloned().collect(),
+        args.csv_format(),
+        args.read_timeout_secs.map(Duration::from_secs),
+        csv::DEFAULT_PROGRESS_INTERVAL,
+        |rows| info!(target: PROGRESS_TARGET, rows, "Read rows"),
+    )
+    .skip(args.skip_rows)
+}
+
+// Apply every transaction in the input to the manager, returning a count of skipped transactions by error
+// and writing each rejected row to the dead letter output if there is one. With --limit, stops reading
+// once that many transactions have been applied. The dead letter output is flushed even if processing
+// fails, and includes the row that aborted it in strict mode.
+async fn process<R>(
+    input: R,
+    manager: &mut impl Engine,
+    args: &Args,
+    mut dead_letter: Option<&mut DeadLetter<'_>>,
+) -> Result<ErrorSummary, Box<dyn error::Error>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let result = process_rows(input, manager, args, dead_letter.as_deref_mut()).await;
+
+    let flushed = match dead_letter {
+        Some(dead_letter) => dead_letter.flush().await,
+        None => Ok(()),
+    };
+
+    // An error from processing takes precedence over one from flushing.
+    let summary = result?;
+
+    flushed?;
+
+    Ok(summary)
+}
+
+// Reads TransactionDto rows rather than a TransactionSource: each row's timestamp, its tx and client for
+// logging a rejected row, and its raw amount for --strict-precision do not survive conversion to a
+// Transaction, so the source trait is for library callers only and out of scope for the binary.
+async fn process_rows<R>(
+    input: R,
+    manager: &mut impl Engine,
+    args: &Args,
+    mut dead_letter: Option<&mut DeadLetter<'_>>,
+) -> Result<ErrorSummary, Box<dyn error::Error>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let stream = read_input(input, args);
+
+    let mut summary = ErrorSummary::default();
+    let mut applied = 0;
+
+    futures::pin_mut!(stream);
+    while args.limit.is_none_or(|limit| applied < limit) {
+        let Some(result) = stream.next().await else {
+            break;
+        };
+
+        summary.rows += 1;
+
+        let Some(dto) = read_row(result, args.strict, &mut summary)? else {
+            continue;
+        };
+
+        if !args.includes_client(dto.client) || !args.includes_timestamp(dto.timestamp) {
+            continue;
+        }
+
+        match apply(
+            manager,
+            &dto,
+            args.rounding(),
+            args.strict_precision,
+            args.strict,
+        ) {
+            Ok(Some(error)) => {
+                if let Some(dead_letter) = dead_letter.as_deref_mut() {
+                    dead_letter.write(&dto, &error).await?;
+                }
+
+                summary.record(error);
+            }
+            Ok(None) => applied += 1,
+            Err(aborted) => {
+                if let Some(dead_letter) = dead_letter.as_deref_mut() {
+                    dead_letter.write(&dto, &aborted.name).await?;
+                }
+
+                return Err(aborted.into());
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// Apply every transaction in the input across one worker task per manager, routing each transaction by
+// client id so a client's transactions are always applied in order by the same worker. Disputes,
+// resolves and chargebacks name the same client as the original transaction so they reach the worker
+// that holds it. Returns the managers in shard order and the skipped transactions across all workers.
+//
+// As each worker only sees its own clients' transactions, a transaction id reused by a different
+// client is only detected as a duplicate if both clients fall in the same shard.
+async fn process_sharded<R>(
+    input: R,
+    managers: Vec<TransactionManager>,
+    args: &Args,
+) -> Result<(Vec<TransactionManager>, ErrorSummary), Box<dyn error::Error>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let workers = managers.len();
+    let rounding = args.rounding();
+    let strict_precision = args.strict_precision;
+    let strict = args.strict;
+
+    let (senders, handles): (Vec<_>, Vec<_>) = managers
+        .into_iter()
+        .map(|mut manager| {
+            let (sender, mut receiver) = mpsc::channel::<TransactionDto>(WORKER_CHANNEL_CAPACITY);
+
+            let handle = tokio::spawn(async move {
+                let mut summary = ErrorSummary::default();
+
+                while let Some(dto) = receiver.recv().await {
+                    if let Some(error) =
+                        apply(&mut manager, &dto, rounding, strict_precision, strict)?
+                    {
+                        summary.record(error);
+                    }
+                }
+
+                Ok::<_, Aborted>((manager, summary))
             });
+
+            (sender, handle)
+        })
+        .unzip();
+
+    let stream = read_input(input, args);
+
+    // Malformed rows are counted by the reader rather than the workers.
+    let mut summary = ErrorSummary::default();
+
+    futures::pin_mut!(stream);
+    while let Some(result) = stream.next().await {
+        summary.rows += 1;
+
+        let Some(dto) = read_row(result, args.strict, &mut summary)? else {
+            continue;
+        };
+
+        if !args.includes_client(dto.client) || !args.includes_timestamp(dto.timestamp) {
+            continue;
+        }
+
+        // A worker only stops receiving when it has aborted in strict mode; its error is reported below.
+        if senders[shard(dto.client, workers)].send(dto).await.is_err() {
+            break;
+        }
     }
 
-    let balances = manager.balances();
+    // Close the channels so the workers finish once they have drained them.
+    drop(senders);
 
-    print_balances(balances);
+    let mut managers = Vec::with_capacity(workers);
 
-    Ok(())
+    for handle in handles {
+        let (manager, worker_summary) = handle.await??;
+
+        managers.push(manager);
+        summary.merge(worker_summary);
+    }
+
+    Ok((managers, summary))
+}
+
+// Unwraps a row read from the input. A malformed row, e.g. one with the wrong number of columns, is
+// logged, recorded in the summary and skipped by returning None, unless in strict mode. Any other error,
+// such as failing to read the input, is returned.
+fn read_row(
+    result: Result<TransactionDto, Box<dyn error::Error>>,
+    strict: bool,
+    summary: &mut ErrorSummary,
+) -> Result<Option<TransactionDto>, Box<dyn error::Error>> {
+    let error = match result {
+        Ok(dto) => return Ok(Some(dto)),
+        Err(error) => error,
+    };
+
+    match error.downcast_ref::<CsvError>() {
+        Some(malformed @ CsvError::MalformedRow { row, message }) if !strict => {
+            warn!(row, error = message.as_str(), "Skipping malformed row");
+
+            summary.record(malformed.name().to_string());
+
+            Ok(None)
+        }
+        _ => Err(error),
+    }
+}
+
+// Apply a single transaction, returning the name of the error if it was skipped. In strict mode a
+// rejected transaction is returned as an error instead. With strict precision, amounts that would need
+// rounding are rejected rather than rounded.
+fn apply(
+    manager: &mut impl Engine,
+    dto: &TransactionDto,
+    rounding: RoundingMode,
+    strict_precision: bool,
+    strict: bool,
+) -> Result<Option<String>, Aborted> {
+    // Exact amounts need no rounding, so leave no rounding error.
+    let transaction = if strict_precision {
+        dto.to_transaction_exact()
+            .map(|tx| (tx, RoundingError::ZERO))
+    } else {
+        dto.to_transaction_with_rounding_error(rounding)
+    };
+
+    // Only format the error when the transaction is rejected. A rejected transaction is kept so its type
+    // and amount can be logged; a row that could not be converted has none.
+    let outcome = match transaction {
+        Ok((tx, rounding_error)) => manager
+            .accept_row(tx.clone(), dto, rounding_error)
+            .map_err(|err| (err.name().to_string(), err.to_string(), Some(tx))),
+        Err(err) => Err((err.name().to_string(), err.to_string(), None)),
+    };
+
+    match outcome {
+        Ok(()) => {
+            debug!(tx = dto.tx, client = dto.client, "Applied transaction");
+
+            Ok(None)
+        }
+        Err((name, error, _)) if strict => Err(Aborted {
+            name,
+            message: format!(
+                "Aborting on transaction with error: tx={} client={} err={}",
+                dto.tx, dto.client, error
+            ),
+        }),
+        Err((name, error, transaction)) => {
+            warn!(
+                tx = dto.tx,
+                client = dto.client,
+                transaction = transaction.as_ref().map(tracing::field::display),
+                error,
+                "Ignoring transaction"
+            );
+
+            Ok(Some(name))
+        }
+    }
+}
+
+// A transaction rejected in strict mode, with the name of its error for the dead letter output.
+#[derive(Debug)]
+struct Aborted {
+    name: String,
+    message: String,
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for Aborted {}
+
+// Writes rejected rows as CSV with the name of their error in an extra `error` column, so they can be
+// corrected and processed again. Malformed rows could not be read, so are only counted in the summary.
+struct DeadLetter<'w> {
+    writer: AsyncSerializer<BufWriter<Box<dyn AsyncWrite + Unpin + Send + 'w>>>,
+}
+
+impl<'w> DeadLetter<'w> {
+    async fn new(
+        writer: Box<dyn AsyncWrite + Unpin + Send + 'w>,
+    ) -> Result<DeadLetter<'w>, csv_async::Error> {
+        let mut writer = AsyncWriterBuilder::new()
+            .has_headers(false)
+            .create_serializer(BufWriter::new(writer));
+
+        // The currency column is always written, so every row has the same columns.
+        writer
+            .serialize([
+                "type",
+                "client",
+                "tx",
+                "amount",
+                "timestamp",
+                "currency",
+                "error",
+            ])
+            .await?;
+
+        Ok(DeadLetter { writer })
+    }
+
+    async fn write(&mut self, dto: &TransactionDto, error: &str) -> Result<(), csv_async::Error> {
+        self.writer
+            .serialize((
+                &dto.order_type,
+                dto.client,
+                dto.tx,
+                &dto.amount,
+                dto.timestamp,
+                &dto.currency,
+                error,
+            ))
+            .await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
 }
 
-// Print the balances CSV to stdout.
-fn print_balances(mut balances: Vec<ClientBalance>) {
-    // Header
-    println!("client,available,held,total,locked");
+// Number of skipped transactions per error, and the number of rows read.
+#[derive(Debug, Default, PartialEq)]
+struct ErrorSummary {
+    errors: HashMap<String, usize>,
+    // Rows read after any skipped with --skip-rows, whether applied, skipped or filtered out.
+    rows: usize,
+}
 
-    // Not necessary, but sorting by client ID for better visual inspection.
-    balances.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+impl ErrorSummary {
+    fn record(&mut self, error: String) {
+        *self.errors.entry(error).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: ErrorSummary) {
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_default() += count;
+        }
+
+        self.rows += other.rows;
+    }
+
+    fn skipped(&self) -> usize {
+        self.errors.values().sum()
+    }
+}
+
+// Most frequent errors first, e.g. `InsufficientFunds: 12, DuplicateTransaction: 3`.
+impl fmt::Display for ErrorSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.errors.is_empty() {
+            return write!(f, "none");
+        }
+
+        let mut counts: Vec<_> = self.errors.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        for (index, (error, count)) in counts.into_iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}: {}", error, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The worker responsible for a client.
+fn shard(client_id: ClientId, workers: usize) -> usize {
+    usize::from(client_id) % workers
+}
+
+// Write the balances CSV, e.g. to stdout.
+async fn write_balances<W>(
+    writer: W,
+    mut balances: Vec<ClientBalance>,
+    currency_column: bool,
+    sort_by: SortBy,
+    group_separator: Option<char>,
+) -> Result<(), Box<dyn error::Error>>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    // The header is written explicitly so it is present even when there are no balances. The serializer
+    // writes each row as it is serialized, so rows are buffered to write them out in large chunks as each
+    // write to stdout is costly.
+    let mut writer = AsyncWriterBuilder::new()
+        .has_headers(false)
+        .create_serializer(BufWriter::with_capacity(OUTPUT_BUFFER_CAPACITY, writer));
+
+    if currency_column {
+        writer
+            .serialize(["client", "currency", "available", "held", "total", "locked"])
+            .await?;
+    } else {
+        writer
+            .serialize(["client", "available", "held", "total", "locked"])
+            .await?;
+    }
+
+    // Not necessary, but sorting for better visual inspection.
+    sort_balances(&mut balances, sort_by);
 
-    // Balances
     for balance in balances {
-        // Print to 4 decimal just in case we get some weird floating point approximation like 100.5555000000001234.
-        println!(
-            "{},{},{},{},{}",
-            balance.client_id,
-            format_4_decimals(balance.available),
-            format_4_decimals(balance.held),
-            format_4_decimals(balance.total),
-            balance.locked
-        );
+        match group_separator {
+            None => writer.serialize(&balance).await?,
+            // Grouped amounts are for human-facing reports, so are formatted here rather than by the
+            // serializer.
+            Some(separator) => {
+                let amounts = (
+                    format_4_decimals(balance.available, Some(separator)),
+                    format_4_decimals(balance.held, Some(separator)),
+                    format_4_decimals(balance.total, Some(separator)),
+                );
+
+                match &balance.currency {
+                    Some(currency) => {
+                        writer
+                            .serialize((balance.client_id, currency, amounts, balance.locked))
+                            .await?
+                    }
+                    None => {
+                        writer
+                            .serialize((balance.client_id, amounts, balance.locked))
+                            .await?
+                    }
+                }
+            }
+        }
     }
+
+    writer.flush().await?;
+
+    Ok(())
 }
 
-fn format_4_decimals(value: f64) -> String {
-    let formatted = format!("{:.4}", value);
+// Write the balances as an aligned table for reading in a terminal, with amounts right-aligned under a
+// header. Takes the same options as write_balances.
+async fn write_table<W>(
+    mut writer: W,
+    mut balances: Vec<ClientBalance>,
+    currency_column: bool,
+    sort_by: SortBy,
+    group_separator: Option<char>,
+) -> Result<(), Box<dyn error::Error>>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    sort_balances(&mut balances, sort_by);
+
+    // Cells and whether they are right-aligned, per column.
+    let mut columns: Vec<(&str, bool, Vec<String>)> = vec![("client", true, Vec::new())];
+
+    if currency_column {
+        columns.push(("currency", false, Vec::new()));
+    }
+
+    columns.extend([
+        ("available", true, Vec::new()),
+        ("held", true, Vec::new()),
+        ("total", true, Vec::new()),
+        ("locked", false, Vec::new()),
+    ]);
 
-    // Trim excess zeros.
-    formatted
-        .trim_end_matches('0')
-        .trim_end_matches('.')
-        .to_string()
+    for balance in &balances {
+        let mut cells = vec![balance.client_id.to_string()];
+
+        if currency_column {
+            cells.push(balance.currency.clone().unwrap_or_default());
+        }
+
+        cells.extend([
+            format_4_decimals(balance.available, group_separator),
+            format_4_decimals(balance.held, group_separator),
+            format_4_decimals(balance.total, group_separator),
+            balance.locked.to_string(),
+        ]);
+
+        for ((_, _, column), cell) in columns.iter_mut().zip(cells) {
+            column.push(cell);
+        }
+    }
+
+    let widths: Vec<usize> = columns
+        .iter()
+        .map(|(header, _, cells)| {
+            cells
+                .iter()
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+                .max(header.len())
+        })
+        .collect();
+
+    let line = |cell: &dyn Fn(usize) -> String| {
+        let cells: Vec<String> = columns
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(index, ((_, right, _), &width))| {
+                if *right {
+                    format!("{:>width$}", cell(index))
+                } else {
+                    format!("{:<width$}", cell(index))
+                }
+            })
+            .collect();
+
+        format!("{}\n", cells.join("  ").trim_end())
+    };
+
+    let mut table = line(&|index| columns[index].0.to_string());
+
+    table.push_str(&line(&|index| "-".repeat(widths[index])));
+
+    for row in 0..balances.len() {
+        table.push_str(&line(&|index| columns[index].2[row].clone()));
+    }
+
+    writer.write_all(table.as_bytes()).await?;
+    writer.flush().await?;
+
+    Ok(())
 }
 
-#[derive(Parser, Debug)]
+// Whether the balance is all zero on an unlocked account, i.e. carries no information worth reporting.
+fn is_empty(balance: &ClientBalance) -> bool {
+    balance.available == 0.0 && balance.held == 0.0 && balance.total == 0.0 && !balance.locked
+}
+
+fn sort_balances(balances: &mut [ClientBalance], sort_by: SortBy) {
+    match sort_by {
+        SortBy::Client => balances.sort_by(|a, b| {
+            a.client_id
+                .cmp(&b.client_id)
+                .then_with(|| a.currency.cmp(&b.currency))
+        }),
+        // Largest totals first, ties broken by client ID so the output is deterministic.
+        SortBy::Total => balances.sort_by(|a, b| {
+            b.total
+                .total_cmp(&a.total)
+                .then_with(|| a.client_id.cmp(&b.client_id))
+                .then_with(|| a.currency.cmp(&b.currency))
+        }),
+    }
+}
+
+fn format_4_decimals(value: f64, group_separator: Option<char>) -> String {
+    let formatted = format_amount(value);
+
+    match group_separator {
+        Some(separator) => group_thousands(&formatted, separator),
+        None => formatted,
+    }
+}
+
+// Insert the separator between every group of three digits in the integer part, leaving any sign and
+// fractional part untouched.
+fn group_thousands(value: &str, separator: char) -> String {
+    let (sign, unsigned) = match value.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", value),
+    };
+
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(index) => unsigned.split_at(index),
+        None => (unsigned, ""),
+    };
+
+    let mut grouped = String::with_capacity(value.len() + integer.len() / 3);
+
+    grouped.push_str(sign);
+
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            grouped.push(separator);
+        }
+
+        grouped.push(digit);
+    }
+
+    grouped.push_str(fraction);
+
+    grouped
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Transactions CSV to process, or `-` to read from stdin.
     #[arg(index = 1)]
     filename: String,
+
+    /// Read options from a TOML file, or JSON if the file has a `.json` extension. Options given on the
+    /// command line take precedence.
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
+    /// How client balances are printed [default: csv].
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Order in which client balances are printed [default: client].
+    #[arg(long, value_enum)]
+    sort_by: Option<SortBy>,
+
+    /// Insert this separator between thousands in amounts, e.g. `,` for 1,234,567.89. Intended for
+    /// human-facing reports; by default amounts are not grouped.
+    #[arg(long)]
+    group_separator: Option<char>,
+
+    /// How amounts with more than four decimal places are rounded [default: half-up].
+    #[arg(long, value_enum)]
+    rounding: Option<RoundingMode>,
+
+    /// Reject amounts with non-zero digits beyond four decimal places instead of rounding them.
+    #[arg(long)]
+    strict_precision: bool,
+
+    /// Treat an input header as another column name, e.g. `--header-alias client_id=client`. May be
+    /// repeated.
+    #[arg(long = "header-alias", value_name = "ALIAS=NAME", value_parser = parse_header_alias)]
+    header_aliases: Vec<(String, String)>,
+
+    /// Field delimiter of the input, e.g. `;`. Must be a single ASCII character [default: ,].
+    #[arg(long, value_parser = parse_ascii_byte)]
+    delimiter: Option<u8>,
+
+    /// Quote character of the input, e.g. `'`. Must be a single ASCII character [default: "].
+    #[arg(long, value_parser = parse_ascii_byte)]
+    quote: Option<u8>,
+
+    /// Abort if no input row arrives within this many seconds, e.g. when reading from a stalled pipe. By
+    /// default the engine waits indefinitely.
+    #[arg(long, value_name = "SECS")]
+    read_timeout_secs: Option<u64>,
+
+    /// Check every balance's total equals available plus held after processing, failing instead of
+    /// printing balances if not.
+    #[arg(long)]
+    verify: bool,
+
+    /// Log the number of rows read every 100,000 rows, even with --quiet.
+    #[arg(long)]
+    progress: bool,
+
+    /// Print a report to stderr reconciling the output balances against the amounts processed.
+    #[arg(long)]
+    consistency_report: bool,
+
+    /// Print a line to stderr comparing the sum of client totals against the net of deposits,
+    /// withdrawals and chargebacks.
+    #[arg(long)]
+    reconcile: bool,
+
+    /// Ignore deposits and withdrawals resubmitted with the same id, client and amount instead of
+    /// rejecting them as duplicates.
+    #[arg(long)]
+    replay: bool,
+
+    /// How a deposit reusing the id of an earlier deposit is handled. `replace` reverses the earlier
+    /// deposit and applies the later one in its place [default: reject].
+    #[arg(long, value_enum)]
+    duplicate_policy: Option<DuplicatePolicy>,
+
+    /// Abort on the first rejected transaction instead of skipping it.
+    #[arg(long)]
+    strict: bool,
+
+    /// Hold withdrawn funds until a settle_withdrawal row rather than deducting them immediately.
+    #[arg(long)]
+    withdrawal_holds: bool,
+
+    /// Withdraw as much as is available, draining the account, when a withdrawal exceeds the available
+    /// funds instead of rejecting it.
+    #[arg(long)]
+    partial_withdrawal: bool,
+
+    /// Forget resolved and charged back transactions to bound memory on long inputs. They can no longer
+    /// be disputed.
+    #[arg(long)]
+    evict_finalized: bool,
+
+    /// Apply disputes, resolves, chargebacks and cancelled disputes to the client of the transaction they
+    /// reference, ignoring their client column.
+    #[arg(long)]
+    trust_stored_client: bool,
+
+    /// Reject transactions for client 0, for feeds that use it to mean an unknown client.
+    #[arg(long)]
+    reject_client_zero: bool,
+
+    /// Only process transactions for these clients, e.g. `--only-clients 1,2,3`.
+    #[arg(long, value_delimiter = ',')]
+    only_clients: Vec<ClientId>,
+
+    /// Skip transactions for these clients, e.g. `--exclude-clients 5,6`.
+    #[arg(long, value_delimiter = ',')]
+    exclude_clients: Vec<ClientId>,
+
+    /// Skip transactions timestamped before this RFC 3339 time, e.g. `2024-05-01T00:00:00Z`.
+    #[arg(long, value_name = "TIMESTAMP")]
+    from: Option<Timestamp>,
+
+    /// Skip transactions timestamped at or after this RFC 3339 time.
+    #[arg(long, value_name = "TIMESTAMP")]
+    to: Option<Timestamp>,
+
+    /// Print per-operation counts and timings to stderr after processing.
+    #[arg(long)]
+    metrics: bool,
+
+    /// Start from the balances in this file, in the same format as the output, instead of zero.
+    #[arg(long, value_name = "FILE")]
+    opening_balances: Option<String>,
+
+    /// Print the number of skipped transactions per error to stderr after processing, and the number of
+    /// rows to pass to --skip-rows to resume after them.
+    #[arg(long)]
+    summary: bool,
+
+    /// Write every rejected row to this CSV file, with the name of its error in an `error` column.
+    #[arg(long, value_name = "FILE", conflicts_with = "workers")]
+    dead_letter: Option<String>,
+
+    /// Stop reading after this many transactions have been applied, not counting skipped or filtered
+    /// ones, e.g. to preview the balances of a large file.
+    #[arg(long, value_name = "N", conflicts_with = "workers")]
+    limit: Option<usize>,
+
+    /// Skip this many rows after the header, e.g. to resume from the offset printed by --summary.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    skip_rows: usize,
+
+    /// Omit clients whose available, held and total balances are all zero, unless their account is
+    /// locked.
+    #[arg(long)]
+    nonzero_only: bool,
+
+    /// Number of worker tasks to process transactions on, each handling a disjoint set of clients.
+    #[arg(long, default_value = "1", value_name = "N")]
+    workers: NonZeroUsize,
+
+    /// Keep each client's balances separate per currency, read from an optional `currency` column. Rows
+    /// without a currency, and opening balances, are in this currency. Adds a currency column to the
+    /// output.
+    #[arg(long, value_name = "CODE", conflicts_with = "workers")]
+    base_currency: Option<Currency>,
+
+    /// Do not log skipped transactions, only errors and the reports asked for.
+    #[arg(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also log every applied transaction.
+    #[arg(long, short)]
+    verbose: bool,
+}
+
+impl Args {
+    // Fill in any options not given on the command line from the config file.
+    fn merge(&mut self, config: Config) {
+        self.sort_by = self.sort_by.or(config.sort_by);
+        self.group_separator = self.group_separator.or(config.group_separator);
+        self.rounding = self.rounding.or(config.rounding);
+        self.format = self.format.or(config.format);
+        self.duplicate_policy = self.duplicate_policy.or(config.duplicate_policy);
+    }
+
+    fn format(&self) -> OutputFormat {
+        self.format.unwrap_or(OutputFormat::Csv)
+    }
+
+    fn sort_by(&self) -> SortBy {
+        self.sort_by.unwrap_or(SortBy::Client)
+    }
+
+    fn duplicate_policy(&self) -> DuplicatePolicy {
+        self.duplicate_policy.unwrap_or_default()
+    }
+
+    fn rounding(&self) -> RoundingMode {
+        self.rounding.unwrap_or_default()
+    }
+
+    fn csv_format(&self) -> CsvFormat {
+        let default = CsvFormat::default();
+
+        CsvFormat {
+            delimiter: self.delimiter.unwrap_or(default.delimiter),
+            quote: self.quote.unwrap_or(default.quote),
+        }
+    }
+
+    fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
+    }
+
+    // Whether transactions for the client pass the client filters.
+    fn includes_client(&self, client_id: ClientId) -> bool {
+        (self.only_clients.is_empty() || self.only_clients.contains(&client_id))
+            && !self.exclude_clients.contains(&client_id)
+    }
+
+    // Whether a transaction falls in the --from/--to window. Rows without a timestamp cannot be placed,
+    // so are always included.
+    fn includes_timestamp(&self, timestamp: Option<Timestamp>) -> bool {
+        let Some(timestamp) = timestamp else {
+            return true;
+        };
+
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+}
+
+// Options that may be set in a config file, using the same names as the command line flags.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    sort_by: Option<SortBy>,
+    group_separator: Option<char>,
+    rounding: Option<RoundingMode>,
+    format: Option<OutputFormat>,
+    duplicate_policy: Option<DuplicatePolicy>,
+}
+
+impl Config {
+    async fn load(filename: &str) -> Result<Config, Box<dyn error::Error>> {
+        let contents = tokio::fs::read_to_string(filename).await?;
+
+        if Path::new(filename)
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            Ok(serde_json::from_str(&contents)?)
+        } else {
+            Ok(toml::from_str(&contents)?)
+        }
+    }
+}
+
+fn parse_ascii_byte(value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(format!(
+            "expected a single ASCII character, got `{}`",
+            value
+        )),
+    }
+}
+
+fn parse_header_alias(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((alias, name)) if !alias.is_empty() && !name.is_empty() => {
+            Ok((alias.to_string(), name.to_string()))
+        }
+        _ => Err(format!("expected ALIAS=NAME, got `{}`", value)),
+    }
+}
+
+// How much is logged to stderr while processing. RUST_LOG, if set, takes precedence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Verbosity {
+    // Errors only.
+    Quiet,
+    // Skipped transactions as well.
+    Normal,
+    // Applied transactions as well. Dependencies stay at warnings so their debug output doesn't drown ours.
+    Verbose,
+}
+
+impl Verbosity {
+    fn default_filter(self) -> &'static str {
+        match self {
+            Verbosity::Quiet => "error",
+            Verbosity::Normal => "warn",
+            Verbosity::Verbose => "warn,payments_engine=debug",
+        }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum OutputFormat {
+    /// CSV, in the same format as read by --opening-balances.
+    Csv,
+    /// An aligned table for reading in a terminal.
+    Table,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum SortBy {
+    /// Ascending by client ID.
+    Client,
+    /// Descending by total balance.
+    Total,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::write::GzipEncoder;
+    use payments_engine::transaction_manager::errors::TransactionError;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    const TRANSACTIONS: &str = "type,client,tx,amount
+deposit,1,1,100.5
+deposit,2,2,50
+withdrawal,1,3,20.25
+dispute,2,2,
+";
+
+    fn args(filename: &str) -> Args {
+        Args::parse_from(["payments_engine", filename])
+    }
+
+    async fn balances_from_file(path: &Path) -> Vec<ClientBalance> {
+        let filename = path.to_str().unwrap();
+
+        let input = open_input(filename).await.unwrap();
+
+        let mut manager = TransactionManager::new();
+
+        let summary = process(input, &mut manager, &args(filename), None)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.skipped(), 0);
+
+        let mut balances = manager.balances();
+        sort_balances(&mut balances, SortBy::Client);
+
+        balances
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let config: Config = toml::from_str(
+            r#"
+            sort-by = "total"
+            group-separator = ","
+            rounding = "half-even"
+            format = "table"
+            duplicate-policy = "replace"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                sort_by: Some(SortBy::Total),
+                group_separator: Some(','),
+                rounding: Some(RoundingMode::HalfEven),
+                format: Some(OutputFormat::Table),
+                duplicate_policy: Some(DuplicatePolicy::Replace),
+            }
+        );
+
+        let json_config: Config = serde_json::from_str(
+            r#"{"sort-by": "total", "group-separator": ",", "rounding": "half-even", "format": "table", "duplicate-policy": "replace"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(json_config, config);
+
+        let mut args = Args::parse_from(["payments_engine", "-", "--rounding", "truncate"]);
+
+        args.merge(config);
+
+        // Command line flags take precedence over the config file.
+        assert_eq!(args.rounding(), RoundingMode::Truncate);
+        assert_eq!(args.sort_by(), SortBy::Total);
+        assert_eq!(args.group_separator, Some(','));
+        assert_eq!(args.format(), OutputFormat::Table);
+        assert_eq!(args.duplicate_policy(), DuplicatePolicy::Replace);
+
+        let args = Args::parse_from(["payments_engine", "-"]);
+
+        assert_eq!(args.rounding(), RoundingMode::HalfUp);
+        assert_eq!(args.sort_by(), SortBy::Client);
+        assert_eq!(args.format(), OutputFormat::Csv);
+        assert_eq!(args.duplicate_policy(), DuplicatePolicy::Reject);
+    }
+
+    #[tokio::test]
+    async fn test_delimiter_and_quote() {
+        let args = Args::parse_from(["payments_engine", "-", "--delimiter", "\t", "--quote", "'"]);
+
+        assert_eq!(
+            args.csv_format(),
+            CsvFormat {
+                delimiter: b'\t',
+                quote: b'\'',
+            }
+        );
+
+        let input = TRANSACTIONS.replace(',', "\t");
+
+        let mut manager = TransactionManager::new();
+        let summary = process(input.as_bytes(), &mut manager, &args, None)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.skipped(), 0);
+        assert_eq!(manager.balances().len(), 2);
+
+        assert!(Args::try_parse_from(["payments_engine", "-", "--delimiter", ";;"]).is_err());
+        assert!(Args::try_parse_from(["payments_engine", "-", "--quote", "é"]).is_err());
+    }
+
+    #[test]
+    fn test_config_unknown_option() {
+        assert!(toml::from_str::<Config>("sort = \"total\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_filters() {
+        let mut manager = TransactionManager::new();
+
+        let args = Args::parse_from(["payments_engine", "-", "--only-clients", "1,2,3"]);
+        let summary = process(TRANSACTIONS.as_bytes(), &mut manager, &args, None)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.skipped(), 0);
+        assert_eq!(manager.balances().len(), 2);
+
+        let mut manager = TransactionManager::new();
+
+        let args = Args::parse_from([
+            "payments_engine",
+            "-",
+            "--only-clients",
+            "1,2",
+            "--exclude-clients",
+            "2",
+        ]);
+        let summary = process(TRANSACTIONS.as_bytes(), &mut manager, &args, None)
+            .await
+            .unwrap();
+
+        // Filtered transactions, including the dispute for client 2, are not errors.
+        assert_eq!(summary.skipped(), 0);
+
+        let balances = manager.balances();
+        assert_eq!(balances.len(), 1);
+        assert_eq!(balances[0].client_id, 1);
+        assert_eq!(balances[0].total, 80.25);
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_window() {
+        let csv = "type,client,tx,amount,timestamp
+deposit,1,1,1.0,2024-04-30T23:59:59Z
+deposit,1,2,2.0,2024-05-01T00:00:00Z
+deposit,1,3,4.0,2024-05-01T12:00:00Z
+deposit,1,4,8.0,2024-05-02T00:00:00Z
+dispute,1,3,,
+dispute,1,1,,2024-05-01T13:00:00Z
+";
+
+        let mut manager = TransactionManager::new();
+
+        let args = Args::parse_from([
+            "payments_engine",
+            "-",
+            "--from",
+            "2024-05-01T00:00:00Z",
+            "--to",
+            "2024-05-02T00:00:00Z",
+        ]);
+        let summary = process(csv.as_bytes(), &mut manager, &args, None)
+            .await
+            .unwrap();
+
+        // The deposits before and after the window are filtered rather than skipped, while the dispute
+        // inside the window of the deposit before it is skipped as not found.
+        assert_eq!(summary.skipped(), 1);
+
+        let balances = manager.balances();
+        assert_eq!(balances[0].available, 2.0);
+        assert_eq!(balances[0].held, 4.0);
+        assert_eq!(balances[0].total, 6.0);
+    }
+
+    #[tokio::test]
+    async fn test_skip_rows() {
+        let mut manager = TransactionManager::new();
+
+        // A first run interrupted after two rows.
+        let partial = TRANSACTIONS.lines().take(3).collect::<Vec<_>>().join("\n");
+
+        let summary = process(partial.as_bytes(), &mut manager, &args("-"), None)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.rows, 2);
+
+        // Resume with the same engine, as if restored, from the offset reached.
+        let resume = Args::parse_from(["payments_engine", "-", "--skip-rows", "2"]);
+        let summary = process(TRANSACTIONS.as_bytes(), &mut manager, &resume, None)
+            .await
+            .unwrap();
+
+        // Neither deposit is applied twice, which would be skipped as a duplicate.
+        assert_eq!(summary.skipped(), 0);
+        assert_eq!(summary.rows, 2);
+
+        let mut expected = TransactionManager::new();
+        process(TRANSACTIONS.as_bytes(), &mut expected, &args("-"), None)
+            .await
+            .unwrap();
+
+        let mut balances = manager.balances();
+        let mut expected = expected.balances();
+        sort_balances(&mut balances, SortBy::Client);
+        sort_balances(&mut expected, SortBy::Client);
+
+        assert_eq!(balances, expected);
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,50
+deposit,1,3,5
+deposit,1,4,1
+deposit,1,5,1
+";
+
+        let mut manager = TransactionManager::new();
+
+        let args = Args::parse_from(["payments_engine", "-", "--limit", "2"]);
+        let summary = process(input.as_bytes(), &mut manager, &args, None)
+            .await
+            .unwrap();
+
+        // The failed withdrawal does not count towards the limit, and the last two rows are never read.
+        assert_eq!(summary.skipped(), 1);
+        assert_eq!(summary.rows, 3);
+        assert_eq!(manager.balances()[0].total, 15.0);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,10,2024-05-01T09:30:00Z
+withdrawal,1,2,50,
+deposit,1,3,-1,
+deposit,1,4
+dispute,1,9,,
+";
+
+        let mut output = Vec::new();
+        let mut dead_letter = DeadLetter::new(Box::new(&mut output)).await.unwrap();
+
+        let summary = process(
+            input.as_bytes(),
+            &mut TransactionManager::new(),
+            &args("-"),
+            Some(&mut dead_letter),
+        )
+        .await
+        .unwrap();
+
+        drop(dead_letter);
+
+        // Every skipped row but the malformed one is written.
+        assert_eq!(summary.skipped(), 4);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+type,client,tx,amount,timestamp,currency,error
+withdrawal,1,2,50,,,InsufficientFunds
+deposit,1,3,-1,,,NegativeAmount
+dispute,1,9,,,,DisputedTransactionNotFound
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_strict() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,50
+deposit,1,3,5
+";
+
+        let mut output = Vec::new();
+        let mut dead_letter = DeadLetter::new(Box::new(&mut output)).await.unwrap();
+
+        let strict = Args::parse_from(["payments_engine", "-", "--strict"]);
+
+        assert!(process(
+            input.as_bytes(),
+            &mut TransactionManager::new(),
+            &strict,
+            Some(&mut dead_letter),
+        )
+        .await
+        .is_err());
+
+        drop(dead_letter);
+
+        // The aborting row is written and flushed despite the error.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+type,client,tx,amount,timestamp,currency,error
+withdrawal,1,2,50,,,InsufficientFunds
+"
+        );
+
+        // A malformed row aborts before anything is written, but the header is still flushed.
+        let mut output = Vec::new();
+        let mut dead_letter = DeadLetter::new(Box::new(&mut output)).await.unwrap();
+
+        assert!(process(
+            "type,client,tx,amount\ndeposit,1,1,10,extra\n".as_bytes(),
+            &mut TransactionManager::new(),
+            &strict,
+            Some(&mut dead_letter),
+        )
+        .await
+        .is_err());
+
+        drop(dead_letter);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount,timestamp,currency,error\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gzip_input() {
+        let directory =
+            std::env::temp_dir().join(format!("payments_engine_gzip_{}", std::process::id()));
+        tokio::fs::create_dir_all(&directory).await.unwrap();
+
+        let plain_path = directory.join("transactions.csv");
+        tokio::fs::write(&plain_path, TRANSACTIONS).await.unwrap();
+
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(TRANSACTIONS.as_bytes()).await.unwrap();
+        encoder.shutdown().await.unwrap();
+
+        let gzip_path = directory.join("transactions.csv.gz");
+        tokio::fs::write(&gzip_path, encoder.into_inner())
+            .await
+            .unwrap();
+
+        let plain_balances = balances_from_file(&plain_path).await;
+        let gzip_balances = balances_from_file(&gzip_path).await;
+
+        tokio::fs::remove_dir_all(&directory).await.unwrap();
+
+        assert_eq!(plain_balances.len(), 2);
+        assert_eq!(gzip_balances, plain_balances);
+    }
+
+    #[tokio::test]
+    async fn test_process_sharded() {
+        let args = Args::parse_from(["payments_engine", "-", "--workers", "3"]);
+
+        let managers = (0..3).map(|_| TransactionManager::new()).collect();
+
+        let (managers, summary) = process_sharded(TRANSACTIONS.as_bytes(), managers, &args)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.skipped(), 0);
+        assert_eq!(managers.len(), 3);
+
+        // Each client only appears in the shard it was routed to.
+        assert!(managers[0].balances().is_empty());
+        assert_eq!(managers[1].balances()[0].client_id, 1);
+        assert_eq!(managers[2].balances()[0].client_id, 2);
+
+        let mut sharded_balances: Vec<ClientBalance> = managers
+            .iter()
+            .flat_map(TransactionManager::balances)
+            .collect();
+        sort_balances(&mut sharded_balances, SortBy::Client);
+
+        let mut manager = TransactionManager::new();
+        process(TRANSACTIONS.as_bytes(), &mut manager, &args, None)
+            .await
+            .unwrap();
+
+        let mut balances = manager.balances();
+        sort_balances(&mut balances, SortBy::Client);
+
+        assert_eq!(sharded_balances, balances);
+    }
+
+    #[tokio::test]
+    async fn test_process_sharded_strict() {
+        let args = Args::parse_from(["payments_engine", "-", "--workers", "2", "--strict"]);
+
+        let managers = (0..2).map(|_| TransactionManager::new()).collect();
+
+        let input = "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,20\ndeposit,2,3,5\n";
+
+        assert!(process_sharded(input.as_bytes(), managers, &args)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let verbosity = |flags: &[&str]| {
+            Args::try_parse_from(["payments_engine", "-"].iter().chain(flags))
+                .map(|args| args.verbosity())
+        };
+
+        assert_eq!(verbosity(&[]).unwrap(), Verbosity::Normal);
+        assert_eq!(verbosity(&["--quiet"]).unwrap(), Verbosity::Quiet);
+        assert_eq!(verbosity(&["-v"]).unwrap(), Verbosity::Verbose);
+        assert!(verbosity(&["--quiet", "--verbose"]).is_err());
+
+        assert_eq!(Verbosity::Quiet.default_filter(), "error");
+        assert_eq!(Verbosity::Normal.default_filter(), "warn");
+        assert_eq!(
+            Verbosity::Verbose.default_filter(),
+            "warn,payments_engine=debug"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_row() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+deposit,1,3,7,extra
+deposit,2,4,20
+withdrawal,1,5,3
+";
+
+        let mut manager = TransactionManager::new();
+
+        let summary = process(input.as_bytes(), &mut manager, &args("-"), None)
+            .await
+            .unwrap();
+
+        // Only row 3 is skipped; the rows after it are still applied.
+        assert_eq!(summary.to_string(), "MalformedRow: 1");
+        assert_eq!(manager.balance(1).unwrap().available, 12.0);
+        assert_eq!(manager.balance(2).unwrap().available, 20.0);
+
+        let strict = Args::parse_from(["payments_engine", "-", "--strict"]);
+
+        let error = process(
+            input.as_bytes(),
+            &mut TransactionManager::new(),
+            &strict,
+            None,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(error.to_string().starts_with("Malformed row 3:"));
+    }
+
+    #[tokio::test]
+    async fn test_error_summary() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,20
+withdrawal,1,3,30
+deposit,1,1,10
+deposit,2,4,0
+dispute,1,1,
+resolve,1,1,
+resolve,1,1,
+";
+
+        let mut manager = TransactionManager::new();
+
+        let summary = process(input.as_bytes(), &mut manager, &args("-"), None)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.skipped(), 5);
+        assert_eq!(
+            summary.to_string(),
+            "InsufficientFunds: 2, AlreadyResolved: 1, DuplicateTransaction: 1, ZeroAmount: 1"
+        );
+
+        let mut other = ErrorSummary::default();
+        other.record("ZeroAmount".to_string());
+
+        let mut merged = ErrorSummary::default();
+        merged.merge(summary);
+        merged.merge(other);
+
+        assert_eq!(merged.skipped(), 6);
+        assert_eq!(merged.errors["ZeroAmount"], 2);
+        assert_eq!(ErrorSummary::default().to_string(), "none");
+    }
+
+    #[test]
+    fn test_error_name() {
+        // Fields are dropped.
+        assert_eq!(
+            CsvError::MissingAmount {
+                order_type: "deposit".to_string()
+            }
+            .name(),
+            "MissingAmount"
+        );
+        assert_eq!(CsvError::ReadTimeout(Duration::ZERO).name(), "ReadTimeout");
+        assert_eq!(
+            TransactionError::InsufficientFunds.name(),
+            "InsufficientFunds"
+        );
+        assert_eq!(
+            TransactionError::DisputeClientMismatch {
+                expected: 1,
+                actual: 2
+            }
+            .name(),
+            "DisputeClientMismatch"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_balances() {
+        let balances = vec![
+            ClientBalance {
+                client_id: 2,
+                currency: None,
+                available: 1234567.89,
+                held: 0.0,
+                total: 1234567.89,
+                locked: false,
+            },
+            ClientBalance {
+                client_id: 1,
+                currency: None,
+                available: -50.5,
+                held: 100.0,
+                total: 49.5,
+                locked: true,
+            },
+        ];
+
+        let mut output = Vec::new();
+        write_balances(&mut output, balances.clone(), false, SortBy::Client, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,-50.5,100,49.5,true\n2,1234567.89,0,1234567.89,false\n"
+        );
+
+        // Grouped amounts containing the CSV delimiter are quoted.
+        let mut output = Vec::new();
+        write_balances(&mut output, balances, false, SortBy::Total, Some(','))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,\"1,234,567.89\",0,\"1,234,567.89\",false\n1,-50.5,100,49.5,true\n"
+        );
+
+        let mut output = Vec::new();
+        write_balances(&mut output, Vec::new(), false, SortBy::Client, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_table() {
+        let balances = vec![
+            ClientBalance {
+                client_id: 12,
+                currency: None,
+                available: 1234567.89,
+                held: 0.0,
+                total: 1234567.89,
+                locked: false,
+            },
+            ClientBalance {
+                client_id: 1,
+                currency: None,
+                available: -50.5,
+                held: 100.0,
+                total: 49.5,
+                locked: true,
+            },
+        ];
+
+        let mut output = Vec::new();
+        write_table(&mut output, balances, false, SortBy::Client, Some(','))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client     available  held         total  locked
+------  ------------  ----  ------------  ------
+     1         -50.5   100          49.5  true
+    12  1,234,567.89     0  1,234,567.89  false
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_currencies() {
+        let input = "\
+type,client,tx,amount,currency
+deposit,1,1,10.0,
+deposit,1,2,20.0,EUR
+withdrawal,1,3,1234.0,EUR
+dispute,1,2,,
+";
+
+        let mut ledger =
+            CurrencyLedger::new("USD", TransactionManager::new(), TransactionManager::new);
+
+        let summary = process(input.as_bytes(), &mut ledger, &args("-"), None)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.to_string(), "InsufficientFunds: 1");
+
+        let mut output = Vec::new();
+        write_balances(&mut output, ledger.balances(), true, SortBy::Client, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n1,EUR,0,20,20,false\n1,USD,10,0,10,false\n"
+        );
+
+        let mut output = Vec::new();
+        write_balances(
+            &mut output,
+            ledger.balances(),
+            true,
+            SortBy::Total,
+            Some(','),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n1,EUR,0,20,20,false\n1,USD,10,0,10,false\n"
+        );
+    }
+
+    // Collects output while counting the writes made to it.
+    #[derive(Default)]
+    struct CountingWriter {
+        output: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            self.output.extend_from_slice(buf);
+
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_balances_batched() {
+        let clients = 100_000;
+
+        let balances = (0..clients)
+            .map(|client_id| ClientBalance {
+                client_id: client_id as ClientId,
+                currency: None,
+                available: 1234.5678,
+                held: 0.0,
+                total: 1234.5678,
+                locked: false,
+            })
+            .collect();
+
+        let mut writer = CountingWriter::default();
+        write_balances(&mut writer, balances, false, SortBy::Client, None)
+            .await
+            .unwrap();
+
+        let output = String::from_utf8(writer.output).unwrap();
+
+        assert_eq!(output.lines().count(), clients + 1);
+        assert_eq!(output.lines().nth(1), Some("0,1234.5678,0,1234.5678,false"));
+
+        // Rows are written in chunks of the buffer capacity rather than one at a time.
+        assert!(writer.writes <= output.len() / OUTPUT_BUFFER_CAPACITY + 1);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut balances = vec![
+            ClientBalance {
+                client_id: 1,
+                currency: None,
+                available: 0.0,
+                held: 0.0,
+                total: 0.0,
+                locked: false,
+            },
+            ClientBalance {
+                client_id: 2,
+                currency: None,
+                available: 0.0,
+                held: 5.0,
+                total: 5.0,
+                locked: false,
+            },
+            ClientBalance {
+                client_id: 3,
+                currency: None,
+                available: 0.0,
+                held: 0.0,
+                total: 0.0,
+                locked: true,
+            },
+        ];
+
+        balances.retain(|balance| !is_empty(balance));
+
+        let clients: Vec<_> = balances.iter().map(|balance| balance.client_id).collect();
+
+        assert_eq!(clients, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_format_4_decimals() {
+        assert_eq!(format_4_decimals(1234567.89, None), "1234567.89");
+        assert_eq!(format_4_decimals(100.0, None), "100");
+        assert_eq!(format_4_decimals(-50.5, None), "-50.5");
+    }
+
+    #[test]
+    fn test_format_4_decimals_grouped() {
+        assert_eq!(format_4_decimals(1234567.89, Some(',')), "1,234,567.89");
+        assert_eq!(format_4_decimals(123456.1234, Some(',')), "123,456.1234");
+        assert_eq!(format_4_decimals(100.0, Some(',')), "100");
+        assert_eq!(format_4_decimals(0.5, Some(',')), "0.5");
+    }
+
+    #[test]
+    fn test_format_4_decimals_grouped_negative() {
+        assert_eq!(format_4_decimals(-1234567.89, Some(',')), "-1,234,567.89");
+        assert_eq!(format_4_decimals(-123.0, Some(',')), "-123");
+    }
 }
@@ -0,0 +1,480 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    iter::{self, Sum},
+    ops::{Add, AddAssign, Neg, Sub, SubAssign},
+};
+
+// Number of base units in one whole unit of currency, i.e. amounts have four decimal places.
+const BASE_UNITS_PER_UNIT: i64 = 10_000;
+
+const DECIMAL_PLACES: usize = 4;
+
+// Rounding errors are kept in 10^-18 of a base unit. Input digits beyond that are ignored.
+const ROUNDING_ERROR_PLACES: usize = 18;
+
+const ROUNDING_ERROR_PER_BASE_UNIT: i128 = 10_i128.pow(ROUNDING_ERROR_PLACES as u32);
+
+// How amounts with more than four decimal places are converted to base units.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Round half away from zero.
+    #[default]
+    HalfUp,
+    /// Round half to even (banker's rounding).
+    HalfEven,
+    /// Truncate toward zero.
+    Truncate,
+}
+
+// An amount of money in base units (1/10,000 of a unit). Keeping amounts in this type rather than a bare
+// i64 prevents mixing scaled and unscaled values.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub const fn from_base_units(base_units: i64) -> Self {
+        Self(base_units)
+    }
+
+    // Converts a floating point amount, e.g. from an opening balances file, into base units. Prefer
+    // parse_decimal where the original string is available.
+    pub fn from_decimal(amount: f64, rounding: RoundingMode) -> Self {
+        let scaled = amount * BASE_UNITS_PER_UNIT as f64;
+
+        let rounded = match rounding {
+            RoundingMode::HalfUp => scaled.round(),
+            RoundingMode::HalfEven => scaled.round_ties_even(),
+            RoundingMode::Truncate => scaled.trunc(),
+        };
+
+        Self(rounded as i64)
+    }
+
+    // Converts a decimal string such as `-12.3456` into base units using exact integer arithmetic, so no
+    // precision is lost to floating point. Digits beyond four decimal places are rounded. Returns None if
+    // the string is not a plain decimal number or does not fit in base units.
+    pub fn parse_decimal(amount: &str, rounding: RoundingMode) -> Option<Self> {
+        Self::parse_decimal_with_rounding_error(amount, rounding).map(|(money, _)| money)
+    }
+
+    // Same as parse_decimal, but also returns the rounding error, i.e. the rounded amount minus the exact
+    // one, e.g. 0.5 of a base unit for `0.00005` rounded half up.
+    pub fn parse_decimal_with_rounding_error(
+        amount: &str,
+        rounding: RoundingMode,
+    ) -> Option<(Self, RoundingError)> {
+        let (negative, unsigned) = match amount.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, amount.strip_prefix('+').unwrap_or(amount)),
+        };
+
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        if (integer.is_empty() && fraction.is_empty())
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|digit| digit.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let (kept, dropped) = fraction.split_at(fraction.len().min(DECIMAL_PLACES));
+
+        let mut base_units: i64 = 0;
+
+        for digit in integer
+            .bytes()
+            .chain(kept.bytes())
+            .chain(iter::repeat_n(b'0', DECIMAL_PLACES - kept.len()))
+        {
+            base_units = base_units
+                .checked_mul(10)?
+                .checked_add(i64::from(digit - b'0'))?;
+        }
+
+        let round_up = match (rounding, dropped.as_bytes()) {
+            (RoundingMode::Truncate, _) | (_, []) => false,
+            (RoundingMode::HalfUp, [first, ..]) => *first >= b'5',
+            (RoundingMode::HalfEven, [first, rest @ ..]) => {
+                *first > b'5'
+                    || (*first == b'5'
+                        && (rest.iter().any(|&digit| digit != b'0') || base_units % 2 == 1))
+            }
+        };
+
+        if round_up {
+            base_units = base_units.checked_add(1)?;
+        }
+
+        // The dropped digits are the fraction of a base unit that rounding removed.
+        let dropped_fraction = dropped
+            .bytes()
+            .chain(iter::repeat(b'0'))
+            .take(ROUNDING_ERROR_PLACES)
+            .fold(0, |fraction, digit| {
+                fraction * 10 + i128::from(digit - b'0')
+            });
+
+        let rounding_error = RoundingError(
+            i128::from(u8::from(round_up)) * ROUNDING_ERROR_PER_BASE_UNIT - dropped_fraction,
+        );
+
+        if negative {
+            Some((Self(-base_units), -rounding_error))
+        } else {
+            Some((Self(base_units), rounding_error))
+        }
+    }
+
+    // Whether a decimal string has non-zero digits beyond four decimal places, i.e. whether parse_decimal
+    // would have to round it. Trailing zeros such as `1.00000` do not change the value so are allowed.
+    pub fn exceeds_precision(amount: &str) -> bool {
+        amount.split_once('.').is_some_and(|(_, fraction)| {
+            fraction
+                .bytes()
+                .skip(DECIMAL_PLACES)
+                .any(|digit| digit != b'0')
+        })
+    }
+
+    pub const fn base_units(self) -> i64 {
+        self.0
+    }
+
+    pub fn to_decimal(self) -> f64 {
+        self.0 as f64 / BASE_UNITS_PER_UNIT as f64
+    }
+
+    // Splits into whole units and the four decimal remainder, e.g. 100.5555 into (100, 5555). Whole units
+    // round toward negative infinity so the remainder is never negative, i.e. -0.5 is (-1, 5000).
+    pub fn to_parts(self) -> (i64, u16) {
+        (
+            self.0.div_euclid(BASE_UNITS_PER_UNIT),
+            self.0.rem_euclid(BASE_UNITS_PER_UNIT) as u16,
+        )
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+// Allows comparing against a literal number of base units, mostly for brevity in tests.
+impl PartialEq<i64> for Money {
+    fn eq(&self, other: &i64) -> bool {
+        self.0 == *other
+    }
+}
+
+// Renders the exact four decimal form, e.g. 1.5000.
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let base_units = self.0.unsigned_abs();
+        let per_unit = BASE_UNITS_PER_UNIT as u64;
+
+        write!(
+            f,
+            "{}{}.{:04}",
+            sign,
+            base_units / per_unit,
+            base_units % per_unit
+        )
+    }
+}
+
+// The difference between a rounded amount and the exact one it was parsed from, in fractions of a base
+// unit. Kept exactly rather than as a float, so a total over many transactions does not drift.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RoundingError(i128);
+
+impl RoundingError {
+    pub const ZERO: RoundingError = RoundingError(0);
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl AddAssign for RoundingError {
+    fn add_assign(&mut self, other: RoundingError) {
+        self.0 += other.0;
+    }
+}
+
+impl Neg for RoundingError {
+    type Output = RoundingError;
+
+    fn neg(self) -> RoundingError {
+        RoundingError(-self.0)
+    }
+}
+
+// Renders in base units without trailing zeros, e.g. 1.5 or -0.25.
+impl fmt::Display for RoundingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let error = self.0.unsigned_abs();
+        let per_base_unit = ROUNDING_ERROR_PER_BASE_UNIT as u128;
+        let fraction = format!(
+            "{:0width$}",
+            error % per_base_unit,
+            width = ROUNDING_ERROR_PLACES
+        );
+        let fraction = fraction.trim_end_matches('0');
+
+        write!(f, "{}{}", sign, error / per_base_unit)?;
+
+        if fraction.is_empty() {
+            Ok(())
+        } else {
+            write!(f, ".{}", fraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_decimal_rounding_modes() {
+        assert_eq!(Money::from_decimal(0.00005, RoundingMode::HalfUp), 1);
+        assert_eq!(Money::from_decimal(0.00005, RoundingMode::HalfEven), 0);
+        assert_eq!(Money::from_decimal(0.00005, RoundingMode::Truncate), 0);
+
+        // Half to even rounds up when the nearest even value is above.
+        assert_eq!(Money::from_decimal(0.00025, RoundingMode::HalfEven), 2);
+        assert_eq!(Money::from_decimal(0.00035, RoundingMode::HalfEven), 4);
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        let parse = |amount| Money::parse_decimal(amount, RoundingMode::HalfUp);
+
+        assert_eq!(parse("1.0001"), Some(Money::from_base_units(10_001)));
+        assert_eq!(parse("0.0001"), Some(Money::from_base_units(1)));
+        assert_eq!(
+            parse("99999999.9999"),
+            Some(Money::from_base_units(999_999_999_999))
+        );
+        assert_eq!(parse("100"), Some(Money::from_base_units(1_000_000)));
+        assert_eq!(parse("1."), Some(Money::from_base_units(10_000)));
+        assert_eq!(parse(".5"), Some(Money::from_base_units(5_000)));
+        assert_eq!(parse("+2.5"), Some(Money::from_base_units(25_000)));
+        assert_eq!(parse("-2.5"), Some(Money::from_base_units(-25_000)));
+        assert_eq!(parse("0"), Some(Money::ZERO));
+
+        for invalid in ["", "-", ".", "1.2.3", "1e3", "abc", " 1", "1,5", "--1"] {
+            assert_eq!(parse(invalid), None, "{:?}", invalid);
+        }
+
+        // Does not fit in base units.
+        assert_eq!(parse("922337203685477.5808"), None);
+        assert_eq!(
+            parse("922337203685477.5807"),
+            Some(Money::from_base_units(i64::MAX))
+        );
+    }
+
+    #[test]
+    fn test_parse_decimal_rounding_modes() {
+        let parse = Money::parse_decimal;
+
+        assert_eq!(
+            parse("0.00005", RoundingMode::HalfUp),
+            Some(Money::from_base_units(1))
+        );
+        assert_eq!(
+            parse("-0.00005", RoundingMode::HalfUp),
+            Some(Money::from_base_units(-1))
+        );
+        assert_eq!(parse("0.00005", RoundingMode::HalfEven), Some(Money::ZERO));
+        assert_eq!(
+            parse("0.000051", RoundingMode::HalfEven),
+            Some(Money::from_base_units(1))
+        );
+        assert_eq!(
+            parse("0.00015", RoundingMode::HalfEven),
+            Some(Money::from_base_units(2))
+        );
+        assert_eq!(
+            parse("0.00049", RoundingMode::HalfUp),
+            Some(Money::from_base_units(5))
+        );
+        assert_eq!(
+            parse("0.00049", RoundingMode::Truncate),
+            Some(Money::from_base_units(4))
+        );
+        assert_eq!(
+            parse("0.99999", RoundingMode::HalfUp),
+            Some(Money::from_base_units(10_000))
+        );
+    }
+
+    #[test]
+    fn test_parse_decimal_rounding_error() {
+        let parse = |amount, rounding| {
+            Money::parse_decimal_with_rounding_error(amount, rounding)
+                .map(|(money, rounding_error)| (money, rounding_error.to_string()))
+        };
+
+        assert_eq!(
+            parse("1.5", RoundingMode::HalfUp),
+            Some((Money::from_base_units(15_000), "0".to_string()))
+        );
+        assert_eq!(
+            parse("0.00005", RoundingMode::HalfUp),
+            Some((Money::from_base_units(1), "0.5".to_string()))
+        );
+        assert_eq!(
+            parse("0.00005", RoundingMode::HalfEven),
+            Some((Money::ZERO, "-0.5".to_string()))
+        );
+        assert_eq!(
+            parse("-0.00015", RoundingMode::HalfUp),
+            Some((Money::from_base_units(-2), "-0.5".to_string()))
+        );
+        assert_eq!(
+            parse("0.000125", RoundingMode::Truncate),
+            Some((Money::from_base_units(1), "-0.25".to_string()))
+        );
+        assert_eq!(parse("abc", RoundingMode::HalfUp), None);
+    }
+
+    #[test]
+    fn test_rounding_error_exact() {
+        let rounding_error = |amount| {
+            Money::parse_decimal_with_rounding_error(amount, RoundingMode::HalfUp)
+                .unwrap()
+                .1
+        };
+
+        // A float would not sum ten tenths of a base unit back to exactly one.
+        let mut total = RoundingError::ZERO;
+
+        for _ in 0..10 {
+            total += rounding_error("0.00001");
+        }
+
+        assert_eq!(total.to_string(), "-1");
+        assert_eq!(
+            rounding_error("0.000000000000000000000001").to_string(),
+            "0"
+        );
+        assert_eq!(
+            rounding_error("0.00001234567890123456789").to_string(),
+            "-0.123456789012345678"
+        );
+    }
+
+    #[test]
+    fn test_exceeds_precision() {
+        assert!(Money::exceeds_precision("1.00005"));
+        assert!(Money::exceeds_precision("-0.000001"));
+        assert!(!Money::exceeds_precision("1.0001"));
+        assert!(!Money::exceeds_precision("1.000100"));
+        assert!(!Money::exceeds_precision("100"));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = Money::from_base_units(150);
+        let b = Money::from_base_units(50);
+
+        assert_eq!(a + b, 200);
+        assert_eq!(a - b, 100);
+        assert_eq!(b - a, -100);
+        assert_eq!(-a, -150);
+        assert_eq!(a.checked_add(b), Some(Money::from_base_units(200)));
+        assert_eq!(Money::from_base_units(i64::MAX).checked_add(b), None);
+        assert_eq!(Money::from_base_units(i64::MIN).checked_sub(b), None);
+        assert_eq!([a, b].into_iter().sum::<Money>(), 200);
+    }
+
+    #[test]
+    fn test_to_parts() {
+        assert_eq!(Money::from_base_units(1_005_555).to_parts(), (100, 5555));
+        assert_eq!(Money::from_base_units(-500_000).to_parts(), (-50, 0));
+        assert_eq!(Money::from_base_units(-5_000).to_parts(), (-1, 5000));
+        assert_eq!(Money::ZERO.to_parts(), (0, 0));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Money::from_base_units(15_000).to_string(), "1.5000");
+        assert_eq!(Money::from_base_units(1).to_string(), "0.0001");
+        assert_eq!(Money::from_base_units(-509_999).to_string(), "-50.9999");
+        assert_eq!(Money::ZERO.to_string(), "0.0000");
+        assert_eq!(
+            Money::from_base_units(i64::MIN).to_string(),
+            "-922337203685477.5808"
+        );
+    }
+}
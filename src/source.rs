@@ -0,0 +1,184 @@
+use futures::{Stream, StreamExt};
+use std::{collections::HashMap, error, future::Future, pin::Pin, time::Duration};
+use tokio::io::AsyncRead;
+
+use crate::{
+    csv::{self, CsvFormat, RoundingMode, TransactionDto},
+    transaction_manager::Transaction,
+};
+
+// A source of transactions to feed to the engine, e.g. a CSV file, a database table or a message queue.
+// Decouples the engine from where its input comes from. The returned future is Send so a source can be
+// driven from a spawned task. The binary reads CSV rows directly instead, see process_rows in main.rs.
+pub trait TransactionSource {
+    // The next transaction, an error for an entry that could not be read or converted, or None once the
+    // source is exhausted. Whether reading continues after an error is up to the caller.
+    fn next_transaction(
+        &mut self,
+    ) -> impl Future<Output = Option<Result<Transaction, Box<dyn error::Error>>>> + Send;
+}
+
+type DtoStream<'r> =
+    Pin<Box<dyn Stream<Item = Result<TransactionDto, Box<dyn error::Error>>> + Send + 'r>>;
+
+// Reads transactions from a CSV input with read_transactions, converting amounts with the given rounding
+// mode. Malformed rows and invalid amounts are returned as errors without ending the source.
+pub struct CsvTransactionSource<'r> {
+    dtos: DtoStream<'r>,
+    rounding: RoundingMode,
+}
+
+impl<'r> CsvTransactionSource<'r> {
+    pub fn new<R>(
+        reader: R,
+        header_aliases: HashMap<String, String>,
+        format: CsvFormat,
+        read_timeout: Option<Duration>,
+        rounding: RoundingMode,
+    ) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'r,
+    {
+        Self {
+            dtos: Box::pin(csv::read_transactions(
+                reader,
+                header_aliases,
+                format,
+                read_timeout,
+            )),
+            rounding,
+        }
+    }
+}
+
+impl TransactionSource for CsvTransactionSource<'_> {
+    async fn next_transaction(&mut self) -> Option<Result<Transaction, Box<dyn error::Error>>> {
+        let dto = self.dtos.next().await?;
+
+        Some(dto.and_then(|dto| Ok(dto.to_transaction(self.rounding)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        csv::CsvError,
+        money::Money,
+        transaction_manager::{errors::TransactionError, TransactionId, TransactionManager},
+    };
+    use std::collections::VecDeque;
+
+    // An in-memory source, standing in for one backed by a database or queue.
+    struct MemorySource(VecDeque<Result<Transaction, TransactionError>>);
+
+    impl TransactionSource for MemorySource {
+        async fn next_transaction(&mut self) -> Option<Result<Transaction, Box<dyn error::Error>>> {
+            self.0.pop_front().map(|result| Ok(result?))
+        }
+    }
+
+    // Applies every transaction from a source, returning the number skipped.
+    async fn apply_all(
+        source: &mut impl TransactionSource,
+        manager: &mut TransactionManager,
+    ) -> usize {
+        let mut skipped = 0;
+
+        while let Some(result) = source.next_transaction().await {
+            if result.and_then(|tx| Ok(manager.accept(tx)?)).is_err() {
+                skipped += 1;
+            }
+        }
+
+        skipped
+    }
+
+    fn deposit(id: TransactionId, amount: i64) -> Transaction {
+        Transaction::Deposit {
+            id,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(amount),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_source() {
+        let mut source = MemorySource(VecDeque::from([
+            Ok(deposit(1, 100)),
+            Err(TransactionError::AmountIsNegative),
+            Ok(deposit(2, 50)),
+            Ok(deposit(2, 50)),
+        ]));
+
+        let mut manager = TransactionManager::new();
+
+        // The source error and the duplicate are skipped.
+        assert_eq!(apply_all(&mut source, &mut manager).await, 2);
+        assert_eq!(manager.balances()[0].total, 0.015);
+        assert!(source.next_transaction().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_csv_source() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,1,2,abc\nwithdrawal,1,3,0.5\n";
+
+        let mut source = CsvTransactionSource::new(
+            csv.as_bytes(),
+            HashMap::new(),
+            CsvFormat::default(),
+            None,
+            RoundingMode::default(),
+        );
+
+        assert_eq!(
+            source.next_transaction().await.unwrap().unwrap(),
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(15_000),
+            }
+        );
+
+        let error = source.next_transaction().await.unwrap().unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<CsvError>(),
+            Some(CsvError::InvalidAmount { value }) if value == "abc"
+        ));
+
+        // The source continues past the invalid row.
+        assert_eq!(
+            source.next_transaction().await.unwrap().unwrap(),
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(5_000),
+            }
+        );
+        assert!(source.next_transaction().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_csv_source_is_send() {
+        let mut source = CsvTransactionSource::new(
+            "type,client,tx,amount\ndeposit,1,1,1.5\n".as_bytes(),
+            HashMap::new(),
+            CsvFormat::default(),
+            None,
+            RoundingMode::default(),
+        );
+
+        // Only compiles if the source and its future can move to another thread.
+        let read = tokio::spawn(async move { source.next_transaction().await.unwrap().unwrap() });
+
+        assert_eq!(
+            read.await.unwrap(),
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(15_000),
+            }
+        );
+    }
+}
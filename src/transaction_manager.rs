@@ -1,8 +1,30 @@
-use crate::transaction_manager::errors::TransactionError;
-use balance::Balance;
-use std::collections::HashMap;
+use crate::{
+    csv::{self, CsvFormat, TransactionDto},
+    money::{Money, RoundingError, RoundingMode},
+    transaction_manager::errors::TransactionError,
+};
+pub use balance::{Balance, ChargebackPolicy};
+use clap::ValueEnum;
+pub use currency::{Currency, CurrencyLedger};
+pub use events::{BalanceChange, BalanceField, BalanceSnapshot};
+use metrics::Operation;
+pub use metrics::{EngineMetrics, OperationMetrics};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    error::Error,
+    fmt, mem,
+    time::Instant,
+};
+use tokio::{io::AsyncWrite, sync::mpsc};
+pub use totals::ConsistencyReport;
+use totals::Totals;
+use tracing::warn;
 pub use transaction::Transaction;
-use transaction::{ClientId, TransactionId, TransactionState, TransactionType};
+pub use transaction::{ClientId, OrderType, Timestamp, TransactionId, TransactionStatus};
+use transaction::{TransactionState, TransactionType};
+pub use view::ReadOnlyView;
 
 pub mod errors;
 
@@ -10,56 +32,619 @@ mod transaction;
 
 mod balance;
 
+mod totals;
+
+mod metrics;
+
+mod events;
+
+mod currency;
+
+mod view;
+
 pub struct TransactionManager {
     balances: HashMap<ClientId, Balance>,
     transactions: HashMap<TransactionId, TransactionState>,
+    // Ids of transactions currently in the Disputed state, so open disputes can be listed without
+    // scanning every transaction.
+    disputed: HashSet<TransactionId>,
+    // Ids of withdrawals whose funds are held awaiting a SettleWithdrawal, when withdrawal holds are enabled.
+    pending_withdrawals: HashSet<TransactionId>,
+    // Clients whose account is locked, kept alongside the balances so they can be counted without a scan.
+    locked_clients: HashSet<ClientId>,
+    totals: Totals,
+    flows: HashMap<ClientId, ClientFlows>,
+    // Treat an exact resubmission of an already applied deposit or withdrawal as a no-op.
+    replay: bool,
+    // Maximum funds that may be held for a single client across all of their open disputes.
+    held_cap: Option<Money>,
+    // Only collected when enabled, to avoid timing every transaction.
+    metrics: Option<EngineMetrics>,
+    observer: Option<Box<Observer>>,
+    // Largest amount accepted for a single deposit or withdrawal.
+    max_amount: Option<Money>,
+    // Smallest amount accepted for a deposit.
+    min_deposit: Option<Money>,
+    // Largest id accepted for a deposit or withdrawal.
+    max_transaction_id: Option<TransactionId>,
+    // Number of times a transaction may be disputed, each after the previous dispute was resolved.
+    max_disputes_per_transaction: u32,
+    chargeback_policy: ChargebackPolicy,
+    // The most recently applied deposit, withdrawal or adjustment, for undo_last.
+    last_applied: Option<TransactionId>,
+    // Number of transactions passed to accept, whether applied or rejected.
+    processed: usize,
+    // Treat client 0 as a reserved "unknown client" sentinel and reject its transactions.
+    reject_client_zero: bool,
+    // Hold withdrawn funds until settled rather than deducting them immediately.
+    withdrawal_holds: bool,
+    // Withdraw as much as is available rather than rejecting a withdrawal larger than the balance.
+    partial_withdrawals: bool,
+    // Drop resolved and charged back transactions from transactions, as they cannot be disputed again.
+    evict_finalized: bool,
+    // Apply disputes to the client stored with the transaction rather than the one given.
+    trust_stored_client: bool,
+    // Ids of evicted transactions, kept so a resubmission is still rejected as a duplicate.
+    evicted: HashSet<TransactionId>,
+    duplicate_policy: DuplicatePolicy,
+    // Charged on every withdrawal on top of the amount withdrawn.
+    withdrawal_fee_flat: Money,
+    withdrawal_fee_bps: u32,
+    // Receives a BalanceChange for every amount a transaction changes, when set.
+    event_sink: Option<mpsc::Sender<BalanceChange>>,
+    // Most clients to hold balances for, bounding memory for untrusted input.
+    max_clients: Option<usize>,
+    // Every successfully applied transaction in order, for export_transactions. Only kept when enabled.
+    history: Option<Vec<(Transaction, Option<Timestamp>)>>,
+    // Each client's balance before their first applied transaction and after every one. Only kept when
+    // enabled.
+    balance_timeline: Option<HashMap<ClientId, Vec<BalanceSnapshot>>>,
+}
+
+// The state written by snapshot_binary. Borrowed when writing so a checkpoint does not copy the engine,
+// owned when read back.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<'a> {
+    balances: Cow<'a, HashMap<ClientId, Balance>>,
+    transactions: Cow<'a, HashMap<TransactionId, TransactionState>>,
+    evicted: Cow<'a, HashSet<TransactionId>>,
+    disputed: Cow<'a, HashSet<TransactionId>>,
+    pending_withdrawals: Cow<'a, HashSet<TransactionId>>,
+    locked_clients: Cow<'a, HashSet<ClientId>>,
+    totals: Cow<'a, Totals>,
+    flows: Cow<'a, HashMap<ClientId, ClientFlows>>,
+    last_applied: Option<TransactionId>,
+    processed: usize,
+}
+
+// Identifies snapshot_binary's output, followed by SNAPSHOT_VERSION and the width of transaction ids, so a
+// snapshot from an incompatible build is rejected rather than misread.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"PESN";
+
+// Increment whenever Snapshot, or any type it contains, changes how it is encoded.
+const SNAPSHOT_VERSION: u16 = 1;
+
+const SNAPSHOT_HEADER_LEN: usize = SNAPSHOT_MAGIC.len() + 3;
+
+// Largest number of base units that converts to f64 without rounding.
+const MAX_EXACT_F64_BASE_UNITS: u64 = 1 << f64::MANTISSA_DIGITS;
+
+// How a deposit reusing the id of an earlier deposit is handled.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    /// Reject the later deposit as a duplicate.
+    #[default]
+    Reject,
+    /// Reverse the earlier deposit and apply the later one in its place (last write wins). The earlier
+    /// deposit must not be disputed.
+    Replace,
 }
 
+// Basis points in a whole, i.e. 100%.
+const BASIS_POINTS_PER_UNIT: i128 = 10_000;
+
+// Client id rejected when with_reject_client_zero is enabled.
+const RESERVED_CLIENT_ID: ClientId = 0;
+
+// Callback invoked with every transaction passed to accept and its outcome.
+pub type Observer = dyn FnMut(&Transaction, &Result<(), TransactionError>) + Send;
+
 impl TransactionManager {
     pub fn new() -> TransactionManager {
         TransactionManager {
             balances: HashMap::new(),
             transactions: HashMap::new(),
+            disputed: HashSet::new(),
+            pending_withdrawals: HashSet::new(),
+            locked_clients: HashSet::new(),
+            totals: Totals::default(),
+            flows: HashMap::new(),
+            replay: false,
+            held_cap: None,
+            metrics: None,
+            observer: None,
+            max_amount: None,
+            min_deposit: None,
+            max_transaction_id: None,
+            max_disputes_per_transaction: 1,
+            chargeback_policy: ChargebackPolicy::default(),
+            last_applied: None,
+            processed: 0,
+            reject_client_zero: false,
+            withdrawal_holds: false,
+            partial_withdrawals: false,
+            evict_finalized: false,
+            trust_stored_client: false,
+            evicted: HashSet::new(),
+            duplicate_policy: DuplicatePolicy::default(),
+            withdrawal_fee_flat: Money::ZERO,
+            withdrawal_fee_bps: 0,
+            event_sink: None,
+            max_clients: None,
+            history: None,
+            balance_timeline: None,
         }
     }
 
+    // Starts the engine from opening balances, e.g. the output of a previous run, rather than zero. No
+    // transactions are created, so disputes referencing transactions from before the snapshot are not
+    // found. If a client appears more than once the last balance wins.
+    pub fn from_balances<I>(opening_balances: I) -> TransactionManager
+    where
+        I: IntoIterator<Item = OpeningBalance>,
+    {
+        let mut manager = TransactionManager::new();
+
+        for opening_balance in opening_balances {
+            let balance = Balance::opening(
+                opening_balance.available,
+                opening_balance.held,
+                opening_balance.locked,
+            );
+
+            if let Some(replaced) = manager.balances.insert(opening_balance.client_id, balance) {
+                manager.totals.open(-replaced.available(), -replaced.held());
+            }
+
+            if opening_balance.locked {
+                manager.locked_clients.insert(opening_balance.client_id);
+            } else {
+                manager.locked_clients.remove(&opening_balance.client_id);
+            }
+
+            manager
+                .totals
+                .open(opening_balance.available, opening_balance.held);
+        }
+
+        manager
+    }
+
+    // Enables replay mode for at-least-once delivery: a deposit or withdrawal resubmitted with the same
+    // id, client and amount is ignored, while one that conflicts with the original is rejected.
+    pub fn with_replay(mut self, replay: bool) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    // Rejects disputes that would push a client's held funds above the cap, guarding against runaway
+    // disputes.
+    pub fn with_held_cap(mut self, held_cap: Money) -> Self {
+        self.held_cap = Some(held_cap);
+        self
+    }
+
+    // Records counts and timings per operation type, available via metrics().
+    pub fn with_metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled.then(EngineMetrics::default);
+        self
+    }
+
+    // Rejects deposits and withdrawals above this amount, guarding against data-entry errors such as a
+    // misplaced decimal point.
+    pub fn with_max_amount(mut self, max_amount: Money) -> Self {
+        self.max_amount = Some(max_amount);
+        self
+    }
+
+    // Rejects deposits below this amount, guarding against clients flooding the engine with dust deposits.
+    // Withdrawals and administrative force deposits are not affected.
+    pub fn with_min_deposit(mut self, min_deposit: Money) -> Self {
+        self.min_deposit = Some(min_deposit);
+        self
+    }
+
+    // Rejects deposits and withdrawals with an id above this bound, guarding against corrupted id fields
+    // or a feed whose ids have wrapped.
+    pub fn with_max_transaction_id(mut self, max_transaction_id: TransactionId) -> Self {
+        self.max_transaction_id = Some(max_transaction_id);
+        self
+    }
+
+    // Allows a resolved transaction to be disputed again, up to this many disputes in total. Defaults to
+    // 1, i.e. a resolved transaction is final.
+    pub fn with_max_disputes_per_transaction(mut self, max_disputes: u32) -> Self {
+        self.max_disputes_per_transaction = max_disputes;
+        self
+    }
+
+    // Controls whether a chargeback may leave the client's available balance negative.
+    pub fn with_chargeback_policy(mut self, chargeback_policy: ChargebackPolicy) -> Self {
+        self.chargeback_policy = chargeback_policy;
+        self
+    }
+
+    // Rejects every transaction for client 0, for feeds that use it to mean an unknown client.
+    pub fn with_reject_client_zero(mut self, reject_client_zero: bool) -> Self {
+        self.reject_client_zero = reject_client_zero;
+        self
+    }
+
+    // Models T+N settlement: a withdrawal moves the funds from available to held, and only a later
+    // SettleWithdrawal removes them from held and total.
+    pub fn with_withdrawal_holds(mut self, withdrawal_holds: bool) -> Self {
+        self.withdrawal_holds = withdrawal_holds;
+        self
+    }
+
+    // For sweep accounts: a withdrawal larger than the available funds drains the account instead of
+    // failing with InsufficientFunds. The stored transaction records the amount actually withdrawn, and the
+    // remainder is added to the client's withdrawal_shortfall in flows.
+    pub fn with_partial_withdrawals(mut self, partial_withdrawals: bool) -> Self {
+        self.partial_withdrawals = partial_withdrawals;
+        self
+    }
+
+    // Bounds the memory of long streams by dropping transactions once resolved or charged back, keeping
+    // only their ids for duplicate detection. A later dispute of an evicted transaction is rejected with
+    // DisputedTransactionNotFound, and it no longer appears in detect_anomalies or transaction_timestamp.
+    pub fn with_evict_finalized(mut self, evict_finalized: bool) -> Self {
+        self.evict_finalized = evict_finalized;
+        self
+    }
+
+    // For feeds that omit or misstate the client of a dispute, resolve, chargeback or cancel_dispute: the
+    // client stored with the referenced transaction is used instead, so they are never rejected with a
+    // client mismatch.
+    pub fn with_trust_stored_client(mut self, trust_stored_client: bool) -> Self {
+        self.trust_stored_client = trust_stored_client;
+        self
+    }
+
+    // Controls how a deposit reusing the id of an earlier deposit is handled. Replay mode takes precedence
+    // for identical resubmissions.
+    pub fn with_duplicate_policy(mut self, duplicate_policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = duplicate_policy;
+        self
+    }
+
+    // Charges a fee on every withdrawal of a flat amount plus `bps` basis points (hundredths of a percent)
+    // of the amount withdrawn. The fee is deducted from available and total immediately, even when
+    // withdrawal holds are enabled, and a withdrawal is rejected if the client cannot cover both.
+    pub fn with_withdrawal_fee(mut self, flat: Money, bps: u32) -> Self {
+        self.withdrawal_fee_flat = flat;
+        self.withdrawal_fee_bps = bps;
+        self
+    }
+
+    // Sends a BalanceChange for each of available, held and total that a transaction changes. Events are
+    // sent with try_send so a slow or dropped receiver never blocks or fails processing; events that do not
+    // fit in the channel are dropped. Changes made outside accept, such as undo_last, are not reported.
+    pub fn with_event_sink(mut self, event_sink: mpsc::Sender<BalanceChange>) -> Self {
+        self.event_sink = Some(event_sink);
+        self
+    }
+
+    // Rejects transactions that would create a balance for a new client once this many clients have one,
+    // guarding against input that invents client ids to exhaust memory. Existing clients are unaffected,
+    // and balances merged in from another engine are not counted against the cap.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    // Keeps every successfully applied transaction in order so the run can be reproduced elsewhere with
+    // export_transactions. The history grows with the input, so it is off by default.
+    pub fn with_history(mut self, enabled: bool) -> Self {
+        self.history = enabled.then(Vec::new);
+        self
+    }
+
+    // Keeps a snapshot of the client's balance after every applied transaction, for balance_timeline. A
+    // snapshot per transaction is heavier than the history, so it is off by default.
+    pub fn with_balance_timeline(mut self, enabled: bool) -> Self {
+        self.balance_timeline = enabled.then(HashMap::new);
+        self
+    }
+
+    // Registers a callback invoked after each accepted or rejected transaction, e.g. to publish events to
+    // an external system.
+    pub fn with_observer<F>(mut self, observer: F) -> Self
+    where
+        F: FnMut(&Transaction, &Result<(), TransactionError>) + Send + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
     pub fn accept(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.accept_with_timestamp(transaction, None)
+    }
+
+    // Same as accept, but records when the transaction happened according to its source, available
+    // afterwards via transaction_timestamp. Transactions are still applied in the order they are accepted,
+    // whatever their timestamps. Only deposits, withdrawals and adjustments are stored, so the timestamp
+    // of any other transaction is not kept.
+    pub fn accept_with_timestamp(
+        &mut self,
+        transaction: Transaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), TransactionError> {
+        self.accept_with_rounding_error(transaction, timestamp, RoundingError::ZERO)
+    }
+
+    // Same as accept_with_timestamp, but records the error introduced by rounding the transaction's amount,
+    // e.g. from TransactionDto::to_transaction_with_rounding_error, in total_rounding_error. Only counted
+    // when the transaction changes a balance, so not for a replay, and reversed along with the transaction
+    // by undo_last or a replacement. A partial withdrawal is limited by the available funds rather than
+    // the rounded amount, so has no rounding error.
+    pub fn accept_with_rounding_error(
+        &mut self,
+        transaction: Transaction,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError> {
+        // Only keep a copy of the transaction if there is an observer or history to pass it to.
+        let copy = (self.observer.is_some() || self.history.is_some()).then(|| transaction.clone());
+
+        self.processed += 1;
+
+        let client_id = self.effective_client_id(&transaction);
+
+        // Only snapshot the balance if there is a sink to report changes to or a timeline to record.
+        let before = (self.event_sink.is_some() || self.balance_timeline.is_some())
+            .then(|| self.balance_snapshot(client_id));
+
+        let result = self.apply_measured(transaction, timestamp, rounding_error);
+
+        if let (Some(event_sink), Some(before)) = (&self.event_sink, before) {
+            for change in before.changes(client_id, self.balance_snapshot(client_id)) {
+                // A full or closed channel only loses events, it must not stop processing.
+                let _ = event_sink.try_send(change);
+            }
+        }
+
+        if let (Some(observer), Some(transaction)) = (&mut self.observer, &copy) {
+            observer(transaction, &result);
+        }
+
+        if let (Some(history), Some(transaction), Ok(())) = (&mut self.history, copy, &result) {
+            history.push((transaction, timestamp));
+        }
+
+        if let (Some(before), Ok(())) = (before, &result) {
+            let after = self.balance_snapshot(client_id);
+
+            if let Some(timeline) = &mut self.balance_timeline {
+                timeline
+                    .entry(client_id)
+                    .or_insert_with(|| vec![before])
+                    .push(after);
+            }
+        }
+
+        result
+    }
+
+    fn apply_measured(
+        &mut self,
+        transaction: Transaction,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError> {
+        if self.metrics.is_none() {
+            return self.apply(transaction, timestamp, rounding_error);
+        }
+
+        let operation = Operation::from(&transaction);
+        let started = Instant::now();
+
+        let result = self.apply(transaction, timestamp, rounding_error);
+
+        if let Some(metrics) = &mut self.metrics {
+            metrics.record(operation, started.elapsed(), result.is_ok());
+        }
+
+        result
+    }
+
+    fn apply(
+        &mut self,
+        transaction: Transaction,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError> {
+        if self.reject_client_zero && transaction.client_id() == RESERVED_CLIENT_ID {
+            return Err(TransactionError::ReservedClientId);
+        }
+
+        if let (
+            Transaction::Deposit { id, .. }
+            | Transaction::Withdrawal { id, .. }
+            | Transaction::ForceDeposit { id, .. },
+            Some(max_transaction_id),
+        ) = (&transaction, self.max_transaction_id)
+        {
+            if *id > max_transaction_id {
+                return Err(TransactionError::TransactionIdOutOfRange);
+            }
+        }
+
+        let client_id = self.effective_client_id(&transaction);
+
         match transaction {
             Transaction::Deposit {
                 id,
                 client_id,
                 amount_base_units: amount,
-            } => self.deposit(id, client_id, amount),
+            } => self.deposit(id, client_id, amount, timestamp, rounding_error),
             Transaction::Withdrawal {
                 id,
                 client_id,
                 amount_base_units: amount,
-            } => self.withdrawal(id, client_id, amount),
-            Transaction::Dispute { id, client_id } => self.dispute(id, client_id),
-            Transaction::Resolve { id, client_id } => self.resolve(id, client_id),
-            Transaction::Chargeback { id, client_id } => self.chargeback(id, client_id),
+            } => self.withdrawal(id, client_id, amount, timestamp, rounding_error),
+            Transaction::Adjustment {
+                id,
+                client_id,
+                amount_base_units: amount,
+            } => self.adjustment(id, client_id, amount, timestamp, rounding_error),
+            Transaction::ForceDeposit {
+                id,
+                client_id,
+                amount_base_units: amount,
+            } => self.force_deposit(id, client_id, amount, timestamp, rounding_error),
+            Transaction::SettleWithdrawal { id, client_id } => {
+                self.settle_withdrawal(id, client_id)
+            }
+            Transaction::Dispute { id, .. } => self.dispute(id, client_id),
+            Transaction::Resolve { id, .. } => self.resolve(id, client_id),
+            Transaction::Chargeback { id, .. } => self.chargeback(id, client_id),
+            Transaction::CancelDispute { id, .. } => self.cancel_dispute(id, client_id),
+            Transaction::Freeze { client_id } => {
+                self.get_balance_mut(client_id)?.freeze();
+                self.locked_clients.insert(client_id);
+                Ok(())
+            }
+            Transaction::Unfreeze { client_id } => {
+                // Unfreezing a client without a balance is a no-op rather than creating an empty one.
+                if let Some(balance) = self.balances.get_mut(&client_id) {
+                    balance.unfreeze();
+                    self.locked_clients.remove(&client_id);
+                }
+                Ok(())
+            }
         }
     }
 
+    // Same as accepting a Dispute, but returns the amount moved from available to held, e.g. for a
+    // confirmation message.
+    pub fn dispute_with_delta(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<Money, TransactionError> {
+        self.accept_with_delta(
+            transaction_id,
+            Transaction::Dispute {
+                id: transaction_id,
+                client_id,
+            },
+        )
+    }
+
+    // Same as accepting a Resolve, but returns the amount released from held back to available.
+    pub fn resolve_with_delta(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<Money, TransactionError> {
+        self.accept_with_delta(
+            transaction_id,
+            Transaction::Resolve {
+                id: transaction_id,
+                client_id,
+            },
+        )
+    }
+
+    // Same as accepting a Chargeback, but returns the amount removed from held and total.
+    pub fn chargeback_with_delta(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<Money, TransactionError> {
+        self.accept_with_delta(
+            transaction_id,
+            Transaction::Chargeback {
+                id: transaction_id,
+                client_id,
+            },
+        )
+    }
+
+    // Disputes, resolves and chargebacks always move the full amount of the disputed transaction, so it is
+    // looked up rather than threaded back out of apply.
+    fn accept_with_delta(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: Transaction,
+    ) -> Result<Money, TransactionError> {
+        let amount = self
+            .transactions
+            .get(&transaction_id)
+            .map(TransactionState::amount);
+
+        self.accept(transaction)?;
+
+        Ok(amount.expect("an accepted dispute operation references a stored transaction"))
+    }
+
+    // Same as accept, but returns the affected client's balance after the transaction is applied. Some
+    // transactions, e.g. an unfreeze for an unknown client, are accepted without creating a balance, in
+    // which case the empty balance is returned.
+    pub fn accept_with_result(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<ClientBalance, TransactionError> {
+        let client_id = self.effective_client_id(&transaction);
+
+        self.accept(transaction)?;
+
+        Ok(self
+            .balance(client_id)
+            .unwrap_or_else(|| ClientBalance::new(client_id, &Balance::default())))
+    }
+
     fn deposit(
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
-        amount: i64,
+        amount: Money,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
     ) -> Result<(), TransactionError> {
-        if let Some(_) = self.transactions.get_mut(&transaction_id) {
-            return Err(TransactionError::DuplicateTransaction);
+        if self.duplicate_policy == DuplicatePolicy::Replace {
+            self.remove_replaced_deposit(transaction_id, client_id, amount)?;
+        }
+
+        if self.is_replay(&TransactionType::Deposit, transaction_id, client_id, amount)? {
+            return Ok(());
         }
 
+        self.check_amount_limit(amount)?;
+        self.check_min_deposit(amount)?;
+
+        self.check_not_locked(client_id)?;
+
         let transaction_state =
-            TransactionState::new(TransactionType::Deposit, transaction_id, client_id, amount)?;
+            TransactionState::new(TransactionType::Deposit, transaction_id, client_id, amount)?
+                .with_timestamp(timestamp)
+                .with_rounding_error(rounding_error);
 
-        let balance = self.get_balance_mut(client_id);
+        let balance = self.get_balance_mut(client_id)?;
 
         balance.deposit(amount);
 
+        self.totals.deposit(amount);
+        self.totals.record_rounding_error(rounding_error);
+
+        self.flows.entry(client_id).or_default().gross_deposits += amount;
+
         self.insert_transaction(transaction_state);
 
+        self.last_applied = Some(transaction_id);
+
         Ok(())
     }
 
@@ -67,694 +652,5469 @@ impl TransactionManager {
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
-        amount: i64,
+        amount: Money,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
     ) -> Result<(), TransactionError> {
-        if let Some(_) = self.transactions.get_mut(&transaction_id) {
-            return Err(TransactionError::DuplicateTransaction);
+        if self.is_replay(
+            &TransactionType::Withdrawal,
+            transaction_id,
+            client_id,
+            amount,
+        )? {
+            return Ok(());
         }
 
+        self.check_amount_limit(amount)?;
+
+        self.check_not_locked(client_id)?;
+
+        let requested = amount;
+
+        let amount = if self.partial_withdrawals && !amount.is_negative() {
+            self.partial_withdrawal_amount(client_id, amount)?
+        } else {
+            amount
+        };
+
+        let shortfall = requested - amount;
+
+        // Debiting more than the exact amount lowers the balance, so the error counts against the client.
+        // A partial withdrawal is set by the available funds instead, so was not rounded.
+        let rounding_error = if shortfall.is_zero() {
+            -rounding_error
+        } else {
+            RoundingError::ZERO
+        };
+
         let transaction_state = TransactionState::new(
             TransactionType::Withdrawal,
             transaction_id,
             client_id,
             amount,
-        )?;
+        )?
+        .with_timestamp(timestamp)
+        .with_shortfall(shortfall)
+        .with_rounding_error(rounding_error);
+
+        let withdrawal_holds = self.withdrawal_holds;
+
+        let fee = self.withdrawal_fee(amount);
 
-        let balance = self.get_balance_mut(client_id);
+        // The available funds must cover the fee as well as the amount withdrawn.
+        let amount_with_fee = amount
+            .checked_add(fee)
+            .ok_or(TransactionError::AmountExceedsLimit)?;
 
-        balance.withdrawal(amount)?;
+        let balance = self.get_balance_mut(client_id)?;
+
+        balance.available_after_withdrawal(amount_with_fee)?;
+
+        balance.charge_fee(fee);
+
+        if withdrawal_holds {
+            balance.hold_withdrawal(amount)?;
+
+            self.totals.hold(amount);
+
+            self.pending_withdrawals.insert(transaction_id);
+        } else {
+            balance.withdrawal(amount)?;
+
+            self.totals.withdrawal(amount);
+        }
+
+        self.totals.fee(fee);
+        self.totals.record_rounding_error(rounding_error);
+
+        let flows = self.flows.entry(client_id).or_default();
+
+        flows.gross_withdrawals += amount;
+        flows.withdrawal_shortfall += shortfall;
 
         self.insert_transaction(transaction_state);
 
+        self.last_applied = Some(transaction_id);
+
         Ok(())
     }
 
-    fn dispute(
+    // Completes a withdrawal held under with_withdrawal_holds, removing the funds from the client's held and
+    // total balances. Settlement is allowed on locked accounts, as the funds have already left available.
+    fn settle_withdrawal(
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
     ) -> Result<(), TransactionError> {
-        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
-            if client_id != disputed_transaction.client_id() {
-                return Err(TransactionError::DisputeClientMismatch);
-            }
+        let withdrawal = self
+            .transactions
+            .get(&transaction_id)
+            .ok_or(TransactionError::PendingWithdrawalNotFound)?;
+
+        if client_id != withdrawal.client_id() {
+            return Err(TransactionError::SettleWithdrawalClientMismatch {
+                expected: withdrawal.client_id(),
+                actual: client_id,
+            });
+        }
 
-            let amount = disputed_transaction.amount();
+        let amount = withdrawal.amount();
 
-            disputed_transaction.dispute()?;
+        if !self.pending_withdrawals.remove(&transaction_id) {
+            return Err(TransactionError::PendingWithdrawalNotFound);
+        }
 
-            let balance = self.get_balance_mut(client_id);
+        let balance = self.get_balance_mut(client_id)?;
 
-            balance.hold(amount);
+        balance.settle_withdrawal(amount);
 
-            Ok(())
-        } else {
-            Err(TransactionError::DisputedTransactionNotFound)
-        }
+        self.totals.release(amount);
+        self.totals.withdrawal(amount);
+
+        Ok(())
     }
 
-    fn resolve(
+    // Credits the client like a deposit, but bypasses the locked account check so an operator can correct an
+    // erroneous chargeback on a locked account. The account stays locked. Stored as a ForceDeposit so it is
+    // distinguished from client deposits, and cannot be disputed.
+    fn force_deposit(
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
+        amount: Money,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
     ) -> Result<(), TransactionError> {
-        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
-            if client_id != disputed_transaction.client_id() {
-                return Err(TransactionError::ResolveClientMismatch);
-            }
+        if self.is_replay(
+            &TransactionType::ForceDeposit,
+            transaction_id,
+            client_id,
+            amount,
+        )? {
+            return Ok(());
+        }
 
-            let amount = disputed_transaction.amount();
+        self.check_amount_limit(amount)?;
 
-            disputed_transaction.resolve()?;
+        let transaction_state = TransactionState::new(
+            TransactionType::ForceDeposit,
+            transaction_id,
+            client_id,
+            amount,
+        )?
+        .with_timestamp(timestamp)
+        .with_rounding_error(rounding_error);
 
-            let balance = self.get_balance_mut(client_id);
+        self.get_balance_mut(client_id)?.deposit(amount);
 
-            balance.release(amount);
+        self.totals.deposit(amount);
+        self.totals.record_rounding_error(rounding_error);
 
-            Ok(())
-        } else {
-            Err(TransactionError::DisputedTransactionNotFound)
-        }
+        self.flows.entry(client_id).or_default().gross_deposits += amount;
+
+        self.insert_transaction(transaction_state);
+
+        self.last_applied = Some(transaction_id);
+
+        Ok(())
     }
 
-    fn chargeback(
+    // Posts a manual correction directly to the client's available and total balances, bypassing the
+    // positive amount and sufficient funds checks of deposits and withdrawals. Locked accounts still reject
+    // adjustments. Adjustments are stored so their id cannot be reused, but cannot be disputed.
+    fn adjustment(
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
+        amount: Money,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
     ) -> Result<(), TransactionError> {
-        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
-            if client_id != disputed_transaction.client_id() {
-                return Err(TransactionError::ChargebackClientMismatch);
-            }
+        if self.is_replay(
+            &TransactionType::Adjustment,
+            transaction_id,
+            client_id,
+            amount,
+        )? {
+            return Ok(());
+        }
 
-            let amount = disputed_transaction.amount();
+        self.check_not_locked(client_id)?;
 
-            disputed_transaction.chargeback()?;
+        let transaction_state = TransactionState::new(
+            TransactionType::Adjustment,
+            transaction_id,
+            client_id,
+            amount,
+        )?
+        .with_timestamp(timestamp)
+        .with_rounding_error(rounding_error);
 
-            let balance = self.get_balance_mut(client_id);
+        self.get_balance_mut(client_id)?.adjust(amount);
 
-            balance.chargeback(amount);
+        self.totals.adjust(amount);
+        self.totals.record_rounding_error(rounding_error);
 
-            Ok(())
-        } else {
-            Err(TransactionError::DisputedTransactionNotFound)
-        }
-    }
+        self.insert_transaction(transaction_state);
+
+        self.last_applied = Some(transaction_id);
 
-    fn get_balance_mut(&mut self, client_id: ClientId) -> &mut Balance {
-        self.balances.entry(client_id).or_insert(Balance::new())
+        Ok(())
     }
 
-    fn insert_transaction(&mut self, transaction: TransactionState) {
-        let transaction_id = transaction.id();
+    // Reverses the most recently applied deposit, withdrawal or adjustment as if it had never been accepted,
+    // e.g. for interactive test harnesses. Only one level of undo is kept, and a transaction that has been
+    // disputed cannot be undone.
+    pub fn undo_last(&mut self) -> Result<(), TransactionError> {
+        let transaction_id = self.last_applied.ok_or(TransactionError::NothingToUndo)?;
 
-        if self
+        // An evicted transaction has been finalized after a dispute.
+        let transaction = self
             .transactions
-            .insert(transaction_id, transaction)
-            .is_some()
-        {
-            // We expect duplicates to have already been checked be we reach here.
-            panic!("Duplicate transaction id: {}", transaction_id);
+            .get(&transaction_id)
+            .ok_or(TransactionError::UndoDisputedTransaction)?;
+
+        if *transaction.status() != TransactionStatus::Valid {
+            return Err(TransactionError::UndoDisputedTransaction);
+        }
+
+        let transaction = self
+            .transactions
+            .remove(&transaction_id)
+            .expect("last applied transaction is stored");
+
+        self.last_applied = None;
+
+        self.forget_history(transaction_id);
+
+        let client_id = transaction.client_id();
+        let amount = transaction.amount();
+        let shortfall = transaction.shortfall();
+
+        self.totals
+            .record_rounding_error(-transaction.rounding_error());
+
+        let pending = self.pending_withdrawals.remove(&transaction_id);
+
+        // The fee is recalculated rather than stored, as it only depends on the amount.
+        let fee = self.withdrawal_fee(amount);
+
+        let balance = self.get_balance_mut(client_id)?;
+
+        match transaction.transaction_type() {
+            TransactionType::Deposit => {
+                balance.reverse_deposit(amount);
+
+                self.totals.deposit(-amount);
+
+                self.flows.entry(client_id).or_default().gross_deposits -= amount;
+            }
+            TransactionType::Withdrawal if pending => {
+                balance.release(amount);
+                balance.charge_fee(-fee);
+
+                self.totals.fee(-fee);
+
+                self.totals.release(amount);
+
+                let flows = self.flows.entry(client_id).or_default();
+
+                flows.gross_withdrawals -= amount;
+                flows.withdrawal_shortfall -= shortfall;
+            }
+            TransactionType::Withdrawal => {
+                balance.reverse_withdrawal(amount);
+                balance.charge_fee(-fee);
+
+                self.totals.fee(-fee);
+
+                self.totals.withdrawal(-amount);
+
+                let flows = self.flows.entry(client_id).or_default();
+
+                flows.gross_withdrawals -= amount;
+                flows.withdrawal_shortfall -= shortfall;
+            }
+            TransactionType::Adjustment => {
+                balance.adjust(-amount);
+
+                self.totals.adjust(-amount);
+            }
+            TransactionType::ForceDeposit => {
+                balance.reverse_deposit(amount);
+
+                self.totals.deposit(-amount);
+
+                self.flows.entry(client_id).or_default().gross_deposits -= amount;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Drops the deposit, withdrawal or adjustment with this id from the history, so an export does not
+    // replay a transaction whose effect has been reversed.
+    fn forget_history(&mut self, transaction_id: TransactionId) {
+        if let Some(history) = &mut self.history {
+            let reversed = history.iter().rposition(|(transaction, _)| {
+                matches!(
+                    *transaction,
+                    Transaction::Deposit { id, .. }
+                    | Transaction::Withdrawal { id, .. }
+                    | Transaction::Adjustment { id, .. }
+                    | Transaction::ForceDeposit { id, .. } if id == transaction_id
+                )
+            });
+
+            if let Some(position) = reversed {
+                history.remove(position);
+            }
         }
     }
 
-    // Copies balance entries to ClientBalance so as to not break encapsulation.
-    pub fn balances(&self) -> Vec<ClientBalance> {
-        self.balances
-            .iter()
-            .map(|(&client_id, balance)| ClientBalance {
-                client_id,
-                available: ClientBalance::from_base_units(balance.available()),
-                held: ClientBalance::from_base_units(balance.held()),
-                total: ClientBalance::from_base_units(balance.total()),
-                locked: balance.locked(),
+    // Under DuplicatePolicy::Replace, reverses and removes a stored deposit with the same id as a new one
+    // so the new deposit can take its place. Everything that could reject the new deposit is checked first,
+    // so the original is only removed if its replacement will be applied. Does nothing if there is no
+    // stored deposit with the id, or if the new deposit is an identical replay.
+    fn remove_replaced_deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Money,
+    ) -> Result<(), TransactionError> {
+        let Some(original) = self.transactions.get(&transaction_id) else {
+            return Ok(());
+        };
+
+        if *original.transaction_type() != TransactionType::Deposit
+            || (self.replay && original.client_id() == client_id && original.amount() == amount)
+        {
+            return Ok(());
+        }
+
+        if *original.status() != TransactionStatus::Valid {
+            return Err(TransactionError::ReplaceDisputedTransaction);
+        }
+
+        let original_client_id = original.client_id();
+        let original_amount = original.amount();
+        let original_rounding_error = original.rounding_error();
+
+        if amount.is_negative() {
+            return Err(TransactionError::AmountIsNegative);
+        }
+
+        self.check_amount_limit(amount)?;
+        self.check_min_deposit(amount)?;
+        self.check_not_locked(original_client_id)?;
+        self.check_not_locked(client_id)?;
+        self.get_balance_mut(client_id)?;
+
+        self.transactions.remove(&transaction_id);
+        self.forget_history(transaction_id);
+
+        self.get_balance_mut(original_client_id)?
+            .reverse_deposit(original_amount);
+
+        self.totals.deposit(-original_amount);
+        self.totals.record_rounding_error(-original_rounding_error);
+
+        self.flows
+            .entry(original_client_id)
+            .or_default()
+            .gross_deposits -= original_amount;
+
+        Ok(())
+    }
+
+    // Folds another engine's state into this one, e.g. to combine the workers of sharded processing. Fails
+    // without changing either engine if they share a transaction id, stored or evicted, which for sharded
+    // processing indicates a transaction id reused across shards.
+    //
+    // Balances, flows and totals of a client present in both engines are summed and the account is
+    // locked if it was locked in either. Only this engine's configuration, such as replay and limits, is
+    // kept, and undo_last has nothing to undo afterwards. If both engines keep a balance timeline, the
+    // other's snapshots are appended to this one's; for a client in both, each engine's snapshots show
+    // only the balance within that engine.
+    pub fn merge(&mut self, other: TransactionManager) -> Result<(), TransactionError> {
+        if let Some(&transaction_id) =
+            other
+                .transactions
+                .keys()
+                .chain(&other.evicted)
+                .find(|transaction_id| {
+                    self.transactions.contains_key(transaction_id)
+                        || self.evicted.contains(transaction_id)
+                })
+        {
+            return Err(TransactionError::MergeConflict(transaction_id));
+        }
+
+        for (client_id, balance) in &other.balances {
+            self.balances.entry(*client_id).or_default().merge(balance);
+        }
+
+        for (client_id, flows) in other.flows {
+            let merged = self.flows.entry(client_id).or_default();
+
+            merged.gross_deposits += flows.gross_deposits;
+            merged.gross_withdrawals += flows.gross_withdrawals;
+            merged.withdrawal_shortfall += flows.withdrawal_shortfall;
+        }
+
+        self.transactions.extend(other.transactions);
+        self.evicted.extend(other.evicted);
+        self.disputed.extend(other.disputed);
+        self.pending_withdrawals.extend(other.pending_withdrawals);
+        self.locked_clients.extend(other.locked_clients);
+        self.totals.merge(&other.totals);
+
+        if let (Some(metrics), Some(other_metrics)) = (&mut self.metrics, &other.metrics) {
+            metrics.merge(other_metrics);
+        }
+
+        if let (Some(timeline), Some(other_timeline)) =
+            (&mut self.balance_timeline, other.balance_timeline)
+        {
+            for (client_id, snapshots) in other_timeline {
+                timeline.entry(client_id).or_default().extend(snapshots);
+            }
+        }
+
+        self.processed += other.processed;
+        self.last_applied = None;
+
+        Ok(())
+    }
+
+    // Writes the transactions applied so far, in order, as CSV that read_transactions reads back. Applying
+    // the rows to a fresh engine reproduces the same balances. Requires with_history, and does not cover
+    // changes made outside accept, such as split_resolve, merge or opening balances.
+    pub async fn export_transactions<W>(&self, writer: W) -> Result<(), Box<dyn Error>>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or(TransactionError::HistoryNotEnabled)?;
+
+        csv::write_transactions(
+            writer,
+            history.iter().map(|(transaction, timestamp)| {
+                TransactionDto::from_transaction(transaction, *timestamp)
+            }),
+        )
+        .await
+    }
+
+    // Parses transactions in the input CSV format from a string and applies them in order, without files
+    // or an async runtime, e.g. for scripts and tests. Returns one result per row, with rows that cannot be
+    // read or converted reported as InvalidRow. Amounts are rounded half up.
+    pub fn apply_csv_str(&mut self, csv: &str) -> Vec<Result<(), TransactionError>> {
+        let invalid_row = |error: &dyn Error| TransactionError::InvalidRow(error.to_string());
+
+        csv::read_transactions_sync(csv.as_bytes(), HashMap::new(), CsvFormat::default())
+            .map(|row| {
+                let dto = row.map_err(|error| invalid_row(&*error))?;
+
+                let transaction = dto
+                    .to_transaction(RoundingMode::default())
+                    .map_err(|error| invalid_row(&error))?;
+
+                self.accept_with_timestamp(transaction, dto.timestamp)
             })
             .collect()
     }
-}
 
-pub struct ClientBalance {
-    pub client_id: ClientId,
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
-    pub locked: bool,
-}
+    // Encodes the engine's state, i.e. balances, stored transactions, open disputes, pending withdrawals and
+    // running totals, in a compact binary form for checkpointing. Configuration set with the with_*
+    // methods, metrics, history and balance timelines are not included. The state is preceded by a header
+    // with the format version, so restore_binary can reject snapshots from other builds.
+    pub fn snapshot_binary(&self) -> Vec<u8> {
+        let snapshot = Snapshot {
+            balances: Cow::Borrowed(&self.balances),
+            transactions: Cow::Borrowed(&self.transactions),
+            evicted: Cow::Borrowed(&self.evicted),
+            disputed: Cow::Borrowed(&self.disputed),
+            pending_withdrawals: Cow::Borrowed(&self.pending_withdrawals),
+            locked_clients: Cow::Borrowed(&self.locked_clients),
+            totals: Cow::Borrowed(&self.totals),
+            flows: Cow::Borrowed(&self.flows),
+            last_applied: self.last_applied,
+            processed: self.processed,
+        };
 
-impl ClientBalance {
-    fn from_base_units(amount_base_units: i64) -> f64 {
-        amount_base_units as f64 / 10_000.0
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_LEN);
+
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.push(mem::size_of::<TransactionId>() as u8);
+
+        // Every field has a fixed shape with no maps keyed by anything bincode cannot encode, so encoding
+        // into memory cannot fail.
+        bincode::serialize_into(&mut bytes, &snapshot).expect("engine state is always encodable");
+
+        bytes
+    }
+
+    // Rebuilds an engine from snapshot_binary's output, with default configuration. Fails with
+    // InvalidSnapshot if the bytes are not a snapshot, or were written by a build with a different
+    // snapshot version or transaction id width.
+    pub fn restore_binary(bytes: &[u8]) -> Result<TransactionManager, TransactionError> {
+        let invalid = |message: String| TransactionError::InvalidSnapshot(message);
+
+        let (header, state) = bytes
+            .split_at_checked(SNAPSHOT_HEADER_LEN)
+            .filter(|(header, _)| header.starts_with(SNAPSHOT_MAGIC))
+            .ok_or_else(|| invalid("missing snapshot header".to_string()))?;
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "snapshot version {} is not supported, expected {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+
+        let id_width = usize::from(header[6]);
+
+        if id_width != mem::size_of::<TransactionId>() {
+            return Err(invalid(format!(
+                "snapshot has {}-byte transaction ids, expected {}",
+                id_width,
+                mem::size_of::<TransactionId>()
+            )));
+        }
+
+        let snapshot: Snapshot =
+            bincode::deserialize(state).map_err(|err| invalid(err.to_string()))?;
+
+        Ok(TransactionManager {
+            balances: snapshot.balances.into_owned(),
+            transactions: snapshot.transactions.into_owned(),
+            evicted: snapshot.evicted.into_owned(),
+            disputed: snapshot.disputed.into_owned(),
+            pending_withdrawals: snapshot.pending_withdrawals.into_owned(),
+            locked_clients: snapshot.locked_clients.into_owned(),
+            totals: snapshot.totals.into_owned(),
+            flows: snapshot.flows.into_owned(),
+            last_applied: snapshot.last_applied,
+            processed: snapshot.processed,
+            ..TransactionManager::new()
+        })
+    }
+
+    // The most of the requested amount that can be withdrawn with the client's available funds still
+    // covering the fee on it. Fails with InsufficientFunds if nothing can be withdrawn.
+    fn partial_withdrawal_amount(
+        &self,
+        client_id: ClientId,
+        requested: Money,
+    ) -> Result<Money, TransactionError> {
+        let available = self
+            .balances
+            .get(&client_id)
+            .map_or(Money::ZERO, Balance::available);
+
+        // The largest amount w with w + flat fee + w * bps / 10,000 within the available funds. As the fee
+        // rounds its percentage down, the fee on w never exceeds what is left.
+        let withdrawable = i128::from((available - self.withdrawal_fee_flat).base_units())
+            * BASIS_POINTS_PER_UNIT
+            / (BASIS_POINTS_PER_UNIT + i128::from(self.withdrawal_fee_bps));
+
+        let withdrawable = Money::from_base_units(i64::try_from(withdrawable).unwrap_or(i64::MAX));
+
+        if !withdrawable.is_positive() {
+            return Err(TransactionError::InsufficientFunds);
+        }
+
+        Ok(requested.min(withdrawable))
+    }
+
+    // The fee charged on a withdrawal of the given amount. The percentage part is rounded down to the
+    // nearest base unit, in the client's favour. Saturates rather than overflowing, so an unpayable fee is
+    // rejected when added to the amount.
+    fn withdrawal_fee(&self, amount: Money) -> Money {
+        let percentage = i128::from(amount.base_units()) * i128::from(self.withdrawal_fee_bps)
+            / BASIS_POINTS_PER_UNIT;
+
+        self.withdrawal_fee_flat
+            .checked_add(Money::from_base_units(
+                i64::try_from(percentage).unwrap_or(i64::MAX),
+            ))
+            .unwrap_or(Money::from_base_units(i64::MAX))
+    }
+
+    // Total withdrawal fees charged, net of any refunded by undo_last.
+    pub fn total_fees(&self) -> Money {
+        self.totals.fees()
+    }
+
+    // Net effect on client balances of rounding the amounts of the transactions applied with
+    // accept_with_rounding_error, in base units. Positive when rounding left clients with more than the
+    // exact inputs overall, i.e. credited them more or debited them less.
+    pub fn total_rounding_error(&self) -> RoundingError {
+        self.totals.rounding_error()
+    }
+
+    // Previews a withdrawal, returning the available balance it would leave, or the error it would be
+    // rejected with, without applying it. Duplicate ids are not checked as no id is given.
+    pub fn simulate_withdrawal(
+        &self,
+        client_id: ClientId,
+        amount: Money,
+    ) -> Result<Money, TransactionError> {
+        if amount.is_negative() {
+            return Err(TransactionError::AmountIsNegative);
+        }
+
+        self.check_amount_limit(amount)?;
+
+        self.check_not_locked(client_id)?;
+
+        let amount = amount
+            .checked_add(self.withdrawal_fee(amount))
+            .ok_or(TransactionError::AmountExceedsLimit)?;
+
+        match self.balances.get(&client_id) {
+            Some(balance) => balance.available_after_withdrawal(amount),
+            None => Balance::new().available_after_withdrawal(amount),
+        }
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
+            if client_id != disputed_transaction.client_id() {
+                return Err(TransactionError::DisputeClientMismatch {
+                    expected: disputed_transaction.client_id(),
+                    actual: client_id,
+                });
+            }
+
+            let amount = disputed_transaction.amount();
+
+            disputed_transaction.check_dispute(self.max_disputes_per_transaction)?;
+
+            if let Some(held_cap) = self.held_cap {
+                let held = self
+                    .balances
+                    .get(&client_id)
+                    .map_or(Money::ZERO, Balance::held);
+
+                if held + amount > held_cap {
+                    return Err(TransactionError::HeldCapExceeded);
+                }
+            }
+
+            disputed_transaction.dispute(self.max_disputes_per_transaction)?;
+
+            self.disputed.insert(transaction_id);
+
+            let balance = self.get_balance_mut(client_id)?;
+
+            balance.hold(amount);
+
+            self.totals.hold(amount);
+
+            Ok(())
+        } else {
+            Err(TransactionError::DisputedTransactionNotFound)
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
+            if client_id != disputed_transaction.client_id() {
+                return Err(TransactionError::ResolveClientMismatch {
+                    expected: disputed_transaction.client_id(),
+                    actual: client_id,
+                });
+            }
+
+            let amount = disputed_transaction.amount();
+
+            disputed_transaction.resolve()?;
+
+            self.disputed.remove(&transaction_id);
+
+            let balance = self.get_balance_mut(client_id)?;
+
+            balance.release(amount);
+
+            self.totals.release(amount);
+
+            self.evict(transaction_id);
+
+            Ok(())
+        } else {
+            Err(TransactionError::DisputedTransactionNotFound)
+        }
+    }
+
+    // The client a transaction applies to: under with_trust_stored_client, the client of the transaction a
+    // dispute, resolve, chargeback or cancel_dispute references, if it is stored, otherwise the given one.
+    fn effective_client_id(&self, transaction: &Transaction) -> ClientId {
+        match *transaction {
+            Transaction::Dispute { id, client_id }
+            | Transaction::Resolve { id, client_id }
+            | Transaction::Chargeback { id, client_id }
+            | Transaction::CancelDispute { id, client_id }
+                if self.trust_stored_client =>
+            {
+                self.transactions
+                    .get(&id)
+                    .map_or(client_id, TransactionState::client_id)
+            }
+            _ => transaction.client_id(),
+        }
+    }
+
+    // Under with_evict_finalized, drops a transaction that has been resolved or charged back.
+    fn evict(&mut self, transaction_id: TransactionId) {
+        let finalized = self
+            .transactions
+            .get(&transaction_id)
+            .is_some_and(|transaction| transaction.is_finalized(self.max_disputes_per_transaction));
+
+        if self.evict_finalized && finalized {
+            self.transactions.remove(&transaction_id);
+            self.evicted.insert(transaction_id);
+        }
+    }
+
+    // Withdraws a dispute at the client's request, releasing the held funds and returning the transaction
+    // to Valid rather than Resolved.
+    fn cancel_dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
+            if client_id != disputed_transaction.client_id() {
+                return Err(TransactionError::CancelDisputeClientMismatch {
+                    expected: disputed_transaction.client_id(),
+                    actual: client_id,
+                });
+            }
+
+            let amount = disputed_transaction.amount();
+
+            disputed_transaction.cancel_dispute()?;
+
+            self.disputed.remove(&transaction_id);
+
+            let balance = self.get_balance_mut(client_id)?;
+
+            balance.release(amount);
+
+            self.totals.release(amount);
+
+            Ok(())
+        } else {
+            Err(TransactionError::DisputedTransactionNotFound)
+        }
+    }
+
+    // Settles a dispute by releasing part of the held amount back to the client and charging back the
+    // rest. The two amounts must sum exactly to the disputed amount. Any charged back portion locks the
+    // account and finalizes the transaction as a chargeback, otherwise it is resolved.
+    pub fn split_resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        resolve_amount: Money,
+        chargeback_amount: Money,
+    ) -> Result<(), TransactionError> {
+        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
+            if client_id != disputed_transaction.client_id() {
+                return Err(TransactionError::ResolveClientMismatch {
+                    expected: disputed_transaction.client_id(),
+                    actual: client_id,
+                });
+            }
+
+            if resolve_amount.is_negative() || chargeback_amount.is_negative() {
+                return Err(TransactionError::AmountIsNegative);
+            }
+
+            if resolve_amount.checked_add(chargeback_amount) != Some(disputed_transaction.amount())
+            {
+                return Err(TransactionError::SplitAmountMismatch);
+            }
+
+            if chargeback_amount.is_positive() {
+                disputed_transaction.chargeback()?;
+            } else {
+                disputed_transaction.resolve()?;
+            }
+
+            self.disputed.remove(&transaction_id);
+
+            let chargeback_policy = self.chargeback_policy;
+            let balance = self.get_balance_mut(client_id)?;
+
+            balance.split_release(resolve_amount, chargeback_amount, chargeback_policy);
+
+            if chargeback_amount.is_positive() {
+                self.locked_clients.insert(client_id);
+            }
+
+            self.totals.release(resolve_amount);
+            self.totals.chargeback(chargeback_amount);
+
+            self.evict(transaction_id);
+
+            Ok(())
+        } else {
+            Err(TransactionError::DisputedTransactionNotFound)
+        }
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        if let Some(disputed_transaction) = self.transactions.get_mut(&transaction_id) {
+            if client_id != disputed_transaction.client_id() {
+                return Err(TransactionError::ChargebackClientMismatch {
+                    expected: disputed_transaction.client_id(),
+                    actual: client_id,
+                });
+            }
+
+            let amount = disputed_transaction.amount();
+
+            disputed_transaction.chargeback()?;
+
+            self.disputed.remove(&transaction_id);
+
+            let chargeback_policy = self.chargeback_policy;
+            let balance = self.get_balance_mut(client_id)?;
+
+            balance.chargeback(amount, chargeback_policy);
+
+            self.locked_clients.insert(client_id);
+
+            self.totals.chargeback(amount);
+
+            self.evict(transaction_id);
+
+            Ok(())
+        } else {
+            Err(TransactionError::DisputedTransactionNotFound)
+        }
+    }
+
+    // Every transaction currently under dispute as (transaction ID, client ID, held amount), ordered by
+    // transaction ID.
+    pub fn disputed_transactions(&self) -> Vec<(TransactionId, ClientId, Money)> {
+        let mut disputed: Vec<_> = self
+            .disputed
+            .iter()
+            .map(|transaction_id| {
+                let transaction = &self.transactions[transaction_id];
+
+                (
+                    transaction.id(),
+                    transaction.client_id(),
+                    transaction.amount(),
+                )
+            })
+            .collect();
+
+        disputed.sort_by_key(|&(transaction_id, _, _)| transaction_id);
+
+        disputed
+    }
+
+    // The client's disputed transactions as (transaction ID, held amount), ordered by transaction ID. The
+    // amounts sum to the client's held balance unless it was seeded from an opening balance or has
+    // withdrawals awaiting settlement.
+    pub fn held_breakdown(&self, client_id: ClientId) -> Vec<(TransactionId, Money)> {
+        let mut held: Vec<_> = self
+            .disputed
+            .iter()
+            .map(|transaction_id| &self.transactions[transaction_id])
+            .filter(|transaction| transaction.client_id() == client_id)
+            .map(|transaction| (transaction.id(), transaction.amount()))
+            .collect();
+
+        held.sort_by_key(|&(transaction_id, _)| transaction_id);
+
+        held
+    }
+
+    // The most the client could have held if they disputed every deposit still open to dispute, i.e. the
+    // sum of their Valid deposits and their Resolved deposits below the dispute limit. Scans every stored
+    // transaction.
+    pub fn max_dispute_exposure(&self, client_id: ClientId) -> Money {
+        self.transactions
+            .values()
+            .filter(|transaction| {
+                transaction.client_id() == client_id
+                    && transaction
+                        .check_dispute(self.max_disputes_per_transaction)
+                        .is_ok()
+            })
+            .map(TransactionState::amount)
+            .sum()
+    }
+
+    // How the client's balance evolved under with_balance_timeline: their balance before their first
+    // applied transaction, then after each applied transaction in order, so transaction n took the balance
+    // from entry n to entry n + 1. Rejected transactions and undo_last are not recorded. Empty if the
+    // timeline is not enabled or no transaction for the client has been applied.
+    pub fn balance_timeline(&self, client_id: ClientId) -> Vec<BalanceSnapshot> {
+        self.balance_timeline
+            .as_ref()
+            .and_then(|timeline| timeline.get(&client_id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // When a stored transaction happened according to its source, if it was accepted with a timestamp.
+    pub fn transaction_timestamp(&self, transaction_id: TransactionId) -> Option<Timestamp> {
+        self.transactions
+            .get(&transaction_id)
+            .and_then(TransactionState::timestamp)
+    }
+
+    // The disputed transaction holding the largest amount, if any disputes are open.
+    pub fn largest_held(&self) -> Option<(TransactionId, ClientId, Money)> {
+        self.disputed
+            .iter()
+            .map(|transaction_id| &self.transactions[transaction_id])
+            .max_by_key(|transaction| transaction.amount())
+            .map(|transaction| {
+                (
+                    transaction.id(),
+                    transaction.client_id(),
+                    transaction.amount(),
+                )
+            })
+    }
+
+    // Checks a new deposit or withdrawal against any stored transaction with the same id. Returns true if
+    // it is an identical replay that should be skipped.
+    fn is_replay(
+        &self,
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Money,
+    ) -> Result<bool, TransactionError> {
+        match self.transactions.get(&transaction_id) {
+            None if self.evicted.contains(&transaction_id) => {
+                Err(TransactionError::DuplicateTransaction)
+            }
+            None => Ok(false),
+            Some(_) if !self.replay => Err(TransactionError::DuplicateTransaction),
+            Some(existing)
+                if existing.transaction_type() == transaction_type
+                    && existing.client_id() == client_id
+                    && existing.amount() == amount =>
+            {
+                Ok(true)
+            }
+            Some(_) => Err(TransactionError::ConflictingDuplicate),
+        }
+    }
+
+    fn check_amount_limit(&self, amount: Money) -> Result<(), TransactionError> {
+        match self.max_amount {
+            Some(max_amount) if amount > max_amount => Err(TransactionError::AmountExceedsLimit),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_min_deposit(&self, amount: Money) -> Result<(), TransactionError> {
+        match self.min_deposit {
+            Some(min_deposit) if amount < min_deposit => Err(TransactionError::BelowMinimumDeposit),
+            _ => Ok(()),
+        }
+    }
+
+    // Locked accounts, whether by a chargeback or a freeze, accept no further deposits or withdrawals.
+    // Disputes on their existing transactions are still processed.
+    fn check_not_locked(&self, client_id: ClientId) -> Result<(), TransactionError> {
+        match self.balances.get(&client_id) {
+            Some(balance) if balance.locked() => Err(TransactionError::AccountLocked),
+            _ => Ok(()),
+        }
+    }
+
+    // A client without a balance is treated as all zero, so creating one reports no changes.
+    fn balance_snapshot(&self, client_id: ClientId) -> BalanceSnapshot {
+        self.balances
+            .get(&client_id)
+            .map(BalanceSnapshot::of)
+            .unwrap_or_default()
+    }
+
+    // Creates a balance for a client seen for the first time, unless that would exceed the maximum number of
+    // clients.
+    fn get_balance_mut(&mut self, client_id: ClientId) -> Result<&mut Balance, TransactionError> {
+        let at_capacity = self
+            .max_clients
+            .is_some_and(|max_clients| self.balances.len() >= max_clients);
+
+        match self.balances.entry(client_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(_) if at_capacity => Err(TransactionError::TooManyClients),
+            Entry::Vacant(entry) => Ok(entry.insert(Balance::default())),
+        }
+    }
+
+    fn insert_transaction(&mut self, transaction: TransactionState) {
+        let transaction_id = transaction.id();
+
+        if self
+            .transactions
+            .insert(transaction_id, transaction)
+            .is_some()
+        {
+            // We expect duplicates to have already been checked be we reach here.
+            panic!("Duplicate transaction id: {}", transaction_id);
+        }
+    }
+
+    // Checks every client's balance is internally consistent, i.e. total equals available plus held,
+    // returning the lowest client id that is not. Unlike consistency_report this does not depend on the
+    // running totals, so pinpoints the broken balance.
+    pub fn verify_invariants(&self) -> Result<(), (ClientId, TransactionError)> {
+        let mut client_ids: Vec<_> = self.balances.keys().copied().collect();
+        client_ids.sort_unstable();
+
+        for client_id in client_ids {
+            self.balances[&client_id]
+                .check_invariant()
+                .map_err(|error| (client_id, error))?;
+        }
+
+        Ok(())
+    }
+
+    // Flags clients needing review, ordered by client id: funds held beyond the deposits that have not been
+    // charged back, which are all a dispute can hold, or a locked account with funds still available.
+    // Read-only, for spotting logic bugs or accounts needing review. Held funds from opening balances have
+    // no deposits behind them, so may be flagged. Once transactions have been evicted under
+    // with_evict_finalized the deposit history is incomplete, so held funds are no longer checked. A
+    // chargeback or freeze leaves any other funds available, so a locked account with funds available is
+    // not necessarily a bug.
+    pub fn detect_anomalies(&self) -> Vec<Anomaly> {
+        let mut deposits: HashMap<ClientId, Money> = HashMap::new();
+
+        for transaction in self.transactions.values() {
+            if *transaction.transaction_type() == TransactionType::Deposit
+                && *transaction.status() != TransactionStatus::Chargeback
+            {
+                *deposits.entry(transaction.client_id()).or_default() += transaction.amount();
+            }
+        }
+
+        let mut client_ids: Vec<_> = self.balances.keys().copied().collect();
+        client_ids.sort_unstable();
+
+        let mut anomalies = Vec::new();
+
+        for client_id in client_ids {
+            let balance = &self.balances[&client_id];
+            let deposits = deposits.get(&client_id).copied().unwrap_or_default();
+
+            if self.evicted.is_empty() && balance.held() > deposits {
+                anomalies.push(Anomaly::HeldExceedsDeposits {
+                    client_id,
+                    held: balance.held(),
+                    deposits,
+                });
+            }
+
+            if balance.locked() && balance.available().is_positive() {
+                anomalies.push(Anomaly::LockedWithAvailable {
+                    client_id,
+                    available: balance.available(),
+                });
+            }
+        }
+
+        anomalies
+    }
+
+    // Reconciles the sum of all client balances against the totals deposited, withdrawn, held and
+    // charged back. Any discrepancy indicates a bug in the balance arithmetic.
+    pub fn consistency_report(&self) -> ConsistencyReport {
+        let (balances_total, balances_held) =
+            self.balances
+                .values()
+                .fold((Money::ZERO, Money::ZERO), |(total, held), balance| {
+                    // A shortfall is money that was never received, so is excluded from the reconciliation.
+                    (
+                        total + balance.total() - balance.shortfall(),
+                        held + balance.held(),
+                    )
+                });
+
+        self.totals.report(balances_total, balances_held)
+    }
+
+    // Number of deposits and withdrawals currently stored, i.e. that can be referenced by a dispute.
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    // The current status of a stored deposit, withdrawal or adjustment, or None if there is no such
+    // transaction. Transactions dropped under with_evict_finalized are no longer stored, so are None.
+    pub fn transaction_status(&self, id: TransactionId) -> Option<TransactionStatus> {
+        self.transactions
+            .get(&id)
+            .map(|transaction| transaction.status().clone())
+    }
+
+    // Number of transactions of any type passed to accept so far, including rejected ones, e.g. for
+    // progress reporting.
+    pub fn processed_count(&self) -> usize {
+        self.processed
+    }
+
+    // Processing metrics, empty unless enabled with with_metrics.
+    pub fn metrics(&self) -> EngineMetrics {
+        self.metrics.clone().unwrap_or_default()
+    }
+
+    // Gross money that has flowed in and out of the client's account, unaffected by later disputes.
+    pub fn flows(&self, client_id: ClientId) -> Option<ClientFlows> {
+        self.flows.get(&client_id).copied()
+    }
+
+    // Number of locked accounts, whether by a chargeback, a freeze or an opening balance.
+    pub fn locked_account_count(&self) -> usize {
+        self.locked_clients.len()
+    }
+
+    // Ids of locked accounts in ascending order.
+    pub fn locked_clients(&self) -> Vec<ClientId> {
+        let mut clients: Vec<_> = self.locked_clients.iter().copied().collect();
+
+        clients.sort_unstable();
+
+        clients
+    }
+
+    // Clients owing money, i.e. a chargeback has pushed their available or total balance below zero.
+    pub fn clients_with_negative_balance(&self) -> Vec<ClientId> {
+        let mut clients: Vec<_> = self
+            .balances
+            .iter()
+            .filter(|(_, balance)| {
+                balance.available().is_negative() || balance.total().is_negative()
+            })
+            .map(|(&client_id, _)| client_id)
+            .collect();
+
+        clients.sort_unstable();
+
+        clients
+    }
+
+    // Borrows balance entries in client ID order, avoiding the copies made by balances().
+    pub fn iter_balances_sorted(&self) -> impl Iterator<Item = (ClientId, &Balance)> {
+        let mut balances: Vec<_> = self
+            .balances
+            .iter()
+            .map(|(&client_id, balance)| (client_id, balance))
+            .collect();
+
+        balances.sort_unstable_by_key(|&(client_id, _)| client_id);
+
+        balances.into_iter()
+    }
+
+    // Same as balances, but in client ID order, for callers that need stable output.
+    pub fn balances_sorted(&self) -> Vec<ClientBalance> {
+        self.iter_balances_sorted()
+            .map(|(client_id, balance)| ClientBalance::new(client_id, balance))
+            .collect()
+    }
+
+    // Copies balance entries to ClientBalance so as to not break encapsulation. In no particular order, see
+    // balances_sorted.
+    pub fn balances(&self) -> Vec<ClientBalance> {
+        self.balances
+            .iter()
+            .map(|(&client_id, balance)| ClientBalance::new(client_id, balance))
+            .collect()
+    }
+
+    // A hash of every balance in client ID order, for cheaply checking that two engines, e.g. a primary
+    // and a replica, ended in the same state. FNV-1a is used rather than the standard library's hasher,
+    // whose output may change between Rust releases, so hashes from different builds can be compared.
+    pub fn state_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut write = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+
+        for (client_id, balance) in self.iter_balances_sorted() {
+            write(&client_id.to_le_bytes());
+            write(&balance.available().base_units().to_le_bytes());
+            write(&balance.held().base_units().to_le_bytes());
+            write(&balance.total().base_units().to_le_bytes());
+            write(&balance.shortfall().base_units().to_le_bytes());
+            write(&[u8::from(balance.locked())]);
+        }
+
+        hash
+    }
+
+    // Whether the engine holds exactly the expected balances, in any order: the same set of clients, with
+    // equal amounts and locked flags. Amounts are compared exactly at the base-unit level, i.e. each
+    // expected amount is converted to base units and must match to the last of the four decimal places.
+    // Intended for asserting full state in tests outside the crate.
+    pub fn balances_equal(&self, expected: &[ClientBalance]) -> bool {
+        let matches = |expected: &ClientBalance| {
+            let to_base_units = |amount| Money::from_decimal(amount, RoundingMode::HalfUp);
+
+            self.balances
+                .get(&expected.client_id)
+                .is_some_and(|balance| {
+                    balance.available() == to_base_units(expected.available)
+                        && balance.held() == to_base_units(expected.held)
+                        && balance.total() == to_base_units(expected.total)
+                        && balance.locked() == expected.locked
+                })
+        };
+
+        let expected_clients: HashSet<_> =
+            expected.iter().map(|balance| balance.client_id).collect();
+
+        // Comparing the client counts also rejects duplicate clients in expected.
+        expected_clients.len() == expected.len()
+            && expected.len() == self.balances.len()
+            && expected.iter().all(matches)
+    }
+
+    // Ids of clients with a balance, i.e. that have had a transaction or an opening balance, in no
+    // particular order.
+    pub fn clients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.balances.keys().copied()
+    }
+
+    pub fn balance(&self, client_id: ClientId) -> Option<ClientBalance> {
+        self.balances
+            .get(&client_id)
+            .map(|balance| ClientBalance::new(client_id, balance))
+    }
+
+    // The client's available balance as whole units and a fractional remainder of 0 to 9999, for storage
+    // as two integer columns. Split from the exact base units, so correct for any balance. See
+    // Money::to_parts for how negative amounts are split.
+    pub fn available_parts(&self, client_id: ClientId) -> Option<(i64, u16)> {
+        self.balances
+            .get(&client_id)
+            .map(|balance| balance.available().to_parts())
+    }
+
+    // Borrows the engine for reading only, see ReadOnlyView.
+    pub fn as_readonly(&self) -> ReadOnlyView<'_> {
+        ReadOnlyView::new(self)
+    }
+}
+
+impl Default for TransactionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A client's balance at the start of processing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpeningBalance {
+    pub client_id: ClientId,
+    pub available: Money,
+    pub held: Money,
+    pub locked: bool,
+}
+
+// A client state flagged by detect_anomalies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Anomaly {
+    // More is held than the client's deposits that have not been charged back.
+    HeldExceedsDeposits {
+        client_id: ClientId,
+        held: Money,
+        deposits: Money,
+    },
+    // The account is locked but still has funds available, e.g. after a chargeback of one of several deposits.
+    LockedWithAvailable {
+        client_id: ClientId,
+        available: Money,
+    },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::HeldExceedsDeposits {
+                client_id,
+                held,
+                deposits,
+            } => write!(
+                f,
+                "client {} holds {} but has only {} of deposits",
+                client_id, held, deposits
+            ),
+            Anomaly::LockedWithAvailable {
+                client_id,
+                available,
+            } => write!(
+                f,
+                "client {} is locked with {} available",
+                client_id, available
+            ),
+        }
+    }
+}
+
+// Gross deposits and withdrawals for a client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientFlows {
+    pub gross_deposits: Money,
+    pub gross_withdrawals: Money,
+    // Amounts requested but not withdrawn as they exceeded the balance, under with_partial_withdrawals.
+    pub withdrawal_shortfall: Money,
+}
+
+// Serializes with the output column names and amounts as trimmed decimal strings, so the same rows can
+// be written as CSV or JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientBalance {
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    // Only set for balances from a CurrencyLedger, otherwise the column is left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    #[serde(serialize_with = "serialize_amount")]
+    pub available: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    pub held: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl ClientBalance {
+    fn new(client_id: ClientId, balance: &Balance) -> Self {
+        Self {
+            client_id,
+            currency: None,
+            available: Self::from_base_units(client_id, balance.available()),
+            held: Self::from_base_units(client_id, balance.held()),
+            total: Self::from_base_units(client_id, balance.total()),
+            locked: balance.locked(),
+        }
+    }
+
+    // Amounts beyond 2^53 base units, roughly 900 billion, are not exactly representable as f64, so warn
+    // rather than round them silently. Money holds the exact amount.
+    fn from_base_units(client_id: ClientId, amount_base_units: Money) -> f64 {
+        if amount_base_units.base_units().unsigned_abs() > MAX_EXACT_F64_BASE_UNITS {
+            warn!(
+                client = client_id,
+                amount = %amount_base_units,
+                "Balance is too large to be represented exactly and will be rounded"
+            );
+        }
+
+        amount_base_units.to_decimal()
+    }
+}
+
+// Formats an amount to four decimal places with trailing zeros trimmed, e.g. 100.5 rather than 100.5000.
+// Rounding to four places first hides floating point approximations like 100.5555000000001234.
+pub fn format_amount(amount: f64) -> String {
+    let formatted = format!("{:.4}", amount);
+
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+fn serialize_amount<S>(amount: &f64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format_amount(*amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_deposit() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(!manager.balances[&1].locked());
+        assert_eq!(
+            *manager.transactions[&1].transaction_type(),
+            TransactionType::Deposit
+        );
+        assert_eq!(manager.transactions[&1].id(), 1);
+        assert_eq!(manager.transactions[&1].client_id(), 1);
+        assert_eq!(manager.transactions[&1].amount(), 100);
+
+        // Try another deposit.
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 150);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 150);
+        assert!(!manager.balances[&1].locked());
+    }
+
+    #[test]
+    fn test_deposit_multi_user() {
+        let mut manager = TransactionManager::new();
+
+        let deposit1 = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        let deposit2 = Transaction::Deposit {
+            id: 2,
+            client_id: 2,
+            amount_base_units: Money::from_base_units(200),
+        };
+
+        manager.accept(deposit1).unwrap();
+
+        manager.accept(deposit2).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(!manager.balances[&1].locked());
+        assert_eq!(
+            *manager.transactions[&1].transaction_type(),
+            TransactionType::Deposit
+        );
+        assert_eq!(manager.transactions[&1].id(), 1);
+        assert_eq!(manager.transactions[&1].client_id(), 1);
+        assert_eq!(manager.transactions[&1].amount(), 100);
+
+        assert_eq!(manager.balances[&2].available(), 200);
+        assert_eq!(manager.balances[&2].held(), 0);
+        assert_eq!(manager.balances[&2].total(), 200);
+        assert!(!manager.balances[&2].locked());
+        assert_eq!(
+            *manager.transactions[&2].transaction_type(),
+            TransactionType::Deposit
+        );
+        assert_eq!(manager.transactions[&2].id(), 2);
+        assert_eq!(manager.transactions[&2].client_id(), 2);
+        assert_eq!(manager.transactions[&2].amount(), 200);
+    }
+
+    #[test]
+    fn test_withdrawal() {
+        let mut manager = TransactionManager::new();
+
+        // Add funds to the account to give a non-zero balance.
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        // Withdraw funds from the account.
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(withdrawal).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 50);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 50);
+        assert!(!manager.balances[&1].locked());
+        assert_eq!(
+            *manager.transactions[&2].transaction_type(),
+            TransactionType::Withdrawal
+        );
+        assert_eq!(manager.transactions[&2].id(), 2);
+        assert_eq!(manager.transactions[&2].client_id(), 1);
+        assert_eq!(manager.transactions[&2].amount(), 50);
+    }
+
+    #[test]
+    fn test_withdrawal_overdraw() {
+        let mut manager = TransactionManager::new();
+
+        // Add funds to the account to give a non-zero balance.
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        // Withdraw funds from the account.
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(101),
+        };
+
+        let res = manager.accept(withdrawal);
+
+        assert!(matches!(res, Err(TransactionError::InsufficientFunds)));
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(!manager.balances[&1].locked());
+    }
+
+    #[test]
+    fn test_simulate_withdrawal() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(
+            manager.simulate_withdrawal(1, Money::from_base_units(60)),
+            Ok(Money::from_base_units(40))
+        );
+        assert_eq!(
+            manager.simulate_withdrawal(1, Money::from_base_units(100)),
+            Ok(Money::ZERO)
+        );
+
+        // Nothing is applied.
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.flows(1).unwrap().gross_withdrawals, 0);
+    }
+
+    #[test]
+    fn test_simulate_withdrawal_insufficient_funds() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(
+            manager.simulate_withdrawal(1, Money::from_base_units(101)),
+            Err(TransactionError::InsufficientFunds)
+        );
+
+        // Unknown clients have nothing available, and are not created by the preview.
+        assert_eq!(
+            manager.simulate_withdrawal(2, Money::from_base_units(1)),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert!(!manager.balances.contains_key(&2));
+    }
+
+    #[test]
+    fn test_simulate_withdrawal_locked() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+        manager
+            .accept(Transaction::Freeze { client_id: 1 })
+            .unwrap();
+
+        // The preview is rejected as the withdrawal itself would be, despite the funds being available.
+        assert_eq!(
+            manager.simulate_withdrawal(1, Money::from_base_units(60)),
+            Err(TransactionError::AccountLocked)
+        );
+        assert_eq!(
+            manager.accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(60),
+            }),
+            Err(TransactionError::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_deposit() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        // Duplicate
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        let res = manager.accept(deposit);
+
+        assert!(matches!(res, Err(TransactionError::DuplicateTransaction)));
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(!manager.balances[&1].locked());
+    }
+
+    #[test]
+    fn test_dispute_non_existent_tx() {
+        let mut manager = TransactionManager::new();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        let res = manager.accept(dispute);
+
+        assert!(matches!(
+            res,
+            Err(TransactionError::DisputedTransactionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_dispute_client_mismatch() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            // Client ID does not match.
+            client_id: 2,
+        };
+
+        let res = manager.accept(dispute);
+
+        assert_eq!(
+            res,
+            Err(TransactionError::DisputeClientMismatch {
+                expected: 1,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Dispute does not match client: expected client 1, got 2"
+        );
+    }
+
+    #[test]
+    fn test_dispute_withdrawal_fails() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        manager.accept(withdrawal).unwrap();
+
+        let dispute = Transaction::Dispute {
+            // Dispute the withdrawal.
+            id: 2,
+            client_id: 1,
+        };
+
+        let res = manager.accept(dispute);
+
+        assert!(matches!(
+            res,
+            Err(TransactionError::DisputeWithdrawalNotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_dispute_transaction() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Disputed
+        );
+
+        assert_eq!(manager.balances[&1].available(), 50);
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert_eq!(manager.balances[&1].total(), 150);
+    }
+
+    #[test]
+    fn test_resolve_non_existent_tx() {
+        let mut manager = TransactionManager::new();
+
+        let resolve = Transaction::Resolve {
+            id: 1,
+            client_id: 1,
+        };
+
+        let res = manager.accept(resolve);
+
+        assert!(matches!(
+            res,
+            Err(TransactionError::DisputedTransactionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_resolve_client_mismatch() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        let resolve = Transaction::Resolve {
+            id: 1,
+            // Client ID does not match.
+            client_id: 2,
+        };
+
+        let res = manager.accept(resolve);
+
+        assert_eq!(
+            res,
+            Err(TransactionError::ResolveClientMismatch {
+                expected: 1,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_resolve_transaction() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Disputed
+        );
+
+        assert_eq!(manager.balances[&1].available(), 50);
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert_eq!(manager.balances[&1].total(), 150);
+
+        let resolve = Transaction::Resolve {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(resolve).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Resolved
+        );
+
+        assert_eq!(manager.balances[&1].available(), 150);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 150);
+    }
+
+    #[test]
+    fn test_chargeback_non_existent_tx() {
+        let mut manager = TransactionManager::new();
+
+        let chargeback = Transaction::Chargeback {
+            id: 1,
+            client_id: 1,
+        };
+
+        let res = manager.accept(chargeback);
+
+        assert!(matches!(
+            res,
+            Err(TransactionError::DisputedTransactionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_chargeback_client_mismatch() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        let chargeback = Transaction::Chargeback {
+            id: 1,
+            // Client ID does not match.
+            client_id: 2,
+        };
+
+        let res = manager.accept(chargeback);
+
+        assert_eq!(
+            res,
+            Err(TransactionError::ChargebackClientMismatch {
+                expected: 1,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_chargeback_transaction() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Disputed
+        );
+
+        assert_eq!(manager.balances[&1].available(), 50);
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert_eq!(manager.balances[&1].total(), 150);
+
+        let chargeback = Transaction::Chargeback {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(chargeback).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Chargeback
+        );
+
+        assert_eq!(manager.balances[&1].available(), 50);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 50);
+    }
+
+    #[test]
+    fn test_chargeback_transaction_negative_balance() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(withdrawal).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Disputed
+        );
+
+        assert_eq!(manager.balances[&1].available(), -50);
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert_eq!(manager.balances[&1].total(), 50);
+
+        let chargeback = Transaction::Chargeback {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(chargeback).unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Chargeback
+        );
+
+        assert_eq!(manager.balances[&1].available(), -50);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), -50);
+    }
+
+    #[test]
+    fn test_largest_held() {
+        let mut manager = TransactionManager::new();
+
+        assert_eq!(manager.largest_held(), None);
+
+        for (id, client_id, amount_base_units) in
+            [(1, 1, 100), (2, 2, 300), (3, 1, 200), (4, 2, 500)]
+        {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        // No disputes are open yet.
+        assert_eq!(manager.largest_held(), None);
+
+        for (id, client_id) in [(1, 1), (2, 2), (3, 1)] {
+            manager
+                .accept(Transaction::Dispute { id, client_id })
+                .unwrap();
+        }
+
+        assert_eq!(
+            manager.largest_held(),
+            Some((2, 2, Money::from_base_units(300)))
+        );
+
+        // Once resolved the transaction is no longer held.
+        manager
+            .accept(Transaction::Resolve {
+                id: 2,
+                client_id: 2,
+            })
+            .unwrap();
+
+        assert_eq!(
+            manager.largest_held(),
+            Some((3, 1, Money::from_base_units(200)))
+        );
+    }
+
+    #[test]
+    fn test_disputed_transactions() {
+        let mut manager = TransactionManager::new();
+
+        assert!(manager.disputed_transactions().is_empty());
+
+        for (id, client_id, amount_base_units) in [(1, 1, 100), (2, 2, 300), (3, 1, 200)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        for (id, client_id) in [(3, 1), (1, 1), (2, 2)] {
+            manager
+                .accept(Transaction::Dispute { id, client_id })
+                .unwrap();
+        }
+
+        assert_eq!(
+            manager.disputed_transactions(),
+            vec![
+                (1, 1, Money::from_base_units(100)),
+                (2, 2, Money::from_base_units(300)),
+                (3, 1, Money::from_base_units(200))
+            ]
+        );
+
+        // Resolved and charged back transactions are no longer disputed.
+        manager
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 2,
+                client_id: 2,
+            })
+            .unwrap();
+
+        assert_eq!(
+            manager.disputed_transactions(),
+            vec![(3, 1, Money::from_base_units(200))]
+        );
+    }
+
+    #[test]
+    fn test_held_breakdown() {
+        let mut manager = TransactionManager::new();
+
+        for (id, client_id, amount_base_units) in [(1, 1, 100), (2, 2, 300), (3, 1, 200)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        assert!(manager.held_breakdown(1).is_empty());
+
+        for (id, client_id) in [(3, 1), (1, 1), (2, 2)] {
+            manager
+                .accept(Transaction::Dispute { id, client_id })
+                .unwrap();
+        }
+
+        let breakdown = manager.held_breakdown(1);
+
+        assert_eq!(
+            breakdown,
+            vec![
+                (1, Money::from_base_units(100)),
+                (3, Money::from_base_units(200))
+            ]
+        );
+        assert_eq!(
+            breakdown.iter().map(|&(_, amount)| amount).sum::<Money>(),
+            manager.balances[&1].held()
+        );
+        assert!(manager.held_breakdown(3).is_empty());
+    }
+
+    #[test]
+    fn test_iter_balances_sorted() {
+        let mut manager = TransactionManager::new();
+
+        for (id, client_id, amount_base_units) in [(1, 3, 300), (2, 1, 100), (3, 2, 200)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        let balances: Vec<_> = manager.iter_balances_sorted().collect();
+
+        assert_eq!(balances.len(), 3);
+
+        for (&(client_id, balance), expected_client_id) in balances.iter().zip([1, 2, 3]) {
+            assert_eq!(client_id, expected_client_id);
+            assert!(std::ptr::eq(balance, &manager.balances[&client_id]));
+            assert_eq!(balance.available(), client_id as i64 * 100);
+        }
+    }
+
+    #[test]
+    fn test_clients() {
+        let mut manager = TransactionManager::new();
+
+        assert_eq!(manager.clients().count(), 0);
+
+        for (id, client_id) in [(1, 3), (2, 1), (3, 3)] {
+            let deposit = Transaction::Deposit {
+                id,
+                client_id,
+                amount_base_units: Money::from_base_units(100),
+            };
+
+            manager.accept(deposit).unwrap();
+        }
+
+        // A dispute for an unknown transaction does not create a client.
+        let dispute = Transaction::Dispute {
+            id: 4,
+            client_id: 2,
+        };
+
+        assert!(manager.accept(dispute).is_err());
+
+        let mut clients: Vec<_> = manager.clients().collect();
+        clients.sort_unstable();
+
+        assert_eq!(clients, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_clients_with_negative_balance() {
+        let mut manager = TransactionManager::new();
+
+        for (id, client_id, amount_base_units) in [(1, 1, 100), (2, 2, 100)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 3,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        manager.accept(withdrawal).unwrap();
+
+        assert!(manager.clients_with_negative_balance().is_empty());
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        // Available is already negative while the dispute is open.
+        assert_eq!(manager.clients_with_negative_balance(), vec![1]);
+
+        let chargeback = Transaction::Chargeback {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(chargeback).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), -50);
+        assert_eq!(manager.balances[&1].total(), -50);
+        assert_eq!(manager.clients_with_negative_balance(), vec![1]);
+    }
+
+    #[test]
+    fn test_consistency_report() {
+        let mut manager = TransactionManager::new();
+
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(50),
+            },
+            Transaction::Deposit {
+                id: 3,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(200),
+            },
+            Transaction::Withdrawal {
+                id: 4,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(120),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+            // Drives client 1 negative.
+            Transaction::Chargeback {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Dispute {
+                id: 3,
+                client_id: 2,
+            },
+            Transaction::Resolve {
+                id: 3,
+                client_id: 2,
+            },
+            Transaction::Deposit {
+                id: 5,
+                client_id: 3,
+                amount_base_units: Money::from_base_units(75),
+            },
+            Transaction::Dispute {
+                id: 5,
+                client_id: 3,
+            },
+        ];
+
+        for transaction in transactions {
+            manager.accept(transaction).unwrap();
+        }
+
+        let report = manager.consistency_report();
+
+        assert_eq!(report.total_deposited, 425);
+        assert_eq!(report.total_withdrawn, 120);
+        assert_eq!(report.total_charged_back, 100);
+        assert_eq!(report.expected_total(), 205);
+        assert_eq!(report.balances_total, 205);
+        assert_eq!(report.expected_held, 75);
+        assert_eq!(report.balances_held, 75);
+        assert!(report.is_consistent());
+
+        // Inject a balance change that bypasses the engine.
+        manager
+            .balances
+            .get_mut(&2)
+            .unwrap()
+            .deposit(Money::from_base_units(1));
+
+        let report = manager.consistency_report();
+
+        assert_eq!(report.balances_total, 206);
+        assert!(!report.is_consistent());
+    }
+
+    #[test]
+    fn test_replay_identical_duplicate() {
+        let mut manager = TransactionManager::new().with_replay(true);
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(30),
+        };
+
+        manager.accept(deposit.clone()).unwrap();
+        manager.accept(withdrawal.clone()).unwrap();
+
+        // Redelivered events are ignored.
+        manager.accept(deposit).unwrap();
+        manager.accept(withdrawal).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 70);
+        assert_eq!(manager.balances[&1].total(), 70);
+        assert_eq!(manager.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_replay_conflicting_duplicate() {
+        let mut manager = TransactionManager::new().with_replay(true);
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        // Same id with a different amount.
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(200),
+        };
+
+        let res = manager.accept(deposit);
+
+        assert!(matches!(res, Err(TransactionError::ConflictingDuplicate)));
+
+        // Same id, client and amount but a different transaction type.
+        let withdrawal = Transaction::Withdrawal {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        let res = manager.accept(withdrawal);
+
+        assert!(matches!(res, Err(TransactionError::ConflictingDuplicate)));
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].total(), 100);
+    }
+
+    #[test]
+    fn test_flows() {
+        let mut manager = TransactionManager::new();
+
+        assert_eq!(manager.flows(1), None);
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(30),
+        };
+
+        manager.accept(withdrawal).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        let chargeback = Transaction::Chargeback {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(chargeback).unwrap();
+
+        // Disputes and chargebacks do not alter the original flows.
+        assert_eq!(
+            manager.flows(1),
+            Some(ClientFlows {
+                gross_deposits: Money::from_base_units(100),
+                gross_withdrawals: Money::from_base_units(30),
+                withdrawal_shortfall: Money::ZERO,
+            })
+        );
+    }
+
+    #[test]
+    fn test_accept_with_result() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(1_000_000),
+        };
+
+        let balance = manager.accept_with_result(deposit).unwrap();
+
+        assert_eq!(
+            balance,
+            ClientBalance {
+                client_id: 1,
+                currency: None,
+                available: 100.0,
+                held: 0.0,
+                total: 100.0,
+                locked: false,
+            }
+        );
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        let balance = manager.accept_with_result(dispute).unwrap();
+
+        assert_eq!(balance.available, 0.0);
+        assert_eq!(balance.held, 100.0);
+        assert_eq!(balance.total, 100.0);
+        assert_eq!(Some(balance), manager.balance(1));
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(1),
+        };
+
+        let res = manager.accept_with_result(withdrawal);
+
+        assert!(matches!(res, Err(TransactionError::InsufficientFunds)));
+
+        // Accepted without creating a balance for the client.
+        let balance = manager
+            .accept_with_result(Transaction::Unfreeze { client_id: 2 })
+            .unwrap();
+
+        assert_eq!(balance.client_id, 2);
+        assert_eq!(balance.total, 0.0);
+        assert!(!balance.locked);
+        assert_eq!(manager.balance(2), None);
+    }
+
+    #[test]
+    fn test_dispute_held_cap_exceeded() {
+        let mut manager = TransactionManager::new().with_held_cap(Money::from_base_units(150));
+
+        for (id, amount_base_units) in [(1, 100), (2, 100)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 2,
+            client_id: 1,
+        };
+
+        let res = manager.accept(dispute);
+
+        assert!(matches!(res, Err(TransactionError::HeldCapExceeded)));
+
+        // The hold was not applied.
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert_eq!(*manager.transactions[&2].status(), TransactionStatus::Valid);
+
+        // Re-disputing an already disputed transaction reports the state error rather than the cap.
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        let res = manager.accept(dispute);
+
+        assert!(matches!(
+            res,
+            Err(TransactionError::InvalidStateTransition(..))
+        ));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut manager = TransactionManager::new().with_metrics(true);
+
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(500),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Chargeback {
+                id: 1,
+                client_id: 1,
+            },
+        ];
+
+        for transaction in transactions {
+            let _ = manager.accept(transaction);
+        }
+
+        let metrics = manager.metrics();
+
+        assert_eq!(metrics.deposits.count, 2);
+        assert_eq!(metrics.deposits.rejected, 0);
+        assert_eq!(metrics.withdrawals.count, 1);
+        assert_eq!(metrics.withdrawals.rejected, 1);
+        assert_eq!(metrics.disputes.count, 1);
+        assert_eq!(metrics.resolves.count, 0);
+        assert_eq!(metrics.resolves.average(), Duration::ZERO);
+        assert_eq!(metrics.chargebacks.count, 1);
+    }
+
+    #[test]
+    fn test_metrics_disabled() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(manager.metrics(), EngineMetrics::default());
+    }
+
+    fn disputed_deposit_manager() -> TransactionManager {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(dispute).unwrap();
+
+        manager
+    }
+
+    #[test]
+    fn test_split_resolve() {
+        let mut manager = disputed_deposit_manager();
+
+        manager
+            .split_resolve(1, 1, Money::from_base_units(70), Money::from_base_units(30))
+            .unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Chargeback
+        );
+        assert_eq!(manager.balances[&1].available(), 70);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 70);
+        assert!(manager.balances[&1].locked());
+        assert!(manager.consistency_report().is_consistent());
+    }
+
+    #[test]
+    fn test_split_resolve_without_chargeback() {
+        let mut manager = disputed_deposit_manager();
+
+        manager
+            .split_resolve(1, 1, Money::from_base_units(100), Money::ZERO)
+            .unwrap();
+
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Resolved
+        );
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert!(!manager.balances[&1].locked());
+    }
+
+    #[test]
+    fn test_split_resolve_amount_mismatch() {
+        let mut manager = disputed_deposit_manager();
+
+        let res =
+            manager.split_resolve(1, 1, Money::from_base_units(70), Money::from_base_units(20));
+
+        assert!(matches!(res, Err(TransactionError::SplitAmountMismatch)));
+
+        let res = manager.split_resolve(
+            1,
+            1,
+            Money::from_base_units(150),
+            Money::from_base_units(-50),
+        );
+
+        assert!(matches!(res, Err(TransactionError::AmountIsNegative)));
+
+        // Nothing was applied.
+        assert_eq!(
+            *manager.transactions[&1].status(),
+            TransactionStatus::Disputed
+        );
+        assert_eq!(manager.balances[&1].available(), 0);
+        assert_eq!(manager.balances[&1].held(), 100);
+    }
+
+    #[test]
+    fn test_from_balances() {
+        let mut manager = TransactionManager::from_balances([
+            OpeningBalance {
+                client_id: 1,
+                available: Money::from_base_units(500),
+                held: Money::ZERO,
+                locked: false,
+            },
+            OpeningBalance {
+                client_id: 2,
+                available: Money::from_base_units(50),
+                held: Money::from_base_units(25),
+                locked: true,
+            },
+        ]);
+
+        assert!(manager.transactions.is_empty());
+        assert_eq!(manager.balances[&2].total(), 75);
+        assert!(manager.balances[&2].locked());
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 600);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 600);
+
+        let report = manager.consistency_report();
+        assert_eq!(report.total_opening, 575);
+        assert!(report.is_consistent());
+    }
+
+    #[test]
+    fn test_observer() {
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        let mut manager = TransactionManager::new().with_observer({
+            let observed = Arc::clone(&observed);
+
+            move |transaction, result| {
+                observed
+                    .lock()
+                    .unwrap()
+                    .push((transaction.clone(), result.clone()))
+            }
+        });
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(200),
+        };
+
+        let dispute = Transaction::Dispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(deposit.clone()).unwrap();
+        manager.accept(withdrawal.clone()).unwrap_err();
+        manager.accept(dispute.clone()).unwrap();
+
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![
+                (deposit, Ok(())),
+                (withdrawal, Err(TransactionError::InsufficientFunds)),
+                (dispute, Ok(())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_amount() {
+        let mut manager = TransactionManager::new().with_max_amount(Money::from_base_units(1_000));
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(1_000),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(1_001),
+        };
+
+        assert_eq!(
+            manager.accept(deposit),
+            Err(TransactionError::AmountExceedsLimit)
+        );
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 3,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(1_001),
+        };
+
+        assert_eq!(
+            manager.accept(withdrawal),
+            Err(TransactionError::AmountExceedsLimit)
+        );
+
+        assert_eq!(manager.balances[&1].available(), 1_000);
+        assert!(!manager.transactions.contains_key(&2));
+        assert!(!manager.transactions.contains_key(&3));
+    }
+
+    #[test]
+    fn test_no_max_amount() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(10_000_000_000),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 10_000_000_000);
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(100.5), "100.5");
+        assert_eq!(format_amount(100.0), "100");
+        assert_eq!(format_amount(0.00001), "0");
+        assert_eq!(format_amount(0.1 + 0.2), "0.3");
+    }
+
+    #[test]
+    fn test_client_balance_serialize() {
+        let balance = ClientBalance {
+            client_id: 2,
+            currency: None,
+            available: -50.5,
+            held: 100.0,
+            total: 49.5,
+            locked: true,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&balance).unwrap(),
+            r#"{"client":2,"available":"-50.5","held":"100","total":"49.5","locked":true}"#
+        );
+    }
+
+    #[test]
+    fn test_freeze_unfreeze() {
+        let mut manager = TransactionManager::new();
+
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit).unwrap();
+
+        // Freezing is idempotent.
+        for _ in 0..2 {
+            manager
+                .accept(Transaction::Freeze { client_id: 1 })
+                .unwrap();
+        }
+
+        assert!(manager.balances[&1].locked());
+
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        assert_eq!(
+            manager.accept(deposit),
+            Err(TransactionError::AccountLocked)
+        );
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 3,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(50),
+        };
+
+        assert_eq!(
+            manager.accept(withdrawal.clone()),
+            Err(TransactionError::AccountLocked)
+        );
+
+        // Disputes on a frozen account are still processed.
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        for _ in 0..2 {
+            manager
+                .accept(Transaction::Unfreeze { client_id: 1 })
+                .unwrap();
+        }
+
+        assert!(!manager.balances[&1].locked());
+
+        manager.accept(withdrawal).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 50);
+        assert!(!manager.transactions.contains_key(&2));
+    }
+
+    #[test]
+    fn test_freeze_unknown_client() {
+        let mut manager = TransactionManager::new();
+
+        manager
+            .accept(Transaction::Unfreeze { client_id: 1 })
+            .unwrap();
+
+        assert!(manager.balance(1).is_none());
+
+        manager
+            .accept(Transaction::Freeze { client_id: 1 })
+            .unwrap();
+
+        assert!(manager.balance(1).unwrap().locked);
+    }
+
+    #[test]
+    fn test_deposit_after_chargeback_rejected() {
+        let mut manager = disputed_deposit_manager();
+
+        manager
+            .accept(Transaction::Chargeback {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        let deposit = Transaction::Deposit {
+            id: 2,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        assert_eq!(
+            manager.accept(deposit),
+            Err(TransactionError::AccountLocked)
+        );
+        assert_eq!(manager.balances[&1].total(), 0);
+    }
+
+    // Deposits 100, withdraws 60 then disputes and charges back the deposit, so the chargeback is not
+    // covered by the client's remaining funds.
+    fn uncovered_chargeback(manager: &mut TransactionManager) {
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(60),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_chargeback_policy_allow_negative() {
+        let mut manager = TransactionManager::new();
+
+        uncovered_chargeback(&mut manager);
+
+        let balance = &manager.balances[&1];
+
+        assert_eq!(balance.available(), -60);
+        assert_eq!(balance.held(), 0);
+        assert_eq!(balance.total(), -60);
+        assert_eq!(balance.shortfall(), 0);
+        assert!(balance.locked());
+        assert!(manager.consistency_report().is_consistent());
+    }
+
+    #[test]
+    fn test_chargeback_policy_clamp_to_zero() {
+        let mut manager =
+            TransactionManager::new().with_chargeback_policy(ChargebackPolicy::ClampToZero);
+
+        uncovered_chargeback(&mut manager);
+
+        let balance = &manager.balances[&1];
+
+        assert_eq!(balance.available(), 0);
+        assert_eq!(balance.held(), 0);
+        assert_eq!(balance.total(), 0);
+        assert_eq!(balance.shortfall(), 60);
+        assert!(balance.locked());
+        assert!(manager.consistency_report().is_consistent());
+        assert!(manager.clients_with_negative_balance().is_empty());
+    }
+
+    #[test]
+    fn test_chargeback_policy_clamp_to_zero_covered() {
+        let mut manager =
+            TransactionManager::new().with_chargeback_policy(ChargebackPolicy::ClampToZero);
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        // A chargeback covered by the client's own funds leaves no shortfall.
+        assert_eq!(manager.balances[&1].available(), 0);
+        assert_eq!(manager.balances[&1].shortfall(), 0);
+    }
+
+    #[test]
+    fn test_undo_last_deposit() {
+        let mut manager = TransactionManager::new();
+
+        assert_eq!(manager.undo_last(), Err(TransactionError::NothingToUndo));
+
+        for (id, amount_base_units) in [(1, 100), (2, 50)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        manager.undo_last().unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(!manager.transactions.contains_key(&2));
+        assert_eq!(manager.flows(1).unwrap().gross_deposits, 100);
+        assert!(manager.consistency_report().is_consistent());
+
+        // Only the last transaction can be undone.
+        assert_eq!(manager.undo_last(), Err(TransactionError::NothingToUndo));
+
+        // The undone id may be reused.
+        manager
+            .accept(Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(10),
+            })
+            .unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 110);
+    }
+
+    #[test]
+    fn test_undo_last_withdrawal() {
+        let mut manager = TransactionManager::new();
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            })
+            .unwrap();
+
+        manager.undo_last().unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.flows(1).unwrap().gross_withdrawals, 0);
+        assert!(manager.consistency_report().is_consistent());
+    }
+
+    #[test]
+    fn test_undo_last_disputed() {
+        let mut manager = disputed_deposit_manager();
+
+        assert_eq!(
+            manager.undo_last(),
+            Err(TransactionError::UndoDisputedTransaction)
+        );
+
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert!(manager.transactions.contains_key(&1));
+    }
+
+    #[test]
+    fn test_transaction_and_processed_counts() {
+        let mut manager = disputed_deposit_manager();
+
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(1),
+            })
+            .unwrap_err();
+        manager
+            .accept(Transaction::Deposit {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(10),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        // The deposits are stored, while the rejected withdrawal, dispute and resolve are only counted.
+        assert_eq!(manager.transaction_count(), 2);
+        assert_eq!(manager.processed_count(), 5);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(300),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 2,
+            },
+        ];
+
+        let mut primary = TransactionManager::new();
+        let mut replica = TransactionManager::new();
+
+        assert_eq!(primary.state_hash(), replica.state_hash());
+
+        for transaction in transactions {
+            primary.accept(transaction.clone()).unwrap();
+            replica.accept(transaction).unwrap();
+        }
+
+        assert_eq!(primary.state_hash(), replica.state_hash());
+
+        replica
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 2,
+            })
+            .unwrap();
+
+        // Totals are unchanged by the resolve, but available and held are not.
+        assert_ne!(primary.state_hash(), replica.state_hash());
+    }
+
+    #[test]
+    fn test_cancel_dispute() {
+        let mut manager = disputed_deposit_manager();
+
+        let cancel = Transaction::CancelDispute {
+            id: 1,
+            client_id: 2,
+        };
+
+        assert_eq!(
+            manager.accept(cancel),
+            Err(TransactionError::CancelDisputeClientMismatch {
+                expected: 1,
+                actual: 2
+            })
+        );
+
+        let cancel = Transaction::CancelDispute {
+            id: 1,
+            client_id: 1,
+        };
+
+        manager.accept(cancel).unwrap();
+
+        assert_eq!(*manager.transactions[&1].status(), TransactionStatus::Valid);
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert!(!manager.balances[&1].locked());
+        assert!(manager.disputed_transactions().is_empty());
+        assert!(manager.consistency_report().is_consistent());
+
+        let cancel = Transaction::CancelDispute {
+            id: 2,
+            client_id: 1,
+        };
+
+        assert_eq!(
+            manager.accept(cancel),
+            Err(TransactionError::DisputedTransactionNotFound)
+        );
+    }
+
+    #[test]
+    fn test_locked_accounts() {
+        let mut manager = TransactionManager::from_balances([OpeningBalance {
+            client_id: 5,
+            available: Money::ZERO,
+            held: Money::ZERO,
+            locked: true,
+        }]);
+
+        for (id, client_id) in [(1, 1), (2, 1), (3, 2)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(100),
+                })
+                .unwrap();
+            manager
+                .accept(Transaction::Dispute { id, client_id })
+                .unwrap();
+        }
+
+        assert_eq!(manager.locked_clients(), vec![5]);
+
+        // Charging back twice locks the account once.
+        for id in [1, 2] {
+            manager
+                .accept(Transaction::Chargeback { id, client_id: 1 })
+                .unwrap();
+        }
+
+        manager
+            .accept(Transaction::Freeze { client_id: 3 })
+            .unwrap();
+        manager
+            .accept(Transaction::Freeze { client_id: 3 })
+            .unwrap();
+
+        assert_eq!(manager.locked_account_count(), 3);
+        assert_eq!(manager.locked_clients(), vec![1, 3, 5]);
+
+        manager
+            .accept(Transaction::Unfreeze { client_id: 5 })
+            .unwrap();
+
+        assert_eq!(manager.locked_account_count(), 2);
+        assert_eq!(manager.locked_clients(), vec![1, 3]);
+        assert_eq!(
+            manager.locked_account_count(),
+            manager
+                .balances()
+                .iter()
+                .filter(|balance| balance.locked)
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_merge_disjoint_clients() {
+        let mut shards = [TransactionManager::new(), TransactionManager::new()];
+
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(300),
+            },
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(40),
+            },
+            Transaction::Dispute {
+                id: 2,
+                client_id: 2,
+            },
+        ];
+
+        let mut single = TransactionManager::new();
+
+        for transaction in transactions {
+            single.accept(transaction.clone()).unwrap();
+            shards[usize::from(transaction.client_id()) % 2]
+                .accept(transaction)
+                .unwrap();
+        }
+
+        let [mut merged, other] = shards;
+
+        merged.merge(other).unwrap();
+
+        assert_eq!(merged.state_hash(), single.state_hash());
+        assert_eq!(merged.transaction_count(), 3);
+        assert_eq!(merged.processed_count(), 4);
+        assert_eq!(
+            merged.disputed_transactions(),
+            vec![(2, 2, Money::from_base_units(300))]
+        );
+        assert_eq!(merged.flows(1), single.flows(1));
+        assert_eq!(merged.consistency_report(), single.consistency_report());
+
+        // Disputes continue to work on transactions from the merged engine.
+        merged
+            .accept(Transaction::Resolve {
+                id: 2,
+                client_id: 2,
+            })
+            .unwrap();
+
+        assert_eq!(merged.balances[&2].available(), 300);
+    }
+
+    #[test]
+    fn test_merge_shared_client() {
+        let mut manager = disputed_deposit_manager();
+        let mut other = TransactionManager::new();
+
+        other
+            .accept(Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(50),
+            })
+            .unwrap();
+        other.accept(Transaction::Freeze { client_id: 1 }).unwrap();
+
+        manager.merge(other).unwrap();
+
+        let balance = &manager.balances[&1];
+
+        assert_eq!(balance.available(), 50);
+        assert_eq!(balance.held(), 100);
+        assert_eq!(balance.total(), 150);
+        assert!(balance.locked());
+        assert_eq!(manager.locked_clients(), vec![1]);
+        assert!(manager.consistency_report().is_consistent());
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let mut manager = disputed_deposit_manager();
+        let other = disputed_deposit_manager();
+
+        assert_eq!(
+            manager.merge(other),
+            Err(TransactionError::MergeConflict(1))
+        );
+
+        // Nothing is merged.
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert_eq!(manager.processed_count(), 2);
+    }
+
+    #[test]
+    fn test_merge_conflict_evicted() {
+        let evicted_deposit_manager = || {
+            let mut manager = TransactionManager::new().with_evict_finalized(true);
+
+            for transaction in [
+                Transaction::Deposit {
+                    id: 1,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(100),
+                },
+                Transaction::Dispute {
+                    id: 1,
+                    client_id: 1,
+                },
+                Transaction::Resolve {
+                    id: 1,
+                    client_id: 1,
+                },
+            ] {
+                manager.accept(transaction).unwrap();
+            }
+
+            manager
+        };
+
+        // An id stored in one engine and evicted from the other conflicts, whichever is merged into which.
+        assert_eq!(
+            evicted_deposit_manager().merge(disputed_deposit_manager()),
+            Err(TransactionError::MergeConflict(1))
+        );
+        assert_eq!(
+            disputed_deposit_manager().merge(evicted_deposit_manager()),
+            Err(TransactionError::MergeConflict(1))
+        );
+        assert_eq!(
+            evicted_deposit_manager().merge(evicted_deposit_manager()),
+            Err(TransactionError::MergeConflict(1))
+        );
+    }
+
+    #[test]
+    fn test_max_dispute_exposure() {
+        let mut manager = TransactionManager::new();
+
+        assert_eq!(manager.max_dispute_exposure(1), 0);
+
+        for (id, client_id, amount_base_units) in [
+            (1, 1, 1),
+            (2, 1, 10),
+            (3, 1, 100),
+            (4, 1, 1_000),
+            (5, 1, 10_000),
+            (6, 2, 100_000),
+        ] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount_base_units),
+                })
+                .unwrap();
+        }
+
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 7,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(5),
+            })
+            .unwrap();
+
+        for id in [2, 3, 4] {
+            manager
+                .accept(Transaction::Dispute { id, client_id: 1 })
+                .unwrap();
+        }
+
+        manager
+            .accept(Transaction::Resolve {
+                id: 3,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 4,
+                client_id: 1,
+            })
+            .unwrap();
+
+        // Only deposits 1 and 5 are still Valid; the disputed, resolved and charged back deposits, the
+        // withdrawal and the other client's deposit are excluded.
+        assert_eq!(manager.max_dispute_exposure(1), 10_001);
+        assert_eq!(manager.max_dispute_exposure(2), 100_000);
+
+        // With a limit of two, a resolved deposit may be disputed again, so still counts.
+        let mut manager = TransactionManager::new().with_max_disputes_per_transaction(2);
+
+        for transaction in [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            },
+        ] {
+            manager.accept(transaction).unwrap();
+        }
+
+        assert_eq!(manager.max_dispute_exposure(1), 100);
+
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(manager.max_dispute_exposure(1), 0);
+    }
+
+    #[test]
+    fn test_balances_equal() {
+        let mut manager = TransactionManager::new();
+
+        assert!(manager.balances_equal(&[]));
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(15_001),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(20_000),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Freeze { client_id: 2 })
+            .unwrap();
+
+        let client_1 = ClientBalance {
+            client_id: 1,
+            currency: None,
+            available: 1.5001,
+            held: 0.0,
+            total: 1.5001,
+            locked: false,
+        };
+        let client_2 = ClientBalance {
+            client_id: 2,
+            currency: None,
+            available: 2.0,
+            held: 0.0,
+            total: 2.0,
+            locked: true,
+        };
+
+        // Order does not matter.
+        assert!(manager.balances_equal(&[client_2.clone(), client_1.clone()]));
+
+        // Off by a single base unit.
+        let off_by_one = ClientBalance {
+            available: 1.5002,
+            ..client_1.clone()
+        };
+        assert!(!manager.balances_equal(&[off_by_one, client_2.clone()]));
+
+        let unlocked = ClientBalance {
+            locked: false,
+            ..client_2.clone()
+        };
+        assert!(!manager.balances_equal(&[client_1.clone(), unlocked]));
+
+        // Missing, extra and duplicated clients.
+        assert!(!manager.balances_equal(std::slice::from_ref(&client_1)));
+        assert!(!manager.balances_equal(&[client_1.clone(), client_1.clone()]));
+        assert!(!manager.balances_equal(&[
+            client_1,
+            client_2.clone(),
+            ClientBalance {
+                client_id: 3,
+                currency: None,
+                ..client_2
+            }
+        ]));
+    }
+
+    #[test]
+    fn test_reject_client_zero() {
+        let deposit = Transaction::Deposit {
+            id: 1,
+            client_id: 0,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        // Client 0 is an ordinary client by default.
+        let mut manager = TransactionManager::new();
+
+        manager.accept(deposit.clone()).unwrap();
+
+        assert_eq!(manager.balances[&0].available(), 100);
+
+        let mut manager = TransactionManager::new().with_reject_client_zero(true);
+
+        assert_eq!(
+            manager.accept(deposit),
+            Err(TransactionError::ReservedClientId)
+        );
+        assert_eq!(
+            manager.accept(Transaction::Freeze { client_id: 0 }),
+            Err(TransactionError::ReservedClientId)
+        );
+        assert!(manager.balance(0).is_none());
+        assert_eq!(manager.processed_count(), 2);
+    }
+
+    #[test]
+    fn test_available_parts() {
+        let mut manager = TransactionManager::new();
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(1_005_555),
+            })
+            .unwrap();
+
+        assert_eq!(manager.available_parts(1), Some((100, 5555)));
+        assert_eq!(manager.available_parts(2), None);
+
+        // Beyond 2^53 base units, where a float would lose the last digits.
+        manager
+            .accept(Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(90_071_992_547_409_931),
+            })
+            .unwrap();
+
+        assert_eq!(manager.available_parts(2), Some((9_007_199_254_740, 9931)));
+
+        // An overdrawn balance has a negative whole part and a positive remainder.
+        manager
+            .accept(Transaction::Deposit {
+                id: 3,
+                client_id: 3,
+                amount_base_units: Money::from_base_units(5_000),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 4,
+                client_id: 3,
+                amount_base_units: Money::from_base_units(5_000),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 3,
+                client_id: 3,
+            })
+            .unwrap();
+
+        assert_eq!(manager.available_parts(3), Some((-1, 5000)));
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let mut manager = TransactionManager::new();
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+
+        manager
+            .accept(Transaction::Adjustment {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(50),
+            })
+            .unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 150);
+        assert_eq!(manager.balances[&1].total(), 150);
+
+        // A debit bypasses the funds check of a withdrawal.
+        manager
+            .accept(Transaction::Adjustment {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(-200),
+            })
+            .unwrap();
+
+        assert_eq!(manager.balances[&1].available(), -50);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), -50);
+        assert_eq!(
+            *manager.transactions[&3].transaction_type(),
+            TransactionType::Adjustment
+        );
+        assert_eq!(manager.transactions[&3].amount(), -200);
+        assert!(manager.consistency_report().is_consistent());
+
+        assert_eq!(
+            manager.accept(Transaction::Dispute {
+                id: 3,
+                client_id: 1,
+            }),
+            Err(TransactionError::DisputeAdjustmentNotSupported)
+        );
+        assert_eq!(
+            manager.accept(Transaction::Adjustment {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(10),
+            }),
+            Err(TransactionError::DuplicateTransaction)
+        );
+
+        manager.undo_last().unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 150);
+        assert!(manager.consistency_report().is_consistent());
+    }
+
+    #[test]
+    fn test_withdrawal_holds() {
+        let mut manager = TransactionManager::new().with_withdrawal_holds(true);
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            })
+            .unwrap();
+
+        // Pending: the funds leave available but are still part of the total.
+        assert_eq!(manager.balances[&1].available(), 70);
+        assert_eq!(manager.balances[&1].held(), 30);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(manager.consistency_report().is_consistent());
+
+        // Held withdrawals count against the available funds of later ones.
+        assert_eq!(
+            manager.accept(Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(80),
+            }),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            manager.accept(Transaction::SettleWithdrawal {
+                id: 2,
+                client_id: 2,
+            }),
+            Err(TransactionError::SettleWithdrawalClientMismatch {
+                expected: 1,
+                actual: 2,
+            })
+        );
+
+        manager
+            .accept(Transaction::SettleWithdrawal {
+                id: 2,
+                client_id: 1,
+            })
+            .unwrap();
+
+        // Settled: the funds leave held and total.
+        assert_eq!(manager.balances[&1].available(), 70);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 70);
+        assert!(manager.consistency_report().is_consistent());
+
+        assert_eq!(
+            manager.accept(Transaction::SettleWithdrawal {
+                id: 2,
+                client_id: 1,
+            }),
+            Err(TransactionError::PendingWithdrawalNotFound)
+        );
+        assert_eq!(
+            manager.accept(Transaction::SettleWithdrawal {
+                id: 1,
+                client_id: 1,
+            }),
+            Err(TransactionError::PendingWithdrawalNotFound)
+        );
+    }
+
+    #[test]
+    fn test_undo_pending_withdrawal() {
+        let mut manager = TransactionManager::new().with_withdrawal_holds(true);
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            })
+            .unwrap();
+
+        manager.undo_last().unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.balances[&1].total(), 100);
+        assert!(manager.consistency_report().is_consistent());
+        assert_eq!(
+            manager.accept(Transaction::SettleWithdrawal {
+                id: 2,
+                client_id: 1,
+            }),
+            Err(TransactionError::PendingWithdrawalNotFound)
+        );
+    }
+
+    #[test]
+    fn test_transaction_timestamp() {
+        let mut manager = TransactionManager::new();
+
+        let timestamp = "2024-05-01T09:30:00Z".parse::<Timestamp>().unwrap();
+
+        manager
+            .accept_with_timestamp(
+                Transaction::Deposit {
+                    id: 1,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(100),
+                },
+                Some(timestamp),
+            )
+            .unwrap();
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+
+        assert_eq!(manager.transaction_timestamp(1), Some(timestamp));
+        assert_eq!(manager.transaction_timestamp(2), None);
+        assert_eq!(manager.transaction_timestamp(3), None);
+    }
+
+    #[test]
+    fn test_large_balance_precision_warning() {
+        // Collects log output so the warning can be inspected.
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Logs::default();
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+
+        let large = (1_i64 << 53) + 1;
+
+        let mut manager = TransactionManager::new();
+
+        for (id, client_id, amount) in [(1, 1, large), (2, 2, 1_i64 << 53)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount),
+                })
+                .unwrap();
+        }
+
+        let balance = tracing::subscriber::with_default(subscriber, || manager.balance(1).unwrap());
+
+        // The float cannot hold the exact amount, so the conversion is reported rather than silent.
+        assert_ne!(
+            Money::from_decimal(balance.available, RoundingMode::HalfUp),
+            large
+        );
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+
+        assert!(output.contains("too large to be represented exactly"));
+        assert!(output.contains("client=1"));
+        assert!(output.contains("amount=900719925474.0993"));
+
+        logs.0.lock().unwrap().clear();
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || manager.balance(2).unwrap());
+
+        assert!(logs.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_export_transactions_round_trip() {
+        use futures::StreamExt;
+
+        let mut manager = TransactionManager::new().with_history(true);
+
+        let timestamp = "2024-05-01T09:30:00Z".parse::<Timestamp>().unwrap();
+
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(1_000_001),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(50_000),
+            },
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(2_500),
+            },
+            // Rejected, so not exported.
+            Transaction::Withdrawal {
+                id: 4,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(90_000),
+            },
+            Transaction::Adjustment {
+                id: 5,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(-1),
+            },
+            Transaction::Dispute {
+                id: 2,
+                client_id: 2,
+            },
+            Transaction::Chargeback {
+                id: 2,
+                client_id: 2,
+            },
+            Transaction::Freeze { client_id: 1 },
+            Transaction::Unfreeze { client_id: 1 },
+            Transaction::Deposit {
+                id: 6,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(7),
+            },
+        ];
+
+        for transaction in transactions {
+            let _ = manager.accept_with_timestamp(transaction, Some(timestamp));
+        }
+
+        // Undone transactions are dropped from the history.
+        manager.undo_last().unwrap();
+
+        let mut exported = Vec::new();
+
+        manager.export_transactions(&mut exported).await.unwrap();
+
+        let exported = String::from_utf8(exported).unwrap();
+
+        assert_eq!(
+            exported,
+            "type,client,tx,amount,timestamp
+deposit,1,1,100.0001,2024-05-01T09:30:00Z
+deposit,2,2,5.0000,2024-05-01T09:30:00Z
+withdrawal,1,3,0.2500,2024-05-01T09:30:00Z
+adjustment,1,5,-0.0001,2024-05-01T09:30:00Z
+dispute,2,2,,2024-05-01T09:30:00Z
+chargeback,2,2,,2024-05-01T09:30:00Z
+freeze,1,0,,2024-05-01T09:30:00Z
+unfreeze,1,0,,2024-05-01T09:30:00Z
+"
+        );
+
+        let stream = csv::read_transactions(
+            exported.as_bytes(),
+            HashMap::new(),
+            csv::CsvFormat::default(),
+            None,
+        );
+
+        let mut replayed = TransactionManager::new();
+
+        let dtos: Vec<_> = stream.collect().await;
+
+        for dto in dtos {
+            let dto = dto.unwrap();
+
+            replayed
+                .accept_with_timestamp(
+                    dto.to_transaction(RoundingMode::default()).unwrap(),
+                    dto.timestamp,
+                )
+                .unwrap();
+        }
+
+        assert!(replayed.balances_equal(&manager.balances()));
+        assert_eq!(replayed.state_hash(), manager.state_hash());
+        assert_eq!(replayed.transaction_timestamp(1), Some(timestamp));
+
+        assert!(TransactionManager::new()
+            .export_transactions(Vec::new())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_invariants() {
+        let mut manager = TransactionManager::new();
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(manager.verify_invariants(), Ok(()));
+
+        // Deliberately break the invariant, as a bug in the balance arithmetic would.
+        for client_id in [3, 2] {
+            manager.balances.insert(
+                client_id,
+                Balance::opening(Money::from_base_units(10), Money::from_base_units(5), false)
+                    .with_total(Money::from_base_units(16)),
+            );
+        }
+
+        // The lowest client id is reported.
+        assert_eq!(
+            manager.verify_invariants(),
+            Err((
+                2,
+                TransactionError::BalanceInvariantViolated {
+                    available: Money::from_base_units(10),
+                    held: Money::from_base_units(5),
+                    total: Money::from_base_units(16),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let mut manager = TransactionManager::new().with_max_clients(2);
+
+        let deposit = |id, client_id| Transaction::Deposit {
+            id,
+            client_id,
+            amount_base_units: Money::from_base_units(100),
+        };
+
+        manager.accept(deposit(1, 1)).unwrap();
+        manager.accept(deposit(2, 2)).unwrap();
+
+        assert_eq!(
+            manager.accept(deposit(3, 3)),
+            Err(TransactionError::TooManyClients)
+        );
+        assert_eq!(
+            manager.accept(Transaction::Freeze { client_id: 3 }),
+            Err(TransactionError::TooManyClients)
+        );
+        assert!(manager.balance(3).is_none());
+        assert!(!manager.transactions.contains_key(&3));
+
+        // Existing clients continue to work.
+        manager.accept(deposit(4, 1)).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 200);
+    }
+
+    #[test]
+    fn test_dispute_operations_with_delta() {
+        let mut manager = TransactionManager::new();
+
+        for (id, amount) in [(1, 100), (2, 200)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(amount),
+                })
+                .unwrap();
+        }
+
+        assert_eq!(
+            manager.dispute_with_delta(1, 1),
+            Ok(Money::from_base_units(100))
+        );
+        assert_eq!(
+            manager.resolve_with_delta(1, 1),
+            Ok(Money::from_base_units(100))
+        );
+        assert_eq!(
+            manager.dispute_with_delta(2, 1),
+            Ok(Money::from_base_units(200))
+        );
+        assert_eq!(
+            manager.chargeback_with_delta(2, 1),
+            Ok(Money::from_base_units(200))
+        );
+
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.processed_count(), 6);
+
+        assert_eq!(
+            manager.dispute_with_delta(3, 1),
+            Err(TransactionError::DisputedTransactionNotFound)
+        );
+        assert_eq!(
+            manager.resolve_with_delta(2, 1),
+            Err(TransactionError::AlreadyChargedBack)
+        );
+    }
+
+    #[test]
+    fn test_event_sink() {
+        let (sender, mut receiver) = mpsc::channel(16);
+
+        let mut manager = TransactionManager::new().with_event_sink(sender);
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            })
+            .unwrap();
+
+        // Rejected transactions change nothing, so report nothing.
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(500),
+            })
+            .unwrap_err();
+
+        let change = |field, old, new| BalanceChange {
+            client_id: 1,
+            field,
+            old: Money::from_base_units(old),
+            new: Money::from_base_units(new),
+        };
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+
+        assert_eq!(
+            events,
+            [
+                change(BalanceField::Available, 0, 100),
+                change(BalanceField::Total, 0, 100),
+                change(BalanceField::Available, 100, 70),
+                change(BalanceField::Total, 100, 70),
+            ]
+        );
+
+        // A dropped receiver does not affect processing.
+        drop(receiver);
+
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(manager.balances[&1].held(), 100);
+    }
+
+    #[test]
+    fn test_withdrawal_fees() {
+        // (flat, bps, expected fee on a withdrawal of 10,000 base units)
+        for (flat, bps, fee) in [(50, 0, 50), (0, 125, 125), (50, 125, 175)] {
+            let mut manager =
+                TransactionManager::new().with_withdrawal_fee(Money::from_base_units(flat), bps);
+
+            manager
+                .accept(Transaction::Deposit {
+                    id: 1,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(20_000),
+                })
+                .unwrap();
+
+            manager
+                .accept(Transaction::Withdrawal {
+                    id: 2,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(10_000),
+                })
+                .unwrap();
+
+            assert_eq!(manager.balances[&1].available(), 10_000 - fee);
+            assert_eq!(manager.balances[&1].total(), 10_000 - fee);
+            assert_eq!(manager.total_fees(), fee);
+            assert!(manager.consistency_report().is_consistent());
+
+            // The remaining funds cover the amount but not the fee.
+            assert_eq!(
+                manager.simulate_withdrawal(1, Money::from_base_units(10_000 - fee)),
+                Err(TransactionError::InsufficientFunds)
+            );
+            assert_eq!(
+                manager.accept(Transaction::Withdrawal {
+                    id: 3,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(10_000 - fee),
+                }),
+                Err(TransactionError::InsufficientFunds)
+            );
+            assert_eq!(manager.total_fees(), fee);
+
+            manager.undo_last().unwrap();
+
+            assert_eq!(manager.balances[&1].available(), 20_000);
+            assert_eq!(manager.total_fees(), 0);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::transaction_manager::transaction::TransactionStatus;
+    #[test]
+    fn test_withdrawal_fee_overflow() {
+        let mut manager =
+            TransactionManager::new().with_withdrawal_fee(Money::from_base_units(50), 125);
 
-    use super::*;
+        let amount = Money::from_base_units(i64::MAX - 10);
 
-    #[test]
-    fn test_deposit() {
-        let mut manager = TransactionManager::new();
+        assert_eq!(
+            manager.simulate_withdrawal(1, amount),
+            Err(TransactionError::AmountExceedsLimit)
+        );
+        assert_eq!(
+            manager.accept(Transaction::Withdrawal {
+                id: 1,
+                client_id: 1,
+                amount_base_units: amount,
+            }),
+            Err(TransactionError::AmountExceedsLimit)
+        );
+        assert_eq!(manager.total_fees(), 0);
+    }
 
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
+    #[test]
+    fn test_duplicate_policy_replace() {
+        let deposit = |id, client_id, amount| Transaction::Deposit {
+            id,
+            client_id,
+            amount_base_units: Money::from_base_units(amount),
         };
 
-        manager.accept(deposit).unwrap();
+        let mut manager = TransactionManager::new();
+
+        manager.accept(deposit(1, 1, 100)).unwrap();
 
-        assert_eq!(manager.balances[&1].available(), 100);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 100);
-        assert_eq!(manager.balances[&1].locked(), false);
         assert_eq!(
-            *manager.transactions[&1].transaction_type(),
-            TransactionType::Deposit
+            manager.accept(deposit(1, 1, 250)),
+            Err(TransactionError::DuplicateTransaction)
         );
-        assert_eq!(manager.transactions[&1].id(), 1);
-        assert_eq!(manager.transactions[&1].client_id(), 1);
-        assert_eq!(manager.transactions[&1].amount(), 100);
 
-        // Try another deposit.
-        let deposit = Transaction::Deposit {
-            id: 2,
-            client_id: 1,
-            amount_base_units: 50,
-        };
+        let mut manager = TransactionManager::new().with_duplicate_policy(DuplicatePolicy::Replace);
 
-        manager.accept(deposit).unwrap();
+        manager.accept(deposit(1, 1, 100)).unwrap();
+        manager.accept(deposit(1, 1, 250)).unwrap();
 
-        assert_eq!(manager.balances[&1].available(), 150);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 150);
-        assert_eq!(manager.balances[&1].locked(), false);
+        // Only the second deposit is reflected.
+        assert_eq!(manager.balances[&1].available(), 250);
+        assert_eq!(manager.balances[&1].total(), 250);
+        assert_eq!(manager.transactions[&1].amount(), 250);
+        assert_eq!(manager.flows(1).unwrap().gross_deposits, 250);
+        assert!(manager.consistency_report().is_consistent());
+
+        // The replacement may be for another client.
+        manager.accept(deposit(1, 2, 40)).unwrap();
+
+        assert_eq!(manager.balances[&1].total(), 0);
+        assert_eq!(manager.balances[&2].total(), 40);
+
+        // A disputed deposit is not replaced, and nor is a withdrawal.
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 2,
+            })
+            .unwrap();
+
+        assert_eq!(
+            manager.accept(deposit(1, 2, 10)),
+            Err(TransactionError::ReplaceDisputedTransaction)
+        );
+        assert_eq!(manager.balances[&2].held(), 40);
+
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::ZERO,
+            })
+            .unwrap();
+
+        assert_eq!(
+            manager.accept(deposit(2, 1, 10)),
+            Err(TransactionError::DuplicateTransaction)
+        );
     }
 
     #[test]
-    fn test_deposit_multi_user() {
-        let mut manager = TransactionManager::new();
-
-        let deposit1 = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
+    fn test_total_rounding_error() {
+        let accept = |manager: &mut TransactionManager, order_type, id, amount: &str| {
+            let dto = TransactionDto {
+                order_type,
+                client: 1,
+                tx: id,
+                amount: Some(amount.to_string()),
+                timestamp: None,
+                currency: None,
+            };
+
+            let (transaction, rounding_error) = dto
+                .to_transaction_with_rounding_error(RoundingMode::HalfUp)
+                .unwrap();
+
+            manager.accept_with_rounding_error(transaction, None, rounding_error)
         };
 
-        let deposit2 = Transaction::Deposit {
-            id: 2,
-            client_id: 2,
-            amount_base_units: 200,
-        };
+        let mut manager = TransactionManager::new();
 
-        manager.accept(deposit1).unwrap();
+        for (id, amount) in [(1, "0.00005"), (2, "1.00015"), (3, "2.00005"), (4, "1.5")] {
+            accept(&mut manager, csv::OrderType::Deposit, id, amount).unwrap();
+        }
 
-        manager.accept(deposit2).unwrap();
+        // Each of the three half amounts rounded up by half a base unit.
+        assert_eq!(manager.total_rounding_error().to_string(), "1.5");
+        assert_eq!(manager.balances[&1].total(), 15_000 + 1 + 10_002 + 20_001);
 
-        assert_eq!(manager.balances[&1].available(), 100);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 100);
-        assert_eq!(manager.balances[&1].locked(), false);
+        // A rejected transaction does not count.
         assert_eq!(
-            *manager.transactions[&1].transaction_type(),
-            TransactionType::Deposit
+            accept(&mut manager, csv::OrderType::Withdrawal, 5, "100.00005"),
+            Err(TransactionError::InsufficientFunds)
         );
-        assert_eq!(manager.transactions[&1].id(), 1);
-        assert_eq!(manager.transactions[&1].client_id(), 1);
-        assert_eq!(manager.transactions[&1].amount(), 100);
+        assert_eq!(manager.total_rounding_error().to_string(), "1.5");
 
-        assert_eq!(manager.balances[&2].available(), 200);
-        assert_eq!(manager.balances[&2].held(), 0);
-        assert_eq!(manager.balances[&2].total(), 200);
-        assert_eq!(manager.balances[&2].locked(), false);
+        // Rounding a withdrawal up debits the client more, so counts against them.
+        accept(&mut manager, csv::OrderType::Withdrawal, 6, "0.00005").unwrap();
+
+        assert_eq!(manager.total_rounding_error().to_string(), "1");
         assert_eq!(
-            *manager.transactions[&2].transaction_type(),
-            TransactionType::Deposit
+            manager.consistency_report().total_rounding_error,
+            manager.total_rounding_error()
         );
-        assert_eq!(manager.transactions[&2].id(), 2);
-        assert_eq!(manager.transactions[&2].client_id(), 2);
-        assert_eq!(manager.transactions[&2].amount(), 200);
+
+        // Undoing the withdrawal reverses its rounding error.
+        manager.undo_last().unwrap();
+
+        assert_eq!(manager.total_rounding_error().to_string(), "1.5");
+
+        // A replayed deposit changes no balance, so is not counted again.
+        let mut manager = TransactionManager::new().with_replay(true);
+
+        accept(&mut manager, csv::OrderType::Deposit, 1, "0.00005").unwrap();
+        accept(&mut manager, csv::OrderType::Deposit, 1, "0.00005").unwrap();
+
+        assert_eq!(manager.total_rounding_error().to_string(), "0.5");
+
+        // A replaced deposit takes its rounding error with it.
+        let mut manager = TransactionManager::new().with_duplicate_policy(DuplicatePolicy::Replace);
+
+        accept(&mut manager, csv::OrderType::Deposit, 1, "0.00005").unwrap();
+        accept(&mut manager, csv::OrderType::Deposit, 1, "0.00014").unwrap();
+
+        assert_eq!(manager.total_rounding_error().to_string(), "-0.4");
+
+        // A partial withdrawal is limited by the balance rather than the rounded amount.
+        let mut manager = TransactionManager::new().with_partial_withdrawals(true);
+
+        accept(&mut manager, csv::OrderType::Deposit, 1, "1").unwrap();
+        accept(&mut manager, csv::OrderType::Withdrawal, 2, "5.00005").unwrap();
+
+        assert_eq!(manager.balances[&1].total(), 0);
+        assert_eq!(manager.total_rounding_error(), RoundingError::ZERO);
     }
 
     #[test]
-    fn test_withdrawal() {
-        let mut manager = TransactionManager::new();
+    fn test_snapshot_binary_round_trip() {
+        let mut manager = TransactionManager::new().with_withdrawal_holds(true);
+
+        for (id, client_id, amount) in [(1, 1, 100), (2, 2, 200), (3, 3, 300), (4, 1, 50)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(amount),
+                })
+                .unwrap();
+        }
 
-        // Add funds to the account to give a non-zero balance.
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 5,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(20),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 3,
+                client_id: 3,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 3,
+                client_id: 3,
+            })
+            .unwrap();
 
-        manager.accept(deposit).unwrap();
+        let mut restored = TransactionManager::restore_binary(&manager.snapshot_binary()).unwrap();
 
-        // Withdraw funds from the account.
-        let withdrawal = Transaction::Withdrawal {
-            id: 2,
-            client_id: 1,
-            amount_base_units: 50,
+        let sorted = |manager: &TransactionManager| {
+            let mut balances = manager.balances();
+            balances.sort_by_key(|balance| balance.client_id);
+            balances
         };
 
-        manager.accept(withdrawal).unwrap();
+        assert_eq!(sorted(&restored), sorted(&manager));
+        assert_eq!(restored.disputed, manager.disputed);
+        assert_eq!(restored.pending_withdrawals, manager.pending_withdrawals);
+        assert_eq!(restored.locked_clients, manager.locked_clients);
+        assert_eq!(restored.transaction_count(), manager.transaction_count());
+        assert_eq!(restored.processed_count(), manager.processed_count());
+        assert_eq!(restored.consistency_report(), manager.consistency_report());
+        assert_eq!(restored.flows(2), manager.flows(2));
+
+        for id in 1..=5 {
+            assert_eq!(
+                restored.transactions[&id].status(),
+                manager.transactions[&id].status()
+            );
+            assert_eq!(
+                restored.transactions[&id].amount(),
+                manager.transactions[&id].amount()
+            );
+        }
 
-        assert_eq!(manager.balances[&1].available(), 50);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 50);
-        assert_eq!(manager.balances[&1].locked(), false);
+        // The restored engine carries on where the original left off.
+        restored
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(restored.balances[&1].available(), 150);
         assert_eq!(
-            *manager.transactions[&2].transaction_type(),
-            TransactionType::Withdrawal
+            restored.accept(Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(1),
+            }),
+            Err(TransactionError::DuplicateTransaction)
         );
-        assert_eq!(manager.transactions[&2].id(), 2);
-        assert_eq!(manager.transactions[&2].client_id(), 1);
-        assert_eq!(manager.transactions[&2].amount(), 50);
+        assert_eq!(restored.undo_last(), Ok(()));
+        assert!(matches!(
+            TransactionManager::restore_binary(&[1, 2, 3]),
+            Err(TransactionError::InvalidSnapshot(_))
+        ));
     }
 
     #[test]
-    fn test_withdrawal_overdraw() {
+    fn test_snapshot_binary_header() {
         let mut manager = TransactionManager::new();
 
-        // Add funds to the account to give a non-zero balance.
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
 
-        manager.accept(deposit).unwrap();
+        let snapshot = manager.snapshot_binary();
 
-        // Withdraw funds from the account.
-        let withdrawal = Transaction::Withdrawal {
-            id: 2,
-            client_id: 1,
-            amount_base_units: 101,
+        assert!(snapshot.starts_with(SNAPSHOT_MAGIC));
+
+        let invalid = |bytes: &[u8]| match TransactionManager::restore_binary(bytes) {
+            Err(TransactionError::InvalidSnapshot(message)) => message,
+            _ => panic!("snapshot should be rejected"),
         };
 
-        let res = manager.accept(withdrawal);
+        // A snapshot from before the header was written, i.e. the bare state.
+        assert_eq!(
+            invalid(&snapshot[SNAPSHOT_HEADER_LEN..]),
+            "missing snapshot header"
+        );
 
-        assert!(matches!(res, Err(TransactionError::InsufficientFunds)));
+        let mut newer = snapshot.clone();
+        newer[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
 
-        assert_eq!(manager.balances[&1].available(), 100);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 100);
-        assert_eq!(manager.balances[&1].locked(), false);
+        assert_eq!(
+            invalid(&newer),
+            format!(
+                "snapshot version {} is not supported, expected {}",
+                SNAPSHOT_VERSION + 1,
+                SNAPSHOT_VERSION
+            )
+        );
+
+        let mut other_width = snapshot.clone();
+        other_width[6] = 16;
+
+        assert!(invalid(&other_width).contains("16-byte transaction ids"));
+
+        let restored = TransactionManager::restore_binary(&snapshot).unwrap();
+
+        assert_eq!(restored.balances[&1].available(), 100);
     }
 
     #[test]
-    fn test_duplicate_deposit() {
-        let mut manager = TransactionManager::new();
-
-        let deposit = Transaction::Deposit {
-            id: 1,
+    fn test_partial_withdrawals() {
+        let withdrawal = |id, amount| Transaction::Withdrawal {
+            id,
             client_id: 1,
-            amount_base_units: 100,
+            amount_base_units: Money::from_base_units(amount),
         };
 
-        manager.accept(deposit).unwrap();
+        let mut manager = TransactionManager::new().with_partial_withdrawals(true);
 
-        // Duplicate
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
 
-        let res = manager.accept(deposit);
+        // Within the balance, a withdrawal is applied in full.
+        manager.accept(withdrawal(2, 30)).unwrap();
 
-        assert!(matches!(res, Err(TransactionError::DuplicateTransaction)));
+        assert_eq!(manager.transactions[&2].amount(), 30);
 
-        assert_eq!(manager.balances[&1].available(), 100);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 100);
-        assert_eq!(manager.balances[&1].locked(), false);
+        // Beyond it, the account is drained and only what was withdrawn is recorded.
+        manager.accept(withdrawal(3, 500)).unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 0);
+        assert_eq!(manager.balances[&1].total(), 0);
+        assert_eq!(manager.transactions[&3].amount(), 70);
+        assert_eq!(manager.flows(1).unwrap().gross_withdrawals, 100);
+        assert_eq!(manager.flows(1).unwrap().withdrawal_shortfall, 430);
+        assert!(manager.consistency_report().is_consistent());
+
+        // Nothing is left to withdraw.
+        assert_eq!(
+            manager.accept(withdrawal(4, 10)),
+            Err(TransactionError::InsufficientFunds)
+        );
+
+        // Undoing a partial withdrawal returns what was actually withdrawn, and forgets its shortfall.
+        manager.undo_last().unwrap();
+
+        assert_eq!(manager.balances[&1].available(), 70);
+        assert_eq!(manager.flows(1).unwrap().gross_withdrawals, 30);
+        assert_eq!(manager.flows(1).unwrap().withdrawal_shortfall, 0);
     }
 
     #[test]
-    fn test_dispute_non_existent_tx() {
+    fn test_partial_withdrawals_with_fee() {
+        let mut manager = TransactionManager::new()
+            .with_partial_withdrawals(true)
+            .with_withdrawal_fee(Money::from_base_units(10), 100);
+
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(1_020),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(5_000),
+            })
+            .unwrap();
+
+        // 1,000 withdrawn plus a fee of 10 + 1% of 1,000 uses up the balance.
+        assert_eq!(manager.transactions[&2].amount(), 1_000);
+        assert_eq!(manager.total_fees(), 20);
+        assert_eq!(manager.balances[&1].available(), 0);
+
+        // Without partial withdrawals the same withdrawal is rejected.
         let mut manager = TransactionManager::new();
 
-        let dispute = Transaction::Dispute {
-            id: 1,
-            client_id: 1,
-        };
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
 
-        let res = manager.accept(dispute);
+        assert_eq!(
+            manager.accept(Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(500),
+            }),
+            Err(TransactionError::InsufficientFunds)
+        );
+    }
 
-        assert!(matches!(
-            res,
-            Err(TransactionError::DisputedTransactionNotFound)
-        ));
+    #[test]
+    fn test_detect_anomalies() {
+        let mut manager = TransactionManager::new().with_withdrawal_holds(true);
+
+        for transaction in [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(50),
+            },
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Deposit {
+                id: 4,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(70),
+            },
+            Transaction::Dispute {
+                id: 4,
+                client_id: 2,
+            },
+            Transaction::Chargeback {
+                id: 4,
+                client_id: 2,
+            },
+            Transaction::Freeze { client_id: 3 },
+        ] {
+            manager.accept(transaction).unwrap();
+        }
+
+        // Disputes and pending withdrawals hold no more than was deposited, and accounts locked with nothing
+        // available are expected.
+        assert_eq!(manager.balances[&1].held(), 130);
+        assert_eq!(manager.detect_anomalies(), Vec::new());
+
+        // Opening balances have no deposits behind their held funds.
+        let manager = TransactionManager::from_balances([OpeningBalance {
+            client_id: 5,
+            available: Money::from_base_units(20),
+            held: Money::from_base_units(10),
+            locked: true,
+        }]);
+
+        assert_eq!(
+            manager.detect_anomalies(),
+            vec![
+                Anomaly::HeldExceedsDeposits {
+                    client_id: 5,
+                    held: Money::from_base_units(10),
+                    deposits: Money::ZERO,
+                },
+                Anomaly::LockedWithAvailable {
+                    client_id: 5,
+                    available: Money::from_base_units(20),
+                },
+            ]
+        );
+        assert_eq!(
+            manager.detect_anomalies()[1].to_string(),
+            "client 5 is locked with 0.0020 available"
+        );
     }
 
     #[test]
-    fn test_dispute_client_mismatch() {
-        let mut manager = TransactionManager::new();
+    fn test_detect_anomalies_evicted() {
+        let mut manager = TransactionManager::new()
+            .with_evict_finalized(true)
+            .with_withdrawal_holds(true);
 
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        for transaction in [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Withdrawal {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            },
+        ] {
+            manager.accept(transaction).unwrap();
+        }
 
-        manager.accept(deposit).unwrap();
+        // The resolved deposit behind the pending withdrawal has been evicted, so is not counted against it.
+        assert!(!manager.transactions.contains_key(&1));
+        assert_eq!(manager.balances[&1].held(), 30);
+        assert_eq!(manager.detect_anomalies(), Vec::new());
+    }
 
-        let dispute = Transaction::Dispute {
-            id: 1,
-            // Client ID does not match.
-            client_id: 2,
-        };
+    #[test]
+    fn test_evict_finalized() {
+        let mut manager = TransactionManager::new().with_evict_finalized(true);
+
+        for id in 1..=3 {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(100),
+                })
+                .unwrap();
+        }
 
-        let res = manager.accept(dispute);
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(manager.transaction_count(), 3);
+
+        manager
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(manager.transaction_count(), 2);
+
+        manager
+            .accept(Transaction::Dispute {
+                id: 2,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 2,
+                client_id: 1,
+            })
+            .unwrap();
+
+        assert_eq!(manager.transaction_count(), 1);
+        assert_eq!(manager.balances[&1].total(), 200);
+
+        // An evicted transaction cannot be disputed again, and its id cannot be reused.
+        assert_eq!(
+            manager.accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1
+            }),
+            Err(TransactionError::DisputedTransactionNotFound)
+        );
+        assert_eq!(
+            manager.accept(Transaction::Deposit {
+                id: 1,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(100),
+            }),
+            Err(TransactionError::DuplicateTransaction)
+        );
+
+        // Undoing the last deposit is unaffected.
+        manager.undo_last().unwrap();
 
-        assert!(matches!(res, Err(TransactionError::DisputeClientMismatch)));
+        assert_eq!(manager.transaction_count(), 0);
     }
 
     #[test]
-    fn test_dispute_withdrawal_fails() {
+    fn test_apply_csv_str() {
         let mut manager = TransactionManager::new();
 
+        let results = manager.apply_csv_str(
+            "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1.0\nwithdrawal,1,3,5.0\n",
+        );
+
+        assert_eq!(
+            results,
+            vec![Ok(()), Ok(()), Err(TransactionError::InsufficientFunds)]
+        );
+        assert_eq!(manager.balances[&1].available(), 15_000);
+        assert_eq!(manager.balances[&1].total(), 15_000);
+
+        // Unreadable rows are reported in place without stopping the rest.
+        let results = manager
+            .apply_csv_str("type,client,tx,amount\ndeposit,1,4\ndeposit,1,5,abc\ndeposit,1,6,1\n");
+
+        assert!(matches!(results[0], Err(TransactionError::InvalidRow(_))));
+        assert_eq!(
+            results[1],
+            Err(TransactionError::InvalidRow(
+                "Amount is not a valid decimal number: abc".to_string()
+            ))
+        );
+        assert_eq!(results[2], Ok(()));
+        assert_eq!(manager.balances[&1].total(), 25_000);
+    }
+
+    #[test]
+    fn test_trust_stored_client() {
         let deposit = Transaction::Deposit {
             id: 1,
             client_id: 1,
-            amount_base_units: 100,
+            amount_base_units: Money::from_base_units(100),
         };
 
-        let withdrawal = Transaction::Withdrawal {
-            id: 2,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        let mut manager = TransactionManager::new();
+
+        manager.accept(deposit.clone()).unwrap();
+
+        assert_eq!(
+            manager.accept(Transaction::Dispute {
+                id: 1,
+                client_id: 2
+            }),
+            Err(TransactionError::DisputeClientMismatch {
+                expected: 1,
+                actual: 2
+            })
+        );
+
+        let mut manager = TransactionManager::new().with_trust_stored_client(true);
 
         manager.accept(deposit).unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 2,
+            })
+            .unwrap();
 
-        manager.accept(withdrawal).unwrap();
+        // The stored client's funds are held, and no balance is created for the misstated one.
+        assert_eq!(manager.balances[&1].held(), 100);
+        assert!(!manager.balances.contains_key(&2));
 
-        let dispute = Transaction::Dispute {
-            // Dispute the withdrawal.
-            id: 2,
-            client_id: 1,
-        };
+        manager
+            .accept(Transaction::Chargeback {
+                id: 1,
+                client_id: 9,
+            })
+            .unwrap();
 
-        let res = manager.accept(dispute);
+        assert!(manager.balances[&1].locked());
+        assert_eq!(manager.balances[&1].total(), 0);
 
-        assert!(matches!(
-            res,
-            Err(TransactionError::DisputeWithdrawalNotSupported)
-        ));
+        // The balance returned is the stored client's.
+        manager
+            .accept(Transaction::Deposit {
+                id: 2,
+                client_id: 3,
+                amount_base_units: Money::from_base_units(50),
+            })
+            .unwrap();
+
+        let balance = manager
+            .accept_with_result(Transaction::Dispute {
+                id: 2,
+                client_id: 2,
+            })
+            .unwrap();
+
+        assert_eq!(balance.client_id, 3);
+        assert_eq!(balance.held, 0.005);
     }
 
     #[test]
-    fn test_dispute_transaction() {
-        let mut manager = TransactionManager::new();
+    fn test_max_transaction_id() {
+        let mut manager = TransactionManager::new().with_max_transaction_id(100);
 
         let deposit = Transaction::Deposit {
-            id: 1,
+            id: 100,
             client_id: 1,
-            amount_base_units: 100,
+            amount_base_units: Money::from_base_units(1_000),
         };
 
         manager.accept(deposit).unwrap();
 
         let deposit = Transaction::Deposit {
-            id: 2,
+            id: 101,
             client_id: 1,
-            amount_base_units: 50,
+            amount_base_units: Money::from_base_units(1_000),
         };
 
-        manager.accept(deposit).unwrap();
+        assert_eq!(
+            manager.accept(deposit),
+            Err(TransactionError::TransactionIdOutOfRange)
+        );
 
-        let dispute = Transaction::Dispute {
-            id: 1,
+        let withdrawal = Transaction::Withdrawal {
+            id: TransactionId::MAX,
             client_id: 1,
+            amount_base_units: Money::from_base_units(500),
         };
 
-        manager.accept(dispute).unwrap();
-
         assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Disputed
+            manager.accept(withdrawal),
+            Err(TransactionError::TransactionIdOutOfRange)
         );
 
-        assert_eq!(manager.balances[&1].available(), 50);
-        assert_eq!(manager.balances[&1].held(), 100);
-        assert_eq!(manager.balances[&1].total(), 150);
+        assert_eq!(manager.balances[&1].available(), 1_000);
+        assert!(!manager.transactions.contains_key(&101));
     }
 
     #[test]
-    fn test_resolve_non_existent_tx() {
+    fn test_reconciliation() {
         let mut manager = TransactionManager::new();
 
-        let resolve = Transaction::Resolve {
-            id: 1,
-            client_id: 1,
-        };
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(300),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(150),
+            },
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(80),
+            },
+            Transaction::Dispute {
+                id: 2,
+                client_id: 2,
+            },
+            Transaction::Chargeback {
+                id: 2,
+                client_id: 2,
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+            Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            },
+        ];
+
+        for transaction in transactions {
+            manager.accept(transaction).unwrap();
+        }
 
-        let res = manager.accept(resolve);
+        let report = manager.consistency_report();
+        let net_flows = report.total_deposited - report.total_withdrawn - report.total_charged_back;
 
-        assert!(matches!(
-            res,
-            Err(TransactionError::DisputedTransactionNotFound)
-        ));
+        assert_eq!(net_flows, 220);
+        assert_eq!(report.balances_total, net_flows);
+        assert_eq!(report.discrepancy(), Money::ZERO);
     }
 
     #[test]
-    fn test_resolve_client_mismatch() {
+    fn test_transaction_status() {
         let mut manager = TransactionManager::new();
 
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        assert_eq!(manager.transaction_status(1), None);
 
-        manager.accept(deposit).unwrap();
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
 
-        let dispute = Transaction::Dispute {
-            id: 1,
-            client_id: 1,
-        };
+        assert_eq!(
+            manager.transaction_status(1),
+            Some(TransactionStatus::Valid)
+        );
 
-        manager.accept(dispute).unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
 
-        let resolve = Transaction::Resolve {
-            id: 1,
-            // Client ID does not match.
-            client_id: 2,
-        };
+        assert_eq!(
+            manager.transaction_status(1),
+            Some(TransactionStatus::Disputed)
+        );
 
-        let res = manager.accept(resolve);
+        manager
+            .accept(Transaction::Resolve {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
 
-        assert!(matches!(res, Err(TransactionError::ResolveClientMismatch)));
+        assert_eq!(
+            manager.transaction_status(1),
+            Some(TransactionStatus::Resolved)
+        );
+        assert_eq!(manager.transaction_status(2), None);
     }
 
     #[test]
-    fn test_resolve_transaction() {
-        let mut manager = TransactionManager::new();
-
+    fn test_max_disputes_per_transaction() {
         let deposit = Transaction::Deposit {
             id: 1,
             client_id: 1,
-            amount_base_units: 100,
+            amount_base_units: Money::from_base_units(100),
         };
-
-        manager.accept(deposit).unwrap();
-
-        let deposit = Transaction::Deposit {
-            id: 2,
+        let dispute = Transaction::Dispute {
+            id: 1,
             client_id: 1,
-            amount_base_units: 50,
         };
-
-        manager.accept(deposit).unwrap();
-
-        let dispute = Transaction::Dispute {
+        let resolve = Transaction::Resolve {
             id: 1,
             client_id: 1,
         };
 
-        manager.accept(dispute).unwrap();
+        // By default a resolved transaction is final.
+        let mut manager = TransactionManager::new();
+
+        manager.accept(deposit.clone()).unwrap();
+        manager.accept(dispute.clone()).unwrap();
+        manager.accept(resolve.clone()).unwrap();
 
         assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Disputed
+            manager.accept(dispute.clone()),
+            Err(TransactionError::DisputeLimitReached)
         );
 
-        assert_eq!(manager.balances[&1].available(), 50);
-        assert_eq!(manager.balances[&1].held(), 100);
-        assert_eq!(manager.balances[&1].total(), 150);
-
-        let resolve = Transaction::Resolve {
-            id: 1,
-            client_id: 1,
-        };
+        let mut manager = TransactionManager::new()
+            .with_max_disputes_per_transaction(2)
+            .with_evict_finalized(true);
 
-        manager.accept(resolve).unwrap();
+        manager.accept(deposit).unwrap();
+        manager.accept(dispute.clone()).unwrap();
+        manager.accept(resolve.clone()).unwrap();
 
+        // Still disputable, so not evicted.
         assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Resolved
+            manager.transaction_status(1),
+            Some(TransactionStatus::Resolved)
         );
 
-        assert_eq!(manager.balances[&1].available(), 150);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 150);
-    }
-
-    #[test]
-    fn test_chargeback_non_existent_tx() {
-        let mut manager = TransactionManager::new();
+        manager.accept(dispute.clone()).unwrap();
 
-        let chargeback = Transaction::Chargeback {
-            id: 1,
-            client_id: 1,
-        };
+        assert_eq!(manager.balances[&1].held(), 100);
 
-        let res = manager.accept(chargeback);
+        manager.accept(resolve).unwrap();
 
-        assert!(matches!(
-            res,
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert_eq!(manager.balances[&1].held(), 0);
+        assert_eq!(manager.transaction_status(1), None);
+        assert_eq!(
+            manager.accept(dispute),
             Err(TransactionError::DisputedTransactionNotFound)
-        ));
+        );
     }
 
     #[test]
-    fn test_chargeback_client_mismatch() {
+    fn test_force_deposit() {
         let mut manager = TransactionManager::new();
 
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+        manager
+            .accept(Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
+        manager
+            .accept(Transaction::Chargeback {
+                id: 1,
+                client_id: 1,
+            })
+            .unwrap();
 
-        manager.accept(deposit).unwrap();
+        assert!(manager.balances[&1].locked());
 
-        let dispute = Transaction::Dispute {
-            id: 1,
+        assert_eq!(
+            manager.accept(Transaction::Deposit {
+                id: 2,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            }),
+            Err(TransactionError::AccountLocked)
+        );
+
+        let force_deposit = Transaction::ForceDeposit {
+            id: 3,
             client_id: 1,
+            amount_base_units: Money::from_base_units(100),
         };
 
-        manager.accept(dispute).unwrap();
+        assert!(force_deposit.is_administrative());
 
-        let chargeback = Transaction::Chargeback {
-            id: 1,
-            // Client ID does not match.
-            client_id: 2,
-        };
+        manager.accept(force_deposit).unwrap();
 
-        let res = manager.accept(chargeback);
+        assert_eq!(manager.balances[&1].available(), 100);
+        assert!(manager.balances[&1].locked());
+        assert_eq!(
+            *manager.transactions[&3].transaction_type(),
+            TransactionType::ForceDeposit
+        );
+        assert!(manager.consistency_report().is_consistent());
 
-        assert!(matches!(
-            res,
-            Err(TransactionError::ChargebackClientMismatch)
-        ));
+        assert_eq!(
+            manager.accept(Transaction::Dispute {
+                id: 3,
+                client_id: 1
+            }),
+            Err(TransactionError::DisputeForceDepositNotSupported)
+        );
     }
 
     #[test]
-    fn test_chargeback_transaction() {
-        let mut manager = TransactionManager::new();
-
-        let deposit = Transaction::Deposit {
-            id: 1,
-            client_id: 1,
-            amount_base_units: 100,
-        };
+    fn test_balance_timeline() {
+        let mut manager = TransactionManager::new().with_balance_timeline(true);
 
-        manager.accept(deposit).unwrap();
+        let transactions = [
+            Transaction::Deposit {
+                id: 1,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(100),
+            },
+            Transaction::Deposit {
+                id: 2,
+                client_id: 2,
+                amount_base_units: Money::from_base_units(500),
+            },
+            Transaction::Withdrawal {
+                id: 3,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(30),
+            },
+            // Rejected, so not recorded.
+            Transaction::Withdrawal {
+                id: 4,
+                client_id: 1,
+                amount_base_units: Money::from_base_units(1_000),
+            },
+            Transaction::Dispute {
+                id: 1,
+                client_id: 1,
+            },
+        ];
+
+        for transaction in transactions {
+            let _ = manager.accept(transaction);
+        }
 
-        let deposit = Transaction::Deposit {
-            id: 2,
-            client_id: 1,
-            amount_base_units: 50,
-        };
+        let amounts: Vec<_> = manager
+            .balance_timeline(1)
+            .iter()
+            .map(|snapshot| {
+                (
+                    snapshot.available().base_units(),
+                    snapshot.held().base_units(),
+                    snapshot.total().base_units(),
+                )
+            })
+            .collect();
 
-        manager.accept(deposit).unwrap();
+        assert_eq!(
+            amounts,
+            [(0, 0, 0), (100, 0, 100), (70, 0, 70), (-30, 100, 70)]
+        );
+        assert_eq!(manager.balance_timeline(2).len(), 2);
+        assert!(manager.balance_timeline(3).is_empty());
+        assert!(TransactionManager::new().balance_timeline(1).is_empty());
+    }
 
-        let dispute = Transaction::Dispute {
-            id: 1,
-            client_id: 1,
+    #[test]
+    fn test_merge_balance_timeline() {
+        let deposit = |id, client_id| Transaction::Deposit {
+            id,
+            client_id,
+            amount_base_units: Money::from_base_units(100),
         };
 
-        manager.accept(dispute).unwrap();
+        let mut manager = TransactionManager::new().with_balance_timeline(true);
+        let mut other = TransactionManager::new().with_balance_timeline(true);
 
-        assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Disputed
-        );
+        manager.accept(deposit(1, 1)).unwrap();
+        other.accept(deposit(2, 2)).unwrap();
+        other.accept(deposit(3, 2)).unwrap();
 
-        assert_eq!(manager.balances[&1].available(), 50);
-        assert_eq!(manager.balances[&1].held(), 100);
-        assert_eq!(manager.balances[&1].total(), 150);
+        manager.merge(other).unwrap();
 
-        let chargeback = Transaction::Chargeback {
-            id: 1,
-            client_id: 1,
-        };
+        assert_eq!(manager.balance_timeline(1).len(), 2);
+        assert_eq!(manager.balance_timeline(2).len(), 3);
+        assert_eq!(manager.balance_timeline(2)[2].total(), 200);
+    }
 
-        manager.accept(chargeback).unwrap();
+    #[test]
+    fn test_balances_sorted() {
+        let mut manager = TransactionManager::new();
 
-        assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Chargeback
-        );
+        for (id, client_id) in [(1, 42), (2, 7), (3, 300), (4, 1), (5, 99)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(100),
+                })
+                .unwrap();
+        }
 
-        assert_eq!(manager.balances[&1].available(), 50);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), 50);
+        let clients: Vec<_> = manager
+            .balances_sorted()
+            .iter()
+            .map(|balance| balance.client_id)
+            .collect();
+
+        assert_eq!(clients, [1, 7, 42, 99, 300]);
     }
 
     #[test]
-    fn test_chargeback_transaction_negative_balance() {
-        let mut manager = TransactionManager::new();
+    fn test_min_deposit() {
+        let mut manager = TransactionManager::new().with_min_deposit(Money::from_base_units(100));
 
         let deposit = Transaction::Deposit {
             id: 1,
             client_id: 1,
-            amount_base_units: 100,
+            amount_base_units: Money::from_base_units(99),
         };
 
-        manager.accept(deposit).unwrap();
+        assert_eq!(
+            manager.accept(deposit),
+            Err(TransactionError::BelowMinimumDeposit)
+        );
+        assert!(!manager.transactions.contains_key(&1));
 
-        let withdrawal = Transaction::Withdrawal {
+        let deposit = Transaction::Deposit {
             id: 2,
             client_id: 1,
-            amount_base_units: 50,
+            amount_base_units: Money::from_base_units(100),
         };
 
-        manager.accept(withdrawal).unwrap();
+        manager.accept(deposit).unwrap();
 
-        let dispute = Transaction::Dispute {
-            id: 1,
+        // Withdrawals below the minimum are unaffected.
+        let withdrawal = Transaction::Withdrawal {
+            id: 3,
             client_id: 1,
+            amount_base_units: Money::from_base_units(1),
         };
 
-        manager.accept(dispute).unwrap();
+        manager.accept(withdrawal).unwrap();
 
-        assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Disputed
-        );
+        assert_eq!(manager.balances[&1].available(), 99);
+    }
 
-        assert_eq!(manager.balances[&1].available(), -50);
-        assert_eq!(manager.balances[&1].held(), 100);
-        assert_eq!(manager.balances[&1].total(), 50);
+    #[test]
+    fn test_min_deposit_replace() {
+        let mut manager = TransactionManager::new()
+            .with_min_deposit(Money::from_base_units(100))
+            .with_duplicate_policy(DuplicatePolicy::Replace);
 
-        let chargeback = Transaction::Chargeback {
+        let deposit = Transaction::Deposit {
             id: 1,
             client_id: 1,
+            amount_base_units: Money::from_base_units(500),
         };
 
-        manager.accept(chargeback).unwrap();
+        manager.accept(deposit).unwrap();
+
+        // A replacement below the minimum is rejected and the original deposit is kept.
+        let replacement = Transaction::Deposit {
+            id: 1,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(1),
+        };
 
         assert_eq!(
-            *manager.transactions[&1].status(),
-            TransactionStatus::Chargeback
+            manager.accept(replacement),
+            Err(TransactionError::BelowMinimumDeposit)
         );
-
-        assert_eq!(manager.balances[&1].available(), -50);
-        assert_eq!(manager.balances[&1].held(), 0);
-        assert_eq!(manager.balances[&1].total(), -50);
+        assert_eq!(manager.balances[&1].available(), 500);
+        assert_eq!(manager.transactions[&1].amount(), 500);
+        assert!(manager.consistency_report().is_consistent());
     }
 }
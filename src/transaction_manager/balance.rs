@@ -1,74 +1,193 @@
 use super::errors::TransactionError;
+use crate::money::Money;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+// How a chargeback that leaves the available balance negative is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ChargebackPolicy {
+    // The available balance goes negative, i.e. the client owes us money.
+    #[default]
+    AllowNegative,
+    // The available balance is clamped to zero and the uncovered amount recorded as a shortfall.
+    ClampToZero,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Balance {
-    available_base_units: i64,
-    held_base_units: i64,
-    total_base_units: i64,
+    available_base_units: Money,
+    held_base_units: Money,
+    total_base_units: Money,
+    // Chargeback amounts not covered by the client's funds under ChargebackPolicy::ClampToZero.
+    shortfall_base_units: Money,
     locked: bool,
 }
 
 impl Balance {
     pub fn new() -> Self {
         Self {
-            available_base_units: 0,
-            held_base_units: 0,
-            total_base_units: 0,
+            available_base_units: Money::ZERO,
+            held_base_units: Money::ZERO,
+            total_base_units: Money::ZERO,
+            shortfall_base_units: Money::ZERO,
             locked: false,
         }
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
-    pub fn available(&self) -> i64 {
+    // A balance carried over from a previous run.
+    pub fn opening(available: Money, held: Money, locked: bool) -> Self {
+        Self {
+            available_base_units: available,
+            held_base_units: held,
+            total_base_units: available + held,
+            shortfall_base_units: Money::ZERO,
+            locked,
+        }
+    }
+
+    pub fn available(&self) -> Money {
         self.available_base_units
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
-    pub fn held(&self) -> i64 {
+    pub fn held(&self) -> Money {
         self.held_base_units
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
-    pub fn total(&self) -> i64 {
+    pub fn total(&self) -> Money {
         self.total_base_units
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn shortfall(&self) -> Money {
+        self.shortfall_base_units
+    }
+
     pub fn locked(&self) -> bool {
         self.locked
     }
 
-    pub fn deposit(&mut self, amount: i64) {
+    // Every operation keeps total equal to available plus held, so a mismatch indicates a bug in the
+    // balance arithmetic.
+    pub fn check_invariant(&self) -> Result<(), TransactionError> {
+        if self.available_base_units + self.held_base_units != self.total_base_units {
+            return Err(TransactionError::BalanceInvariantViolated {
+                available: self.available_base_units,
+                held: self.held_base_units,
+                total: self.total_base_units,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn deposit(&mut self, amount: Money) {
+        self.available_base_units += amount;
+
+        self.total_base_units += amount;
+    }
+
+    pub fn withdrawal(&mut self, amount: Money) -> Result<(), TransactionError> {
+        self.available_base_units = self.available_after_withdrawal(amount)?;
+
+        self.total_base_units -= amount;
+
+        Ok(())
+    }
+
+    // Applies a signed manual correction to available and total. Unlike a withdrawal, a debit is not checked
+    // against the available funds and may leave it negative.
+    pub fn adjust(&mut self, amount: Money) {
         self.available_base_units += amount;
 
         self.total_base_units += amount;
     }
 
-    pub fn withdrawal(&mut self, amount: i64) -> Result<(), TransactionError> {
+    // Takes back a deposit, skipping the funds check of a withdrawal as the deposit is being erased rather
+    // than withdrawn.
+    pub fn reverse_deposit(&mut self, amount: Money) {
+        self.available_base_units -= amount;
+
+        self.total_base_units -= amount;
+    }
+
+    pub fn reverse_withdrawal(&mut self, amount: Money) {
+        self.deposit(amount);
+    }
+
+    // The available balance a withdrawal would leave, without applying it.
+    pub fn available_after_withdrawal(&self, amount: Money) -> Result<Money, TransactionError> {
         if self.available_base_units < amount {
             return Err(TransactionError::InsufficientFunds);
         }
 
-        self.available_base_units -= amount;
+        Ok(self.available_base_units - amount)
+    }
 
-        self.total_base_units -= amount;
+    // Deducts a fee from available and total. A negative fee refunds it.
+    pub fn charge_fee(&mut self, fee: Money) {
+        self.available_base_units -= fee;
+
+        self.total_base_units -= fee;
+    }
+
+    // Moves withdrawn funds from available to held until the withdrawal is settled.
+    pub fn hold_withdrawal(&mut self, amount: Money) -> Result<(), TransactionError> {
+        self.available_after_withdrawal(amount)?;
+
+        self.hold(amount);
 
         Ok(())
     }
 
-    pub fn hold(&mut self, amount: i64) {
+    // Removes the held funds of a settled withdrawal from held and total.
+    pub fn settle_withdrawal(&mut self, amount: Money) {
+        self.held_base_units -= amount;
+
+        self.total_base_units -= amount;
+    }
+
+    pub fn hold(&mut self, amount: Money) {
         // Reduce available balance and increase held balance, but keep total the same.
         self.available_base_units -= amount;
         self.held_base_units += amount;
     }
 
-    pub fn release(&mut self, amount: i64) {
+    pub fn release(&mut self, amount: Money) {
         // Increase available balance and decrease held balance, but keep total the same.
         self.available_base_units += amount;
         self.held_base_units -= amount;
     }
 
-    pub fn chargeback(&mut self, amount: i64) {
+    pub fn split_release(
+        &mut self,
+        release_amount: Money,
+        chargeback_amount: Money,
+        policy: ChargebackPolicy,
+    ) {
+        // Release one portion of the held funds back to available and charge back the rest.
+        self.release(release_amount);
+
+        if chargeback_amount.is_positive() {
+            self.chargeback(chargeback_amount, policy);
+        }
+    }
+
+    // Adds another balance for the same client into this one. The account is locked if either was.
+    pub fn merge(&mut self, other: &Balance) {
+        self.available_base_units += other.available_base_units;
+        self.held_base_units += other.held_base_units;
+        self.total_base_units += other.total_base_units;
+        self.shortfall_base_units += other.shortfall_base_units;
+        self.locked |= other.locked;
+    }
+
+    pub fn freeze(&mut self) {
+        self.locked = true;
+    }
+
+    pub fn unfreeze(&mut self) {
+        self.locked = false;
+    }
+
+    pub fn chargeback(&mut self, amount: Money, policy: ChargebackPolicy) {
         // Both the total and held are reduced by the chargeback amount.
         // Note that here we can have a negative available balance without held funds to offset,
         // hence the client could owe us money. Ut seems to be coming in banking, hence we'll]
@@ -76,6 +195,20 @@ impl Balance {
         self.total_base_units -= amount;
         self.held_base_units -= amount;
         self.locked = true;
+
+        if policy == ChargebackPolicy::ClampToZero && self.available_base_units.is_negative() {
+            // Raising available to zero raises the total by the same amount, which is tracked as the
+            // shortfall instead.
+            self.shortfall_base_units -= self.available_base_units;
+            self.total_base_units -= self.available_base_units;
+            self.available_base_units = Money::ZERO;
+        }
+    }
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -84,6 +217,46 @@ impl PartialEq for Balance {
         self.available_base_units == other.available_base_units
             && self.held_base_units == other.held_base_units
             && self.total_base_units == other.total_base_units
+            && self.shortfall_base_units == other.shortfall_base_units
             && self.locked == other.locked
     }
 }
+
+#[cfg(test)]
+impl Balance {
+    // Overrides the total, so tests can construct a balance that breaks the invariant.
+    pub fn with_total(mut self, total: Money) -> Self {
+        self.total_base_units = total;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_invariant() {
+        let mut balance =
+            Balance::opening(Money::from_base_units(10), Money::from_base_units(5), false);
+
+        balance.hold(Money::from_base_units(3));
+        balance.chargeback(Money::from_base_units(8), ChargebackPolicy::ClampToZero);
+
+        assert_eq!(balance.check_invariant(), Ok(()));
+
+        let inconsistent = Balance {
+            total_base_units: Money::from_base_units(20),
+            ..Balance::opening(Money::from_base_units(10), Money::from_base_units(5), false)
+        };
+
+        assert_eq!(
+            inconsistent.check_invariant(),
+            Err(TransactionError::BalanceInvariantViolated {
+                available: Money::from_base_units(10),
+                held: Money::from_base_units(5),
+                total: Money::from_base_units(20),
+            })
+        );
+    }
+}
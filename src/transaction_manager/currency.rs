@@ -0,0 +1,231 @@
+use super::{
+    errors::TransactionError, ClientBalance, Timestamp, Transaction, TransactionId,
+    TransactionManager,
+};
+use crate::money::RoundingError;
+use std::collections::{BTreeMap, HashMap};
+
+// An ISO 4217 style currency code, e.g. `USD`.
+pub type Currency = String;
+
+// Keeps each client's balances separated by currency, with one engine per currency. Deposits, withdrawals
+// and adjustments go to the engine for their currency, or the base currency if they have none. Disputes,
+// resolves, chargebacks and settlements go to the engine holding the transaction they reference, so need
+// not repeat its currency.
+//
+// Locks are per currency: a chargeback or freeze locks the client's balance in that currency only.
+pub struct CurrencyLedger {
+    base_currency: Currency,
+    // Ordered so balances and reports are listed by currency.
+    managers: BTreeMap<Currency, TransactionManager>,
+    // The currency of every applied deposit, withdrawal and adjustment, so transaction ids stay unique
+    // across currencies and disputes can be routed.
+    transaction_currencies: HashMap<TransactionId, Currency>,
+    // Creates the engine for a currency seen for the first time, with the same configuration as the base.
+    new_manager: Box<dyn Fn() -> TransactionManager + Send>,
+}
+
+impl CurrencyLedger {
+    pub fn new<F>(
+        base_currency: impl Into<Currency>,
+        base_manager: TransactionManager,
+        new_manager: F,
+    ) -> CurrencyLedger
+    where
+        F: Fn() -> TransactionManager + Send + 'static,
+    {
+        let base_currency = base_currency.into();
+
+        CurrencyLedger {
+            managers: BTreeMap::from([(base_currency.clone(), base_manager)]),
+            base_currency,
+            transaction_currencies: HashMap::new(),
+            new_manager: Box::new(new_manager),
+        }
+    }
+
+    pub fn base_currency(&self) -> &str {
+        &self.base_currency
+    }
+
+    pub fn accept(
+        &mut self,
+        transaction: Transaction,
+        currency: Option<&str>,
+    ) -> Result<(), TransactionError> {
+        self.accept_with_rounding_error(transaction, currency, None, RoundingError::ZERO)
+    }
+
+    // Applies the transaction to the engine for its currency, see TransactionManager::accept_with_rounding_error.
+    pub fn accept_with_rounding_error(
+        &mut self,
+        transaction: Transaction,
+        currency: Option<&str>,
+        timestamp: Option<Timestamp>,
+        rounding_error: RoundingError,
+    ) -> Result<(), TransactionError> {
+        let currency = self.route(&transaction, currency)?;
+
+        let stored_id = match transaction {
+            Transaction::Deposit { id, .. }
+            | Transaction::Withdrawal { id, .. }
+            | Transaction::Adjustment { id, .. }
+            | Transaction::ForceDeposit { id, .. } => Some(id),
+            _ => None,
+        };
+
+        let manager = self
+            .managers
+            .entry(currency.clone())
+            .or_insert_with(|| (self.new_manager)());
+
+        manager.accept_with_rounding_error(transaction, timestamp, rounding_error)?;
+
+        if let Some(id) = stored_id {
+            self.transaction_currencies.insert(id, currency);
+        }
+
+        Ok(())
+    }
+
+    // The currency whose engine handles the transaction.
+    fn route(
+        &self,
+        transaction: &Transaction,
+        currency: Option<&str>,
+    ) -> Result<Currency, TransactionError> {
+        let currency = currency.unwrap_or(&self.base_currency);
+
+        match *transaction {
+            // An id may only be reused within the same currency, where the engine decides whether it is a
+            // duplicate or a replay.
+            Transaction::Deposit { id, .. }
+            | Transaction::Withdrawal { id, .. }
+            | Transaction::Adjustment { id, .. }
+            | Transaction::ForceDeposit { id, .. } => match self.transaction_currencies.get(&id) {
+                Some(existing) if existing != currency => {
+                    Err(TransactionError::DuplicateTransaction)
+                }
+                _ => Ok(currency.to_string()),
+            },
+            Transaction::SettleWithdrawal { id, .. }
+            | Transaction::Dispute { id, .. }
+            | Transaction::Resolve { id, .. }
+            | Transaction::Chargeback { id, .. }
+            | Transaction::CancelDispute { id, .. } => Ok(self
+                .transaction_currencies
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| currency.to_string())),
+            Transaction::Freeze { .. } | Transaction::Unfreeze { .. } => Ok(currency.to_string()),
+        }
+    }
+
+    pub fn manager(&self, currency: &str) -> Option<&TransactionManager> {
+        self.managers.get(currency)
+    }
+
+    // Every client balance, tagged with its currency, ordered by currency.
+    pub fn balances(&self) -> Vec<ClientBalance> {
+        self.managers
+            .iter()
+            .flat_map(|(currency, manager)| {
+                manager.balances().into_iter().map(|balance| ClientBalance {
+                    currency: Some(currency.clone()),
+                    ..balance
+                })
+            })
+            .collect()
+    }
+
+    // The engine for each currency seen, ordered by currency.
+    pub fn into_managers(self) -> Vec<(Currency, TransactionManager)> {
+        self.managers.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+
+    fn deposit(id: TransactionId, amount: i64) -> Transaction {
+        Transaction::Deposit {
+            id,
+            client_id: 1,
+            amount_base_units: Money::from_base_units(amount),
+        }
+    }
+
+    fn ledger() -> CurrencyLedger {
+        CurrencyLedger::new("USD", TransactionManager::new(), TransactionManager::new)
+    }
+
+    #[test]
+    fn test_separate_balances_per_currency() {
+        let mut ledger = ledger();
+
+        ledger.accept(deposit(1, 10_000), None).unwrap();
+        ledger.accept(deposit(2, 25_000), Some("EUR")).unwrap();
+        ledger.accept(deposit(3, 5_000), Some("USD")).unwrap();
+        ledger
+            .accept(
+                Transaction::Withdrawal {
+                    id: 4,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(20_000),
+                },
+                Some("EUR"),
+            )
+            .unwrap();
+
+        let balances = ledger.balances();
+
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[0].currency.as_deref(), Some("EUR"));
+        assert_eq!(balances[0].total, 0.5);
+        assert_eq!(balances[1].currency.as_deref(), Some("USD"));
+        assert_eq!(balances[1].total, 1.5);
+
+        // Each currency's funds are checked separately.
+        assert_eq!(
+            ledger.accept(
+                Transaction::Withdrawal {
+                    id: 5,
+                    client_id: 1,
+                    amount_base_units: Money::from_base_units(10_000),
+                },
+                Some("EUR"),
+            ),
+            Err(TransactionError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_disputes_follow_transaction_currency() {
+        let mut ledger = ledger();
+
+        ledger.accept(deposit(1, 10_000), None).unwrap();
+        ledger.accept(deposit(2, 25_000), Some("EUR")).unwrap();
+
+        // The dispute names no currency but is routed to the deposit's.
+        ledger
+            .accept(
+                Transaction::Dispute {
+                    id: 2,
+                    client_id: 1,
+                },
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(ledger.manager("EUR").unwrap().balances()[0].held, 2.5);
+        assert_eq!(ledger.manager("USD").unwrap().balances()[0].held, 0.0);
+
+        // Transaction ids are unique across currencies.
+        assert_eq!(
+            ledger.accept(deposit(1, 10_000), Some("EUR")),
+            Err(TransactionError::DuplicateTransaction)
+        );
+    }
+}
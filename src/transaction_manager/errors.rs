@@ -1,25 +1,151 @@
 use thiserror::Error;
 
-use super::transaction::TransactionStatus;
+use super::transaction::{ClientId, TransactionId, TransactionStatus};
+use crate::money::Money;
 
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum TransactionError {
     #[error("Insufficient funds")]
     InsufficientFunds,
+    #[error("Account is locked")]
+    AccountLocked,
+    #[error("Client id is reserved")]
+    ReservedClientId,
+    #[error("Maximum number of clients reached")]
+    TooManyClients,
     #[error("Duplicate transaction")]
     DuplicateTransaction,
+    #[error("Duplicate transaction conflicts with the original")]
+    ConflictingDuplicate,
     #[error("Transaction amount is negative")]
     AmountIsNegative,
+    #[error("Transaction amount is zero")]
+    AmountIsZero,
+    #[error("Amount is required for {0}")]
+    AmountRequired(String),
+    #[error("Amount is not allowed for {0}")]
+    AmountNotAllowed(String),
+    #[error("Unknown transaction type: {0}")]
+    UnknownTransactionType(String),
+    #[error("Transaction amount exceeds the configured maximum")]
+    AmountExceedsLimit,
+    #[error("Deposit amount is below the configured minimum")]
+    BelowMinimumDeposit,
+    #[error("Transaction id exceeds the configured maximum")]
+    TransactionIdOutOfRange,
     #[error("Invalid transaction state transition: {0} -> {1}")]
     InvalidStateTransition(TransactionStatus, TransactionStatus),
+    #[error("Transaction has already been resolved")]
+    AlreadyResolved,
+    #[error("Transaction has already been charged back")]
+    AlreadyChargedBack,
+    #[error("Transaction {0} exists in both engines being merged")]
+    MergeConflict(TransactionId),
+    #[error("Balance total {total} does not equal available {available} plus held {held}")]
+    BalanceInvariantViolated {
+        available: Money,
+        held: Money,
+        total: Money,
+    },
+    #[error("Transaction history is not enabled")]
+    HistoryNotEnabled,
+    #[error("Snapshot could not be read: {0}")]
+    InvalidSnapshot(String),
+    #[error("Row could not be read: {0}")]
+    InvalidRow(String),
+    #[error("No transaction to undo")]
+    NothingToUndo,
+    #[error("Cannot undo a transaction that has been disputed")]
+    UndoDisputedTransaction,
+    #[error("Cannot replace a transaction that has been disputed")]
+    ReplaceDisputedTransaction,
     #[error("Disputed transaction not found")]
     DisputedTransactionNotFound,
-    #[error("Dispute does not match client")]
-    DisputeClientMismatch,
+    #[error("Withdrawal awaiting settlement not found")]
+    PendingWithdrawalNotFound,
+    #[error("Settle withdrawal does not match client: expected client {expected}, got {actual}")]
+    SettleWithdrawalClientMismatch {
+        expected: ClientId,
+        actual: ClientId,
+    },
+    #[error("Dispute does not match client: expected client {expected}, got {actual}")]
+    DisputeClientMismatch {
+        expected: ClientId,
+        actual: ClientId,
+    },
+    #[error("Dispute would exceed the client's held funds cap")]
+    HeldCapExceeded,
     #[error("Dispute withdrawal not supported")]
     DisputeWithdrawalNotSupported,
-    #[error("Resolve does not match client")]
-    ResolveClientMismatch,
-    #[error("Chargeback does not match client")]
-    ChargebackClientMismatch,
+    #[error("Dispute adjustment not supported")]
+    DisputeAdjustmentNotSupported,
+    #[error("Dispute force deposit not supported")]
+    DisputeForceDepositNotSupported,
+    #[error("Transaction has been disputed the maximum number of times")]
+    DisputeLimitReached,
+    #[error("Split resolve amounts do not sum to the disputed amount")]
+    SplitAmountMismatch,
+    #[error("Resolve does not match client: expected client {expected}, got {actual}")]
+    ResolveClientMismatch {
+        expected: ClientId,
+        actual: ClientId,
+    },
+    #[error("Chargeback does not match client: expected client {expected}, got {actual}")]
+    ChargebackClientMismatch {
+        expected: ClientId,
+        actual: ClientId,
+    },
+    #[error("Cancel dispute does not match client: expected client {expected}, got {actual}")]
+    CancelDisputeClientMismatch {
+        expected: ClientId,
+        actual: ClientId,
+    },
+}
+
+impl TransactionError {
+    // The variant's name without any fields, e.g. `InsufficientFunds`, as counted in error summaries.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionError::InsufficientFunds => "InsufficientFunds",
+            TransactionError::AccountLocked => "AccountLocked",
+            TransactionError::ReservedClientId => "ReservedClientId",
+            TransactionError::TooManyClients => "TooManyClients",
+            TransactionError::DuplicateTransaction => "DuplicateTransaction",
+            TransactionError::ConflictingDuplicate => "ConflictingDuplicate",
+            TransactionError::AmountIsNegative => "AmountIsNegative",
+            TransactionError::AmountIsZero => "AmountIsZero",
+            TransactionError::AmountRequired(..) => "AmountRequired",
+            TransactionError::AmountNotAllowed(..) => "AmountNotAllowed",
+            TransactionError::UnknownTransactionType(..) => "UnknownTransactionType",
+            TransactionError::AmountExceedsLimit => "AmountExceedsLimit",
+            TransactionError::BelowMinimumDeposit => "BelowMinimumDeposit",
+            TransactionError::TransactionIdOutOfRange => "TransactionIdOutOfRange",
+            TransactionError::InvalidStateTransition(..) => "InvalidStateTransition",
+            TransactionError::AlreadyResolved => "AlreadyResolved",
+            TransactionError::AlreadyChargedBack => "AlreadyChargedBack",
+            TransactionError::MergeConflict(..) => "MergeConflict",
+            TransactionError::BalanceInvariantViolated { .. } => "BalanceInvariantViolated",
+            TransactionError::HistoryNotEnabled => "HistoryNotEnabled",
+            TransactionError::InvalidSnapshot(..) => "InvalidSnapshot",
+            TransactionError::InvalidRow(..) => "InvalidRow",
+            TransactionError::NothingToUndo => "NothingToUndo",
+            TransactionError::UndoDisputedTransaction => "UndoDisputedTransaction",
+            TransactionError::ReplaceDisputedTransaction => "ReplaceDisputedTransaction",
+            TransactionError::DisputedTransactionNotFound => "DisputedTransactionNotFound",
+            TransactionError::PendingWithdrawalNotFound => "PendingWithdrawalNotFound",
+            TransactionError::SettleWithdrawalClientMismatch { .. } => {
+                "SettleWithdrawalClientMismatch"
+            }
+            TransactionError::DisputeClientMismatch { .. } => "DisputeClientMismatch",
+            TransactionError::HeldCapExceeded => "HeldCapExceeded",
+            TransactionError::DisputeWithdrawalNotSupported => "DisputeWithdrawalNotSupported",
+            TransactionError::DisputeAdjustmentNotSupported => "DisputeAdjustmentNotSupported",
+            TransactionError::DisputeForceDepositNotSupported => "DisputeForceDepositNotSupported",
+            TransactionError::DisputeLimitReached => "DisputeLimitReached",
+            TransactionError::SplitAmountMismatch => "SplitAmountMismatch",
+            TransactionError::ResolveClientMismatch { .. } => "ResolveClientMismatch",
+            TransactionError::ChargebackClientMismatch { .. } => "ChargebackClientMismatch",
+            TransactionError::CancelDisputeClientMismatch { .. } => "CancelDisputeClientMismatch",
+        }
+    }
 }
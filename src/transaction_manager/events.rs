@@ -0,0 +1,74 @@
+use super::{balance::Balance, transaction::ClientId};
+use crate::money::Money;
+
+// The balance amounts reported by BalanceChange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BalanceField {
+    Available,
+    Held,
+    Total,
+}
+
+// A change to one amount of a client's balance, e.g. for pushing live updates to a UI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceChange {
+    pub client_id: ClientId,
+    pub field: BalanceField,
+    pub old: Money,
+    pub new: Money,
+}
+
+// A client's balance at one point in time, e.g. before a transaction, compared against afterwards to find
+// what changed, or an entry of TransactionManager::balance_timeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BalanceSnapshot {
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+}
+
+impl BalanceSnapshot {
+    pub fn of(balance: &Balance) -> Self {
+        Self {
+            available: balance.available(),
+            held: balance.held(),
+            total: balance.total(),
+            locked: balance.locked(),
+        }
+    }
+
+    pub fn available(&self) -> Money {
+        self.available
+    }
+
+    pub fn held(&self) -> Money {
+        self.held
+    }
+
+    pub fn total(&self) -> Money {
+        self.total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    // The changes from this snapshot to `after`, in the order available, held, total.
+    pub fn changes(self, client_id: ClientId, after: BalanceSnapshot) -> Vec<BalanceChange> {
+        [
+            (BalanceField::Available, self.available, after.available),
+            (BalanceField::Held, self.held, after.held),
+            (BalanceField::Total, self.total, after.total),
+        ]
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| BalanceChange {
+            client_id,
+            field,
+            old,
+            new,
+        })
+        .collect()
+    }
+}
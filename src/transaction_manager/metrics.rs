@@ -0,0 +1,153 @@
+use super::transaction::Transaction;
+use std::{fmt, time::Duration};
+
+// The kinds of operation timed by EngineMetrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Deposit,
+    Withdrawal,
+    SettleWithdrawal,
+    Adjustment,
+    ForceDeposit,
+    Dispute,
+    Resolve,
+    Chargeback,
+    CancelDispute,
+    Freeze,
+    Unfreeze,
+}
+
+impl From<&Transaction> for Operation {
+    fn from(transaction: &Transaction) -> Self {
+        match transaction {
+            Transaction::Deposit { .. } => Operation::Deposit,
+            Transaction::Withdrawal { .. } => Operation::Withdrawal,
+            Transaction::SettleWithdrawal { .. } => Operation::SettleWithdrawal,
+            Transaction::Adjustment { .. } => Operation::Adjustment,
+            Transaction::ForceDeposit { .. } => Operation::ForceDeposit,
+            Transaction::Dispute { .. } => Operation::Dispute,
+            Transaction::Resolve { .. } => Operation::Resolve,
+            Transaction::Chargeback { .. } => Operation::Chargeback,
+            Transaction::CancelDispute { .. } => Operation::CancelDispute,
+            Transaction::Freeze { .. } => Operation::Freeze,
+            Transaction::Unfreeze { .. } => Operation::Unfreeze,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OperationMetrics {
+    // Number of transactions processed, including rejected ones.
+    pub count: u64,
+    pub rejected: u64,
+    // Cumulative time spent applying the transactions.
+    pub duration: Duration,
+}
+
+impl OperationMetrics {
+    pub fn merge(&mut self, other: &OperationMetrics) {
+        self.count += other.count;
+        self.rejected += other.rejected;
+        self.duration += other.duration;
+    }
+
+    // Mean time per transaction.
+    pub fn average(&self) -> Duration {
+        match u32::try_from(self.count) {
+            Ok(0) => Duration::ZERO,
+            Ok(count) => self.duration / count,
+            Err(_) => Duration::from_secs_f64(self.duration.as_secs_f64() / self.count as f64),
+        }
+    }
+}
+
+// Processing counts and timings per operation type.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineMetrics {
+    pub deposits: OperationMetrics,
+    pub withdrawals: OperationMetrics,
+    pub settled_withdrawals: OperationMetrics,
+    pub adjustments: OperationMetrics,
+    pub force_deposits: OperationMetrics,
+    pub disputes: OperationMetrics,
+    pub resolves: OperationMetrics,
+    pub chargebacks: OperationMetrics,
+    pub cancelled_disputes: OperationMetrics,
+    pub freezes: OperationMetrics,
+    pub unfreezes: OperationMetrics,
+}
+
+impl EngineMetrics {
+    pub fn merge(&mut self, other: &EngineMetrics) {
+        self.deposits.merge(&other.deposits);
+        self.withdrawals.merge(&other.withdrawals);
+        self.settled_withdrawals.merge(&other.settled_withdrawals);
+        self.adjustments.merge(&other.adjustments);
+        self.force_deposits.merge(&other.force_deposits);
+        self.disputes.merge(&other.disputes);
+        self.resolves.merge(&other.resolves);
+        self.chargebacks.merge(&other.chargebacks);
+        self.cancelled_disputes.merge(&other.cancelled_disputes);
+        self.freezes.merge(&other.freezes);
+        self.unfreezes.merge(&other.unfreezes);
+    }
+
+    pub fn record(&mut self, operation: Operation, duration: Duration, accepted: bool) {
+        let metrics = match operation {
+            Operation::Deposit => &mut self.deposits,
+            Operation::Withdrawal => &mut self.withdrawals,
+            Operation::SettleWithdrawal => &mut self.settled_withdrawals,
+            Operation::Adjustment => &mut self.adjustments,
+            Operation::ForceDeposit => &mut self.force_deposits,
+            Operation::Dispute => &mut self.disputes,
+            Operation::Resolve => &mut self.resolves,
+            Operation::Chargeback => &mut self.chargebacks,
+            Operation::CancelDispute => &mut self.cancelled_disputes,
+            Operation::Freeze => &mut self.freezes,
+            Operation::Unfreeze => &mut self.unfreezes,
+        };
+
+        metrics.count += 1;
+        metrics.duration += duration;
+
+        if !accepted {
+            metrics.rejected += 1;
+        }
+    }
+}
+
+impl fmt::Display for EngineMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operations = [
+            ("deposit", &self.deposits),
+            ("withdrawal", &self.withdrawals),
+            ("settle_withdrawal", &self.settled_withdrawals),
+            ("adjustment", &self.adjustments),
+            ("force_deposit", &self.force_deposits),
+            ("dispute", &self.disputes),
+            ("resolve", &self.resolves),
+            ("chargeback", &self.chargebacks),
+            ("cancel_dispute", &self.cancelled_disputes),
+            ("freeze", &self.freezes),
+            ("unfreeze", &self.unfreezes),
+        ];
+
+        for (index, (name, metrics)) in operations.into_iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            write!(
+                f,
+                "{}: count={} rejected={} total={:?} average={:?}",
+                name,
+                metrics.count,
+                metrics.rejected,
+                metrics.duration,
+                metrics.average()
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use crate::money::{Money, RoundingError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Engine-wide running totals, tracked independently of the client balances so the two
+// can be reconciled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Totals {
+    opening: Money,
+    deposited: Money,
+    withdrawn: Money,
+    // Net of all manual adjustments, positive when clients were credited overall.
+    adjusted: Money,
+    fees: Money,
+    held: Money,
+    charged_back: Money,
+    // Net effect on client balances of rounding input amounts. A fraction of a base unit per transaction,
+    // so kept as a RoundingError rather than Money.
+    rounding_error: RoundingError,
+}
+
+impl Totals {
+    pub fn open(&mut self, available: Money, held: Money) {
+        self.opening += available + held;
+        self.held += held;
+    }
+
+    pub fn deposit(&mut self, amount: Money) {
+        self.deposited += amount;
+    }
+
+    pub fn withdrawal(&mut self, amount: Money) {
+        self.withdrawn += amount;
+    }
+
+    pub fn adjust(&mut self, amount: Money) {
+        self.adjusted += amount;
+    }
+
+    pub fn fee(&mut self, amount: Money) {
+        self.fees += amount;
+    }
+
+    pub fn fees(&self) -> Money {
+        self.fees
+    }
+
+    pub fn record_rounding_error(&mut self, rounding_error: RoundingError) {
+        self.rounding_error += rounding_error;
+    }
+
+    pub fn rounding_error(&self) -> RoundingError {
+        self.rounding_error
+    }
+
+    pub fn hold(&mut self, amount: Money) {
+        self.held += amount;
+    }
+
+    pub fn release(&mut self, amount: Money) {
+        self.held -= amount;
+    }
+
+    pub fn chargeback(&mut self, amount: Money) {
+        self.held -= amount;
+        self.charged_back += amount;
+    }
+
+    pub fn merge(&mut self, other: &Totals) {
+        self.opening += other.opening;
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.adjusted += other.adjusted;
+        self.fees += other.fees;
+        self.held += other.held;
+        self.charged_back += other.charged_back;
+        self.rounding_error += other.rounding_error;
+    }
+
+    pub fn report(&self, balances_total: Money, balances_held: Money) -> ConsistencyReport {
+        ConsistencyReport {
+            total_opening: self.opening,
+            total_deposited: self.deposited,
+            total_withdrawn: self.withdrawn,
+            total_adjusted: self.adjusted,
+            total_fees: self.fees,
+            total_charged_back: self.charged_back,
+            total_rounding_error: self.rounding_error,
+            expected_held: self.held,
+            balances_total,
+            balances_held,
+        }
+    }
+}
+
+// Compares the sum of all client balances against the money that flowed through the engine.
+#[derive(Debug, PartialEq)]
+pub struct ConsistencyReport {
+    pub total_opening: Money,
+    pub total_deposited: Money,
+    pub total_withdrawn: Money,
+    pub total_adjusted: Money,
+    pub total_fees: Money,
+    pub total_charged_back: Money,
+    // Informational only: the balances already hold the rounded amounts, so it plays no part in
+    // is_consistent.
+    pub total_rounding_error: RoundingError,
+    pub expected_held: Money,
+    pub balances_total: Money,
+    pub balances_held: Money,
+}
+
+impl ConsistencyReport {
+    // Chargebacks remove funds even when they drive a balance negative, so they are subtracted in full.
+    pub fn expected_total(&self) -> Money {
+        self.total_opening + self.total_deposited - self.total_withdrawn + self.total_adjusted
+            - self.total_fees
+            - self.total_charged_back
+    }
+
+    // The sum of client balances less the net flows, zero unless the engine has a bug.
+    pub fn discrepancy(&self) -> Money {
+        self.balances_total - self.expected_total()
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.balances_total == self.expected_total() && self.balances_held == self.expected_held
+    }
+}
+
+impl fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "opening={} deposited={} withdrawn={} adjusted={} fees={} charged_back={} rounding_error={} expected_total={} balances_total={} expected_held={} balances_held={} consistent={}",
+            self.total_opening,
+            self.total_deposited,
+            self.total_withdrawn,
+            self.total_adjusted,
+            self.total_fees,
+            self.total_charged_back,
+            self.total_rounding_error,
+            self.expected_total(),
+            self.balances_total,
+            self.expected_held,
+            self.balances_held,
+            self.is_consistent()
+        )
+    }
+}
@@ -1,17 +1,101 @@
 use super::errors::TransactionError;
+use crate::money::{Money, RoundingError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 
 pub type ClientId = u16;
 
+// When a transaction happened according to its source, normalized to UTC.
+pub type Timestamp = DateTime<Utc>;
+
+#[cfg(not(feature = "wide-tx-id"))]
 pub type TransactionId = u32;
 
-#[derive(Debug, PartialEq)]
+// Transaction ids wider than u32 for feeds that need them.
+#[cfg(feature = "wide-tx-id")]
+pub type TransactionId = u64;
+
+#[derive(Debug)]
+pub enum OrderType {
+    Deposit,
+    Withdrawal,
+    SettleWithdrawal,
+    Adjustment,
+    ForceDeposit,
+    Dispute,
+    Resolve,
+    Chargeback,
+    CancelDispute,
+    Freeze,
+    Unfreeze,
+    // A type naming none of the above, e.g. a misspelling such as `deposite`. Read rather than failing the
+    // row so the value can be reported as an unknown transaction type.
+    Unknown(String),
+}
+
+impl OrderType {
+    // The name used in the type column, e.g. `settle_withdrawal`.
+    fn name(&self) -> &str {
+        match self {
+            OrderType::Deposit => "deposit",
+            OrderType::Withdrawal => "withdrawal",
+            OrderType::SettleWithdrawal => "settle_withdrawal",
+            OrderType::Adjustment => "adjustment",
+            OrderType::ForceDeposit => "force_deposit",
+            OrderType::Dispute => "dispute",
+            OrderType::Resolve => "resolve",
+            OrderType::Chargeback => "chargeback",
+            OrderType::CancelDispute => "cancel_dispute",
+            OrderType::Freeze => "freeze",
+            OrderType::Unfreeze => "unfreeze",
+            OrderType::Unknown(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for OrderType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for OrderType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+
+        Ok(match value.as_str() {
+            "deposit" => OrderType::Deposit,
+            "withdrawal" => OrderType::Withdrawal,
+            "settle_withdrawal" => OrderType::SettleWithdrawal,
+            "adjustment" => OrderType::Adjustment,
+            "force_deposit" => OrderType::ForceDeposit,
+            "dispute" => OrderType::Dispute,
+            "resolve" => OrderType::Resolve,
+            "chargeback" => OrderType::Chargeback,
+            "cancel_dispute" => OrderType::CancelDispute,
+            "freeze" => OrderType::Freeze,
+            "unfreeze" => OrderType::Unfreeze,
+            _ => OrderType::Unknown(value),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
+    Adjustment,
+    ForceDeposit,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum TransactionStatus {
     Valid,
     Disputed,
@@ -30,16 +114,17 @@ impl fmt::Display for TransactionStatus {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Transaction {
     Deposit {
         id: TransactionId,
         client_id: ClientId,
-        amount_base_units: i64,
+        amount_base_units: Money,
     },
     Withdrawal {
         id: TransactionId,
         client_id: ClientId,
-        amount_base_units: i64,
+        amount_base_units: Money,
     },
     Dispute {
         id: TransactionId,
@@ -53,16 +138,210 @@ pub enum Transaction {
         id: TransactionId,
         client_id: ClientId,
     },
+    // Finalizes a withdrawal whose funds were held under TransactionManager::with_withdrawal_holds.
+    SettleWithdrawal {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    // A manual correction posted by an operator. A positive amount credits the client and a negative amount
+    // debits them.
+    Adjustment {
+        id: TransactionId,
+        client_id: ClientId,
+        amount_base_units: Money,
+    },
+    // An administrative deposit posted by an operator, e.g. to reverse an erroneous chargeback. Unlike a
+    // Deposit it is accepted on a locked account, and it cannot be disputed.
+    ForceDeposit {
+        id: TransactionId,
+        client_id: ClientId,
+        amount_base_units: Money,
+    },
+    // The client withdrawing their own dispute, as opposed to a Resolve which settles it in their favour.
+    CancelDispute {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    // Administrative lock on the client's account, independent of any chargeback.
+    Freeze {
+        client_id: ClientId,
+    },
+    Unfreeze {
+        client_id: ClientId,
+    },
 }
 
-#[derive(Debug)]
+impl Transaction {
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            Transaction::Deposit { client_id, .. }
+            | Transaction::Withdrawal { client_id, .. }
+            | Transaction::SettleWithdrawal { client_id, .. }
+            | Transaction::Adjustment { client_id, .. }
+            | Transaction::ForceDeposit { client_id, .. }
+            | Transaction::Dispute { client_id, .. }
+            | Transaction::Resolve { client_id, .. }
+            | Transaction::Chargeback { client_id, .. }
+            | Transaction::CancelDispute { client_id, .. }
+            | Transaction::Freeze { client_id }
+            | Transaction::Unfreeze { client_id } => *client_id,
+        }
+    }
+
+    // Builds the variant for the order type from raw fields, e.g. for property-based tests. The amount, in
+    // base units, is required for deposits, withdrawals, force deposits and adjustments and must not be
+    // given for any other type. Amounts must not be zero, and only adjustments may be negative.
+    // Account-level operations ignore the id.
+    pub fn from_parts(
+        order_type: OrderType,
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Option<i64>,
+    ) -> Result<Transaction, TransactionError> {
+        let required = |signed: bool| match amount {
+            None => Err(TransactionError::AmountRequired(order_type.to_string())),
+            Some(0) => Err(TransactionError::AmountIsZero),
+            Some(amount) if amount < 0 && !signed => Err(TransactionError::AmountIsNegative),
+            Some(amount) => Ok(Money::from_base_units(amount)),
+        };
+
+        let transaction = match &order_type {
+            OrderType::Deposit => Transaction::Deposit {
+                id,
+                client_id,
+                amount_base_units: required(false)?,
+            },
+            OrderType::Withdrawal => Transaction::Withdrawal {
+                id,
+                client_id,
+                amount_base_units: required(false)?,
+            },
+            OrderType::ForceDeposit => Transaction::ForceDeposit {
+                id,
+                client_id,
+                amount_base_units: required(false)?,
+            },
+            OrderType::Adjustment => Transaction::Adjustment {
+                id,
+                client_id,
+                amount_base_units: required(true)?,
+            },
+            OrderType::SettleWithdrawal => Transaction::SettleWithdrawal { id, client_id },
+            OrderType::Dispute => Transaction::Dispute { id, client_id },
+            OrderType::Resolve => Transaction::Resolve { id, client_id },
+            OrderType::Chargeback => Transaction::Chargeback { id, client_id },
+            OrderType::CancelDispute => Transaction::CancelDispute { id, client_id },
+            OrderType::Freeze => Transaction::Freeze { client_id },
+            OrderType::Unfreeze => Transaction::Unfreeze { client_id },
+            OrderType::Unknown(value) => {
+                return Err(TransactionError::UnknownTransactionType(value.clone()))
+            }
+        };
+
+        if amount.is_some() && !transaction.has_amount() {
+            return Err(TransactionError::AmountNotAllowed(order_type.to_string()));
+        }
+
+        Ok(transaction)
+    }
+
+    fn has_amount(&self) -> bool {
+        matches!(
+            self,
+            Transaction::Deposit { .. }
+                | Transaction::Withdrawal { .. }
+                | Transaction::Adjustment { .. }
+                | Transaction::ForceDeposit { .. }
+        )
+    }
+
+    // Whether the transaction is posted by an operator rather than on the client's behalf.
+    pub fn is_administrative(&self) -> bool {
+        matches!(
+            self,
+            Transaction::Adjustment { .. }
+                | Transaction::ForceDeposit { .. }
+                | Transaction::Freeze { .. }
+                | Transaction::Unfreeze { .. }
+        )
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transaction::Deposit {
+                id,
+                client_id,
+                amount_base_units,
+            } => write!(
+                f,
+                "Deposit(tx={}, client={}, amount={})",
+                id, client_id, amount_base_units
+            ),
+            Transaction::Withdrawal {
+                id,
+                client_id,
+                amount_base_units,
+            } => write!(
+                f,
+                "Withdrawal(tx={}, client={}, amount={})",
+                id, client_id, amount_base_units
+            ),
+            Transaction::SettleWithdrawal { id, client_id } => {
+                write!(f, "SettleWithdrawal(tx={}, client={})", id, client_id)
+            }
+            Transaction::Adjustment {
+                id,
+                client_id,
+                amount_base_units,
+            } => write!(
+                f,
+                "Adjustment(tx={}, client={}, amount={})",
+                id, client_id, amount_base_units
+            ),
+            Transaction::ForceDeposit {
+                id,
+                client_id,
+                amount_base_units,
+            } => write!(
+                f,
+                "ForceDeposit(tx={}, client={}, amount={})",
+                id, client_id, amount_base_units
+            ),
+            Transaction::Dispute { id, client_id } => {
+                write!(f, "Dispute(tx={}, client={})", id, client_id)
+            }
+            Transaction::Resolve { id, client_id } => {
+                write!(f, "Resolve(tx={}, client={})", id, client_id)
+            }
+            Transaction::Chargeback { id, client_id } => {
+                write!(f, "Chargeback(tx={}, client={})", id, client_id)
+            }
+            Transaction::CancelDispute { id, client_id } => {
+                write!(f, "CancelDispute(tx={}, client={})", id, client_id)
+            }
+            Transaction::Freeze { client_id } => write!(f, "Freeze(client={})", client_id),
+            Transaction::Unfreeze { client_id } => write!(f, "Unfreeze(client={})", client_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(not(test), allow(dead_code))]
 pub struct TransactionState {
     transaction_type: TransactionType,
     id: TransactionId,
     client_id: ClientId,
-    amount_base_units: i64,
+    amount_base_units: Money,
     status: TransactionStatus,
+    timestamp: Option<Timestamp>,
+    // Number of disputes raised and not cancelled, counted against the dispute limit.
+    disputes: u32,
+    // Amount requested but not withdrawn under with_partial_withdrawals, so an undo can reverse it.
+    shortfall: Money,
+    // Effect on the client's balance of rounding the input amount, so an undo or replacement can reverse it.
+    rounding_error: RoundingError,
 }
 
 impl TransactionState {
@@ -70,9 +349,10 @@ impl TransactionState {
         transaction_type: TransactionType,
         id: TransactionId,
         client_id: ClientId,
-        amount: i64,
+        amount: Money,
     ) -> Result<TransactionState, TransactionError> {
-        if amount < 0 {
+        // Adjustments are signed, every other amount must not be negative.
+        if amount.is_negative() && transaction_type != TransactionType::Adjustment {
             return Err(TransactionError::AmountIsNegative);
         }
 
@@ -82,10 +362,36 @@ impl TransactionState {
             client_id,
             amount_base_units: amount,
             status: TransactionStatus::Valid,
+            timestamp: None,
+            disputes: 0,
+            shortfall: Money::ZERO,
+            rounding_error: RoundingError::ZERO,
         })
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_timestamp(mut self, timestamp: Option<Timestamp>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_shortfall(mut self, shortfall: Money) -> Self {
+        self.shortfall = shortfall;
+        self
+    }
+
+    pub fn shortfall(&self) -> Money {
+        self.shortfall
+    }
+
+    pub fn with_rounding_error(mut self, rounding_error: RoundingError) -> Self {
+        self.rounding_error = rounding_error;
+        self
+    }
+
+    pub fn rounding_error(&self) -> RoundingError {
+        self.rounding_error
+    }
+
     pub fn transaction_type(&self) -> &TransactionType {
         &self.transaction_type
     }
@@ -98,34 +404,71 @@ impl TransactionState {
         self.client_id
     }
 
-    pub fn amount(&self) -> i64 {
+    pub fn amount(&self) -> Money {
         self.amount_base_units
     }
 
-    #[cfg_attr(not(test), allow(dead_code))]
     pub fn status(&self) -> &TransactionStatus {
         &self.status
     }
 
-    pub fn dispute(&mut self) -> Result<(), TransactionError> {
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    // Disputes a valid transaction, or a resolved one that has been disputed fewer than max_disputes times.
+    pub fn dispute(&mut self, max_disputes: u32) -> Result<(), TransactionError> {
+        self.check_dispute(max_disputes)?;
+
+        self.disputes += 1;
+        self.status = TransactionStatus::Disputed;
+
+        Ok(())
+    }
+
+    // Checks the transaction can be disputed without changing its status.
+    pub fn check_dispute(&self, max_disputes: u32) -> Result<(), TransactionError> {
         if let TransactionType::Withdrawal = self.transaction_type() {
             // Disputing withdrawals is currently not supported. It is not clear what should happen in this case.
             return Err(TransactionError::DisputeWithdrawalNotSupported);
         }
 
-        if self.status != TransactionStatus::Valid {
-            return Err(TransactionError::InvalidStateTransition(
+        if let TransactionType::Adjustment = self.transaction_type() {
+            // Adjustments are corrections by an operator rather than client payments, so cannot be disputed.
+            return Err(TransactionError::DisputeAdjustmentNotSupported);
+        }
+
+        if let TransactionType::ForceDeposit = self.transaction_type() {
+            // Force deposits are administrative, like adjustments.
+            return Err(TransactionError::DisputeForceDepositNotSupported);
+        }
+
+        match self.status {
+            TransactionStatus::Valid | TransactionStatus::Resolved
+                if self.disputes >= max_disputes =>
+            {
+                Err(TransactionError::DisputeLimitReached)
+            }
+            TransactionStatus::Valid | TransactionStatus::Resolved => Ok(()),
+            _ => Err(TransactionError::InvalidStateTransition(
                 self.status.clone(),
                 TransactionStatus::Disputed,
-            ));
+            )),
         }
+    }
 
-        self.status = TransactionStatus::Disputed;
-
-        Ok(())
+    // Whether the transaction can never be disputed again, so need not be kept.
+    pub fn is_finalized(&self, max_disputes: u32) -> bool {
+        match self.status {
+            TransactionStatus::Chargeback => true,
+            TransactionStatus::Resolved => self.disputes >= max_disputes,
+            _ => false,
+        }
     }
 
     pub fn resolve(&mut self) -> Result<(), TransactionError> {
+        self.check_not_finalized()?;
+
         if self.status != TransactionStatus::Disputed {
             return Err(TransactionError::InvalidStateTransition(
                 self.status.clone(),
@@ -139,6 +482,8 @@ impl TransactionState {
     }
 
     pub fn chargeback(&mut self) -> Result<(), TransactionError> {
+        self.check_not_finalized()?;
+
         if self.status != TransactionStatus::Disputed {
             return Err(TransactionError::InvalidStateTransition(
                 self.status.clone(),
@@ -150,6 +495,37 @@ impl TransactionState {
 
         Ok(())
     }
+
+    // Returns a disputed transaction to Valid, or Resolved if it had been disputed before, as if the dispute
+    // had not been raised, so it may be disputed again.
+    pub fn cancel_dispute(&mut self) -> Result<(), TransactionError> {
+        self.check_not_finalized()?;
+
+        if self.status != TransactionStatus::Disputed {
+            return Err(TransactionError::InvalidStateTransition(
+                self.status.clone(),
+                TransactionStatus::Valid,
+            ));
+        }
+
+        self.disputes -= 1;
+        self.status = if self.disputes == 0 {
+            TransactionStatus::Valid
+        } else {
+            TransactionStatus::Resolved
+        };
+
+        Ok(())
+    }
+
+    // Gives a specific error when settling a dispute that has already been settled.
+    fn check_not_finalized(&self) -> Result<(), TransactionError> {
+        match self.status {
+            TransactionStatus::Resolved => Err(TransactionError::AlreadyResolved),
+            TransactionStatus::Chargeback => Err(TransactionError::AlreadyChargedBack),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -158,17 +534,38 @@ mod tests {
 
     #[test]
     fn test_negative_amount() {
-        let error = TransactionState::new(TransactionType::Deposit, 1, 1, -1).unwrap_err();
+        let error =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(-1))
+                .unwrap_err();
         assert_eq!(error, TransactionError::AmountIsNegative);
     }
 
+    #[test]
+    fn test_adjustment_not_disputable() {
+        let mut state = TransactionState::new(
+            TransactionType::Adjustment,
+            1,
+            1,
+            Money::from_base_units(-100),
+        )
+        .unwrap();
+
+        assert_eq!(
+            state.dispute(1),
+            Err(TransactionError::DisputeAdjustmentNotSupported)
+        );
+        assert_eq!(state.status, TransactionStatus::Valid);
+    }
+
     #[test]
     fn test_dispute_resolve_state_transition() {
-        let mut state = TransactionState::new(TransactionType::Deposit, 1, 1, 100).unwrap();
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
 
         assert_eq!(state.status, TransactionStatus::Valid);
 
-        state.dispute().unwrap();
+        state.dispute(1).unwrap();
 
         assert_eq!(state.status, TransactionStatus::Disputed);
 
@@ -179,11 +576,13 @@ mod tests {
 
     #[test]
     fn test_dispute_chargeback_state_transition() {
-        let mut state = TransactionState::new(TransactionType::Deposit, 1, 1, 100).unwrap();
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
 
         assert_eq!(state.status, TransactionStatus::Valid);
 
-        state.dispute().unwrap();
+        state.dispute(1).unwrap();
 
         assert_eq!(state.status, TransactionStatus::Disputed);
 
@@ -194,7 +593,9 @@ mod tests {
 
     #[test]
     fn test_valid_resolved_fails() {
-        let mut state = TransactionState::new(TransactionType::Deposit, 1, 1, 100).unwrap();
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
 
         assert_eq!(state.status, TransactionStatus::Valid);
 
@@ -211,7 +612,9 @@ mod tests {
 
     #[test]
     fn test_valid_chargeback_fails() {
-        let mut state = TransactionState::new(TransactionType::Deposit, 1, 1, 100).unwrap();
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
 
         assert_eq!(state.status, TransactionStatus::Valid);
 
@@ -225,4 +628,270 @@ mod tests {
             ))
         ));
     }
+
+    #[test]
+    fn test_transaction_display() {
+        let deposit = Transaction::Deposit {
+            id: 5,
+            client_id: 2,
+            amount_base_units: Money::from_base_units(15_000),
+        };
+        assert_eq!(
+            deposit.to_string(),
+            "Deposit(tx=5, client=2, amount=1.5000)"
+        );
+
+        let withdrawal = Transaction::Withdrawal {
+            id: 6,
+            client_id: 2,
+            amount_base_units: Money::from_base_units(1),
+        };
+        assert_eq!(
+            withdrawal.to_string(),
+            "Withdrawal(tx=6, client=2, amount=0.0001)"
+        );
+
+        let dispute = Transaction::Dispute {
+            id: 5,
+            client_id: 2,
+        };
+        assert_eq!(dispute.to_string(), "Dispute(tx=5, client=2)");
+
+        let resolve = Transaction::Resolve {
+            id: 5,
+            client_id: 2,
+        };
+        assert_eq!(resolve.to_string(), "Resolve(tx=5, client=2)");
+
+        let chargeback = Transaction::Chargeback {
+            id: 5,
+            client_id: 2,
+        };
+        assert_eq!(chargeback.to_string(), "Chargeback(tx=5, client=2)");
+
+        let freeze = Transaction::Freeze { client_id: 2 };
+        assert_eq!(freeze.to_string(), "Freeze(client=2)");
+    }
+
+    #[test]
+    fn test_dispute_cancel_state_transition() {
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
+
+        assert!(matches!(
+            state.cancel_dispute(),
+            Err(TransactionError::InvalidStateTransition(
+                TransactionStatus::Valid,
+                TransactionStatus::Valid
+            ))
+        ));
+
+        state.dispute(1).unwrap();
+        state.cancel_dispute().unwrap();
+
+        assert_eq!(state.status, TransactionStatus::Valid);
+
+        // The transaction may be disputed again.
+        state.dispute(1).unwrap();
+        state.resolve().unwrap();
+
+        assert_eq!(
+            state.cancel_dispute(),
+            Err(TransactionError::AlreadyResolved)
+        );
+    }
+
+    #[test]
+    fn test_already_resolved() {
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
+
+        state.dispute(1).unwrap();
+        state.resolve().unwrap();
+
+        assert_eq!(state.resolve(), Err(TransactionError::AlreadyResolved));
+        assert_eq!(state.chargeback(), Err(TransactionError::AlreadyResolved));
+        assert_eq!(state.status, TransactionStatus::Resolved);
+    }
+
+    #[test]
+    fn test_already_charged_back() {
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
+
+        state.dispute(1).unwrap();
+        state.chargeback().unwrap();
+
+        assert_eq!(
+            state.chargeback(),
+            Err(TransactionError::AlreadyChargedBack)
+        );
+        assert_eq!(state.resolve(), Err(TransactionError::AlreadyChargedBack));
+        assert_eq!(state.status, TransactionStatus::Chargeback);
+    }
+
+    #[test]
+    fn test_dispute_limit() {
+        let mut state =
+            TransactionState::new(TransactionType::Deposit, 1, 1, Money::from_base_units(100))
+                .unwrap();
+
+        state.dispute(2).unwrap();
+        state.resolve().unwrap();
+
+        assert!(!state.is_finalized(2));
+
+        // A re-dispute that is cancelled returns to Resolved and does not count.
+        state.dispute(2).unwrap();
+        state.cancel_dispute().unwrap();
+
+        assert_eq!(state.status, TransactionStatus::Resolved);
+        assert_eq!(state.disputes, 1);
+
+        state.dispute(2).unwrap();
+        state.resolve().unwrap();
+
+        assert!(state.is_finalized(2));
+        assert_eq!(state.dispute(2), Err(TransactionError::DisputeLimitReached));
+        assert_eq!(state.status, TransactionStatus::Resolved);
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let amount = Money::from_base_units(100);
+
+        let valid = [
+            (
+                OrderType::Deposit,
+                Some(100),
+                Transaction::Deposit {
+                    id: 1,
+                    client_id: 2,
+                    amount_base_units: amount,
+                },
+            ),
+            (
+                OrderType::Withdrawal,
+                Some(100),
+                Transaction::Withdrawal {
+                    id: 1,
+                    client_id: 2,
+                    amount_base_units: amount,
+                },
+            ),
+            (
+                OrderType::ForceDeposit,
+                Some(100),
+                Transaction::ForceDeposit {
+                    id: 1,
+                    client_id: 2,
+                    amount_base_units: amount,
+                },
+            ),
+            (
+                OrderType::Adjustment,
+                Some(-100),
+                Transaction::Adjustment {
+                    id: 1,
+                    client_id: 2,
+                    amount_base_units: -amount,
+                },
+            ),
+            (
+                OrderType::SettleWithdrawal,
+                None,
+                Transaction::SettleWithdrawal {
+                    id: 1,
+                    client_id: 2,
+                },
+            ),
+            (
+                OrderType::Dispute,
+                None,
+                Transaction::Dispute {
+                    id: 1,
+                    client_id: 2,
+                },
+            ),
+            (
+                OrderType::Resolve,
+                None,
+                Transaction::Resolve {
+                    id: 1,
+                    client_id: 2,
+                },
+            ),
+            (
+                OrderType::Chargeback,
+                None,
+                Transaction::Chargeback {
+                    id: 1,
+                    client_id: 2,
+                },
+            ),
+            (
+                OrderType::CancelDispute,
+                None,
+                Transaction::CancelDispute {
+                    id: 1,
+                    client_id: 2,
+                },
+            ),
+            (
+                OrderType::Freeze,
+                None,
+                Transaction::Freeze { client_id: 2 },
+            ),
+            (
+                OrderType::Unfreeze,
+                None,
+                Transaction::Unfreeze { client_id: 2 },
+            ),
+        ];
+
+        for (order_type, amount, expected) in valid {
+            assert_eq!(
+                Transaction::from_parts(order_type, 1, 2, amount),
+                Ok(expected)
+            );
+        }
+
+        assert_eq!(
+            Transaction::from_parts(OrderType::Deposit, 1, 2, None),
+            Err(TransactionError::AmountRequired("deposit".to_string()))
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Adjustment, 1, 2, None),
+            Err(TransactionError::AmountRequired("adjustment".to_string()))
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Withdrawal, 1, 2, Some(-1)),
+            Err(TransactionError::AmountIsNegative)
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Deposit, 1, 2, Some(0)),
+            Err(TransactionError::AmountIsZero)
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Adjustment, 1, 2, Some(0)),
+            Err(TransactionError::AmountIsZero)
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Dispute, 1, 2, Some(100)),
+            Err(TransactionError::AmountNotAllowed("dispute".to_string()))
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Freeze, 0, 2, Some(0)),
+            Err(TransactionError::AmountNotAllowed("freeze".to_string()))
+        );
+        assert_eq!(
+            Transaction::from_parts(OrderType::Unknown("deposite".to_string()), 1, 2, Some(100)),
+            Err(TransactionError::UnknownTransactionType(
+                "deposite".to_string()
+            ))
+        );
+    }
 }
@@ -0,0 +1,73 @@
+use super::{ClientBalance, ClientId, TransactionId, TransactionManager, TransactionStatus};
+
+// A read-only borrow of a TransactionManager, from TransactionManager::as_readonly, exposing only its
+// queries. For read paths of a service, which can then not accept transactions by mistake.
+#[derive(Clone, Copy)]
+pub struct ReadOnlyView<'a> {
+    manager: &'a TransactionManager,
+}
+
+impl<'a> ReadOnlyView<'a> {
+    pub(super) fn new(manager: &'a TransactionManager) -> Self {
+        Self { manager }
+    }
+
+    // See TransactionManager::balances.
+    pub fn balances(&self) -> Vec<ClientBalance> {
+        self.manager.balances()
+    }
+
+    // See TransactionManager::balances_sorted.
+    pub fn balances_sorted(&self) -> Vec<ClientBalance> {
+        self.manager.balances_sorted()
+    }
+
+    pub fn balance(&self, client_id: ClientId) -> Option<ClientBalance> {
+        self.manager.balance(client_id)
+    }
+
+    // See TransactionManager::transaction_status.
+    pub fn transaction_status(&self, id: TransactionId) -> Option<TransactionStatus> {
+        self.manager.transaction_status(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::Money, transaction_manager::Transaction};
+
+    #[test]
+    fn test_read_through_view() {
+        let mut manager = TransactionManager::new();
+
+        for (id, client_id) in [(1, 2), (2, 1)] {
+            manager
+                .accept(Transaction::Deposit {
+                    id,
+                    client_id,
+                    amount_base_units: Money::from_base_units(15_000),
+                })
+                .unwrap();
+        }
+
+        manager
+            .accept(Transaction::Dispute {
+                id: 2,
+                client_id: 1,
+            })
+            .unwrap();
+
+        let view = manager.as_readonly();
+
+        assert_eq!(view.balances().len(), 2);
+        assert_eq!(view.balances_sorted()[0].client_id, 1);
+        assert_eq!(view.balance(1).unwrap().held, 1.5);
+        assert_eq!(view.balance(3), None);
+        assert_eq!(view.transaction_status(1), Some(TransactionStatus::Valid));
+        assert_eq!(
+            view.transaction_status(2),
+            Some(TransactionStatus::Disputed)
+        );
+    }
+}